@@ -0,0 +1,5 @@
+DROP INDEX idx_emails_queue;
+ALTER TABLE emails DROP COLUMN failed;
+ALTER TABLE emails DROP COLUMN last_error;
+ALTER TABLE emails DROP COLUMN next_attempt;
+ALTER TABLE emails DROP COLUMN attempts;
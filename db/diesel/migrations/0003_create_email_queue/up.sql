@@ -0,0 +1,7 @@
+ALTER TABLE emails ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE emails ADD COLUMN next_attempt TIMESTAMP;
+ALTER TABLE emails ADD COLUMN last_error VARCHAR;
+ALTER TABLE emails ADD COLUMN failed BOOLEAN NOT NULL DEFAULT 0;
+
+CREATE INDEX idx_emails_queue
+ON emails (next_attempt) where sent = false AND failed = false;
@@ -0,0 +1,53 @@
+CREATE TABLE invoices_old (
+    id CHAR(64) NOT NULL PRIMARY KEY,
+
+    bolt11 VARCHAR NOT NULL,
+
+    expiration TIMESTAMP NOT NULL,
+
+    paid BOOLEAN NOT NULL DEFAULT 0,
+    showed BOOLEAN NOT NULL DEFAULT 0
+);
+
+INSERT INTO invoices_old
+SELECT id, bolt11, expiration, state = 'paid', state != 'available'
+FROM invoices;
+
+DROP TABLE invoices;
+ALTER TABLE invoices_old RENAME TO invoices;
+
+CREATE INDEX idx_invoices
+ON invoices (expiration, paid, showed) where paid = false AND showed = false;
+
+CREATE TABLE emails_old (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    payment_hash CHAR(64) NOT NULL,
+
+    reply_to_email VARCHAR,
+    to_email VARCHAR NOT NULL,
+
+    subject VARCHAR NOT NULL,
+    message VARCHAR NOT NULL,
+
+    sent BOOLEAN NOT NULL DEFAULT 0,
+
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt TIMESTAMP,
+    last_error VARCHAR,
+    failed BOOLEAN NOT NULL DEFAULT 0
+);
+
+INSERT INTO emails_old
+SELECT id, payment_hash, reply_to_email, to_email, subject, message,
+    state = 'sent', attempts, next_attempt, last_error, state = 'failed'
+FROM emails;
+
+DROP TABLE emails;
+ALTER TABLE emails_old RENAME TO emails;
+
+CREATE UNIQUE INDEX idx_emails
+ON emails (payment_hash);
+
+CREATE INDEX idx_emails_queue
+ON emails (next_attempt) where sent = false AND failed = false;
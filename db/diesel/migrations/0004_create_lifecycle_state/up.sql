@@ -0,0 +1,69 @@
+CREATE TABLE emails_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    payment_hash CHAR(64) NOT NULL,
+
+    reply_to_email VARCHAR,
+    to_email VARCHAR NOT NULL,
+
+    subject VARCHAR NOT NULL,
+    message VARCHAR NOT NULL,
+
+    state VARCHAR NOT NULL DEFAULT 'reserved',
+
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt TIMESTAMP,
+    last_error VARCHAR
+);
+
+-- paid emails never sent before the queue existed are queued so that the worker retries them
+INSERT INTO emails_new
+SELECT id, payment_hash, reply_to_email, to_email, subject, message,
+    CASE
+        WHEN sent THEN 'sent'
+        WHEN failed THEN 'failed'
+        WHEN next_attempt IS NOT NULL OR payment_hash IN (SELECT id FROM invoices WHERE paid) THEN 'queued'
+        ELSE 'reserved'
+    END,
+    attempts,
+    CASE
+        WHEN NOT sent AND NOT failed AND payment_hash IN (SELECT id FROM invoices WHERE paid)
+            THEN COALESCE(next_attempt, CURRENT_TIMESTAMP)
+        ELSE next_attempt
+    END,
+    last_error
+FROM emails;
+
+DROP TABLE emails;
+ALTER TABLE emails_new RENAME TO emails;
+
+CREATE UNIQUE INDEX idx_emails
+ON emails (payment_hash);
+
+CREATE INDEX idx_emails_queue
+ON emails (next_attempt) where state = 'queued';
+
+CREATE TABLE invoices_new (
+    id CHAR(64) NOT NULL PRIMARY KEY,
+
+    bolt11 VARCHAR NOT NULL,
+
+    expiration TIMESTAMP NOT NULL,
+
+    state VARCHAR NOT NULL DEFAULT 'available'
+);
+
+INSERT INTO invoices_new
+SELECT id, bolt11, expiration,
+    CASE
+        WHEN paid THEN 'paid'
+        WHEN showed THEN 'reserved'
+        ELSE 'available'
+    END
+FROM invoices;
+
+DROP TABLE invoices;
+ALTER TABLE invoices_new RENAME TO invoices;
+
+CREATE INDEX idx_invoices
+ON invoices (expiration) where state = 'available';
@@ -0,0 +1 @@
+ALTER TABLE emails ADD COLUMN relay VARCHAR;
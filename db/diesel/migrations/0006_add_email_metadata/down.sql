@@ -0,0 +1,3 @@
+ALTER TABLE emails DROP COLUMN referer;
+ALTER TABLE invoices DROP COLUMN paid_at;
+ALTER TABLE invoices DROP COLUMN amount_msat;
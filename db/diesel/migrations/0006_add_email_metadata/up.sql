@@ -0,0 +1,3 @@
+ALTER TABLE invoices ADD COLUMN amount_msat BIGINT;
+ALTER TABLE invoices ADD COLUMN paid_at TIMESTAMP;
+ALTER TABLE emails ADD COLUMN referer VARCHAR;
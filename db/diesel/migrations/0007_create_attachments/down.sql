@@ -0,0 +1,2 @@
+DROP INDEX idx_attachments_payment_hash;
+DROP TABLE attachments;
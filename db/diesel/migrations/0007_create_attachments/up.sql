@@ -0,0 +1,10 @@
+CREATE TABLE attachments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    payment_hash VARCHAR NOT NULL,
+    filename VARCHAR NOT NULL,
+    content_type VARCHAR NOT NULL,
+    content BLOB NOT NULL
+);
+
+CREATE INDEX idx_attachments_payment_hash
+ON attachments (payment_hash);
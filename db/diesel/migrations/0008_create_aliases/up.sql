@@ -0,0 +1,5 @@
+CREATE TABLE aliases (
+    name VARCHAR NOT NULL PRIMARY KEY,
+    to_email VARCHAR NOT NULL,
+    min_amount_msat BIGINT NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE offers (
+    id VARCHAR NOT NULL PRIMARY KEY,
+    bolt12 VARCHAR NOT NULL,
+    to_email VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    amount_msat BIGINT NOT NULL
+);
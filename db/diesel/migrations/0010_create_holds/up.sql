@@ -0,0 +1,6 @@
+CREATE TABLE holds (
+    payment_hash VARCHAR NOT NULL PRIMARY KEY,
+    preimage VARCHAR NOT NULL,
+    accepted_at TIMESTAMP,
+    resolved_at TIMESTAMP
+);
@@ -0,0 +1,7 @@
+CREATE TABLE refunds (
+    payment_hash VARCHAR NOT NULL PRIMARY KEY,
+    amount_msat BIGINT NOT NULL,
+    k1 VARCHAR UNIQUE,
+    bolt11 VARCHAR,
+    paid_at TIMESTAMP
+);
@@ -0,0 +1,5 @@
+CREATE TABLE rate_limits (
+    key VARCHAR NOT NULL PRIMARY KEY,
+    tokens DOUBLE NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE challenges (
+    challenge CHAR(64) NOT NULL PRIMARY KEY,
+    expires_at TIMESTAMP NOT NULL,
+    spent_at TIMESTAMP
+);
+
+CREATE INDEX idx_challenges
+ON challenges (expires_at);
@@ -0,0 +1,461 @@
+//! Versioned api mounted at `/api/v1`, taking and returning JSON, errors included. The form routes
+//! are kept for the browsers
+
+use crate::attachment::AttachmentConfig;
+use crate::backend::InvoiceBackend;
+use crate::encrypt::decrypt;
+use crate::lifecycle::Lifecycle;
+use crate::lnurl::LnurlConfig;
+use crate::pow::PowConfig;
+use crate::pricing::PricingConfig;
+use crate::queue::Queue;
+use crate::ratelimit::RateLimit;
+use crate::routes::{
+    self, lookup_info, one_of, submit, Info, Recipients, Referer, Submission, Submitted,
+};
+use crate::{Db, Error};
+use lettre::message::{Mailbox, Mailboxes};
+use rocket::fairing::AdHoc;
+use rocket::http::{MediaType, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::status::Created;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Request, Response, State};
+use serde_json::json;
+use std::sync::Arc;
+
+const BASE: &str = "/api/v1";
+
+/// An error answered as `{"error": {"status": <code>, "code": <kind>, "message": <reason>}}`. The
+/// `code` is stable for programs to match on, the `message` is for humans and may change
+#[derive(Debug)]
+pub struct ApiError {
+    status: Status,
+    code: String,
+    message: String,
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        let invalid = Status::UnprocessableEntity;
+        // the messages don't include the error values, that may echo decrypted recipients
+        let (status, code, message) = match e {
+            Error::MissingTo => (invalid, "missing_to", "either `to` or `to_enc` is required"),
+            Error::OnlyOneTo => (
+                invalid,
+                "only_one_to",
+                "only one of `to` and `to_enc` is allowed",
+            ),
+            Error::MissingSubject => (
+                invalid,
+                "missing_subject",
+                "either `subject` or `subject_enc` is required",
+            ),
+            Error::OnlyOneSubject => (
+                invalid,
+                "only_one_subject",
+                "only one of `subject` and `subject_enc` is allowed",
+            ),
+            Error::EmptyMessage => (invalid, "empty_message", "the message is empty"),
+            Error::TooManyAttachments => (invalid, "too_many_attachments", "too many attachments"),
+            Error::InvalidAttachment(_) => (invalid, "invalid_attachment", "invalid attachment"),
+            Error::EmailAddress(_) => (invalid, "invalid_email_address", "invalid email address"),
+            Error::Bech32(_) | Error::Decryption(_) => (
+                invalid,
+                "invalid_encryption",
+                "can't decrypt the encrypted fields",
+            ),
+            Error::InvalidRecipientOption(_) => (
+                invalid,
+                "invalid_recipient_option",
+                "invalid option of the encrypted recipients",
+            ),
+            Error::InvalidStamp(reason) => {
+                let message = format!("invalid proof of work: {}", reason);
+                return ApiError::new(invalid, "invalid_stamp", message);
+            }
+            Error::InvoicePayeeNotAllowed(_) => {
+                (invalid, "payee_not_allowed", "invoice payee not allowed")
+            }
+            Error::InvoiceWrongNetwork(_) => {
+                (invalid, "wrong_network", "invoice of the wrong network")
+            }
+            Error::InvoiceAmountOutOfRange(_) => (
+                invalid,
+                "amount_out_of_range",
+                "invoice amount out of range",
+            ),
+            Error::RateLimited(_) => return ApiError::status(Status::TooManyRequests),
+            Error::Unauthorized => (Status::Unauthorized, "unauthorized", "unauthorized"),
+            Error::Diesel(diesel::result::Error::NotFound) => {
+                return ApiError::status(Status::NotFound)
+            }
+            Error::InvoiceNotFound => (
+                Status::ServiceUnavailable,
+                "no_invoice_available",
+                "no invoice available, try later",
+            ),
+            _ => {
+                // only the failures of the server are worth logging
+                println!("{:?}", e);
+                (
+                    Status::InternalServerError,
+                    "internal_error",
+                    "internal error",
+                )
+            }
+        };
+        ApiError::new(status, code, message)
+    }
+}
+
+impl ApiError {
+    fn new(status: Status, code: &str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// An error without details, described by the reason of `status`, eg. code `not_found` and
+    /// message `Not Found`. Requests over a limit are `rate_limited`, whether the limit is checked
+    /// by the route or its guard
+    fn status(status: Status) -> Self {
+        if status == Status::TooManyRequests {
+            return ApiError::new(status, "rate_limited", "too many requests, try later");
+        }
+        let reason = status.reason_lossy();
+        let code = reason.to_lowercase().replace(' ', "_");
+        ApiError::new(status, &code, reason)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = json!({
+            "error": { "status": self.status.code, "code": self.code, "message": self.message }
+        });
+        Response::build_from(Json(body).respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+type Result<T> = std::result::Result<T, ApiError>;
+
+/// Fails with `406 Not Acceptable` the requests whose `accept` header excludes JSON
+struct AcceptJson;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptJson {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.accept() {
+            Some(accept) if routes::quality(accept, &MediaType::JSON) <= 0.0 => {
+                let e = Error::InvalidContentType(accept.preferred().media_type().clone().into());
+                Outcome::Failure((Status::NotAcceptable, e))
+            }
+            _ => Outcome::Success(AcceptJson),
+        }
+    }
+}
+
+/// An email submitted to the api, fields are the ones of the form. Attachments are accepted only
+/// by the form, in `multipart/form-data` submissions
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+struct SendJson {
+    reply_to: Option<String>,
+    message: String,
+    to: Option<String>,
+    to_enc: Option<String>,
+    subject: Option<String>,
+    subject_enc: Option<String>,
+    pow_challenge: Option<String>,
+    pow_nonce: Option<u64>,
+}
+
+impl SendJson {
+    fn submission(self, referer: Option<String>) -> crate::error::Result<Submission> {
+        let to = match self.to {
+            Some(to) => Some((to.parse::<Mailboxes>()?, None)),
+            None => None,
+        };
+        let to_enc = match self.to_enc {
+            Some(to_enc) => {
+                let recipients = Recipients::decrypt(&to_enc)?;
+                Some((recipients.to.clone(), recipients.accepted_pow()))
+            }
+            None => None,
+        };
+        let (to, accepted_pow) = one_of(to, to_enc, Error::MissingTo, Error::OnlyOneTo)?;
+        let subject_enc = match self.subject_enc {
+            Some(subject_enc) => Some(decrypt(&subject_enc)?),
+            None => None,
+        };
+        let subject = one_of(
+            self.subject,
+            subject_enc,
+            Error::MissingSubject,
+            Error::OnlyOneSubject,
+        )?;
+        let reply_to = match self.reply_to {
+            Some(reply_to) => Some(reply_to.parse::<Mailbox>()?.to_string()),
+            None => None,
+        };
+        Ok(Submission {
+            to,
+            accepted_pow,
+            subject,
+            message: self.message,
+            reply_to,
+            referer,
+            uploads: vec![],
+            stamp: self.pow_challenge.zip(self.pow_nonce),
+        })
+    }
+}
+
+/// A submitted email, with the invoice paying for it unless sent with a proof of work
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct SubmittedJson {
+    payment_hash: String,
+    state: Lifecycle,
+    bolt11: Option<String>,
+    lnurl: Option<String>,
+    amount_msat: Option<i64>,
+    reply_to: Option<String>,
+    message: String,
+}
+
+/// Submit an email, answering `201 Created` with the location of its info
+#[post("/email", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn email(
+    db: Db,
+    data: Json<SendJson>,
+    attachment_config: &State<AttachmentConfig>,
+    pricing: &State<PricingConfig>,
+    pow_config: &State<PowConfig>,
+    backend: &State<Arc<dyn InvoiceBackend>>,
+    lnurl: &State<LnurlConfig>,
+    queue: &State<Queue>,
+    _accept: AcceptJson,
+    referer: Referer,
+    limit: RateLimit<'_>,
+) -> Result<Created<Json<SubmittedJson>>> {
+    let submission = data.into_inner().submission(referer.0)?;
+    let submitted = submit(
+        &db,
+        attachment_config,
+        pricing,
+        pow_config,
+        backend.as_ref(),
+        queue,
+        limit.0,
+        submission,
+    )
+    .await?;
+    let json = match submitted {
+        Submitted::Invoice(invoice, email_row) => SubmittedJson {
+            lnurl: lnurl.pay_link(backend.as_ref(), &invoice.id)?,
+            payment_hash: invoice.id,
+            state: email_row.state,
+            bolt11: Some(invoice.bolt11),
+            amount_msat: invoice.amount_msat,
+            reply_to: email_row.reply_to_email,
+            message: email_row.message,
+        },
+        Submitted::Stamped(email_row) => SubmittedJson {
+            payment_hash: email_row.payment_hash,
+            state: email_row.state,
+            bolt11: None,
+            lnurl: None,
+            amount_msat: None,
+            reply_to: email_row.reply_to_email,
+            message: email_row.message,
+        },
+    };
+    let location = format!("{}/email/{}", BASE, json.payment_hash);
+    Ok(Created::new(location).body(Json(json)))
+}
+
+/// Whether the invoice of the email with `payment_hash` is paid and the email sent
+#[get("/email/<payment_hash>")]
+async fn info(
+    db: Db,
+    backend: &State<Arc<dyn InvoiceBackend>>,
+    queue: &State<Queue>,
+    _accept: AcceptJson,
+    payment_hash: &str,
+) -> Result<Json<Info>> {
+    let info = lookup_info(&db, backend.as_ref(), queue, payment_hash.to_string()).await;
+    info.map(Json)
+        .ok_or_else(|| ApiError::status(Status::NotFound))
+}
+
+/// Errors of the requests failed before reaching a route, like invalid JSON bodies
+#[catch(default)]
+fn error(status: Status, _request: &Request) -> ApiError {
+    ApiError::status(status)
+}
+
+/// Mount the api routes and their JSON errors
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("API v1", |rocket| async {
+        rocket
+            .mount(BASE, routes![email, info])
+            .register(BASE, catchers![error])
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::ApiError;
+    use crate::mailer::MemoryMailer;
+    use crate::test_util::{self, MockBackend};
+    use crate::Error;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    #[rocket::async_test]
+    async fn test_api() {
+        let rocket = test_util::rocket_with(
+            test_util::figment(),
+            Arc::new(MemoryMailer::default()),
+            Arc::new(MockBackend::default()),
+        );
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let body = json!({ "to": "a@example.com", "subject": "Hi", "message": "Hello" });
+        let response = client.post("/api/v1/email").json(&body).dispatch().await;
+        assert_eq!(response.status(), Status::Created);
+        let location = response.headers().get_one("location").unwrap().to_string();
+        let json: Value = response.into_json().await.unwrap();
+        assert_eq!(json["state"], "reserved");
+        assert_eq!(json["amount_msat"], 20_000);
+        assert!(json["bolt11"].as_str().unwrap().starts_with("lnbc"));
+        let payment_hash = json["payment_hash"].as_str().unwrap();
+        assert_eq!(location, format!("/api/v1/email/{}", payment_hash));
+
+        // the mock backend settles at once, looked up when reserved
+        let json: Value = client
+            .get(location)
+            .header(Header::new("accept", "application/*"))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["payment_hash"], payment_hash);
+        assert_eq!(json["invoice_paid"], true);
+
+        let cases = [
+            (
+                json!({ "to": "a@example.com", "message": "Hello" }),
+                "missing_subject",
+            ),
+            (
+                json!({ "to": "not an email", "subject": "Hi", "message": "Hello" }),
+                "invalid_email_address",
+            ),
+            (
+                json!({ "to": "a@example.com", "subject": "Hi", "message": "" }),
+                "empty_message",
+            ),
+            (
+                json!({ "to": "a@example.com", "subject": "Hi" }),
+                "unprocessable_entity",
+            ),
+        ];
+        for (body, code) in cases {
+            let response = client.post("/api/v1/email").json(&body).dispatch().await;
+            assert_eq!(response.status(), Status::UnprocessableEntity, "{}", body);
+            let json: Value = response.into_json().await.unwrap();
+            assert_eq!(json["error"]["status"], 422);
+            assert_eq!(json["error"]["code"], code);
+            assert!(json["error"]["message"].is_string());
+        }
+
+        let response = client
+            .post("/api/v1/email")
+            .header(ContentType::JSON)
+            .body("{")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let json: Value = response.into_json().await.unwrap();
+        assert_eq!(json["error"]["status"], 400);
+        assert_eq!(json["error"]["code"], "bad_request");
+
+        let response = client.get("/api/v1/email/unknown").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let json: Value = response.into_json().await.unwrap();
+        assert_eq!(json["error"]["code"], "not_found");
+        assert_eq!(json["error"]["message"], "Not Found");
+
+        let response = client
+            .post("/api/v1/email")
+            .header(Header::new("accept", "text/html, application/json;q=0"))
+            .json(&body)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotAcceptable);
+    }
+
+    #[rocket::async_test]
+    async fn test_api_rate_limited() {
+        let figment = test_util::figment()
+            .merge(("rate_limit.ip.capacity", 1))
+            .merge(("rate_limit.ip.per_hour", 1));
+        let rocket = test_util::rocket_with(
+            figment,
+            Arc::new(MemoryMailer::default()),
+            Arc::new(MockBackend::default()),
+        );
+        let client = Client::tracked(rocket).await.unwrap();
+        let body = json!({ "to": "a@example.com", "subject": "Hi", "message": "Hello" });
+        // the guard failure has the code of the limits checked by the route
+        for (status, code) in [
+            (Status::Created, None),
+            (Status::TooManyRequests, Some("rate_limited")),
+        ] {
+            let response = client
+                .post("/api/v1/email")
+                .remote("1.2.3.4:1000".parse().unwrap())
+                .json(&body)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), status);
+            let json: Value = response.into_json().await.unwrap();
+            assert_eq!(json["error"]["code"].as_str(), code);
+        }
+        assert_eq!(
+            ApiError::from(Error::RateLimited("a".to_string())).code,
+            "rate_limited"
+        );
+    }
+
+    #[test]
+    fn test_api_error() {
+        let error = ApiError::from(Error::InvoiceNotFound);
+        assert_eq!(error.status, Status::ServiceUnavailable);
+        assert_eq!(error.code, "no_invoice_available");
+        let error = ApiError::from(Error::NoRelayAvailable);
+        assert_eq!(error.status, Status::InternalServerError);
+        assert_eq!(error.code, "internal_error");
+
+        // the values of the errors are not echoed
+        let error = ApiError::from(Error::InvalidRecipientOption("secret".to_string()));
+        assert_eq!(error.status, Status::UnprocessableEntity);
+        assert_eq!(error.code, "invalid_recipient_option");
+        assert!(!error.message.contains("secret"));
+    }
+}
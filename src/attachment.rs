@@ -0,0 +1,156 @@
+use crate::Error;
+use rocket::data::ByteUnit;
+use rocket::fairing::AdHoc;
+use rocket::form::{self, DataField, FromFormField, ValueField};
+use rocket::http::ContentType;
+use rocket::serde::Deserialize;
+
+/// Limits of the files uploaded with the form, read from the optional `attachments` table of the
+/// Rocket config. Available as managed state.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AttachmentConfig {
+    /// Maximum size of every file, the whole form is also limited by `limits.data-form`
+    pub max_size: ByteUnit,
+
+    /// Maximum number of files in a single email
+    pub max_count: usize,
+
+    /// Accepted MIME types, like `application/pdf`, or `image/*` for every subtype
+    pub content_types: Vec<String>,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        AttachmentConfig {
+            max_size: ByteUnit::Mebibyte(1),
+            max_count: 3,
+            content_types: vec![
+                "application/pdf".to_string(),
+                "image/*".to_string(),
+                "text/plain".to_string(),
+            ],
+        }
+    }
+}
+
+impl AttachmentConfig {
+    /// Whether the `content_type` of an uploaded file is accepted
+    pub fn accepts(&self, content_type: &ContentType) -> bool {
+        let top = content_type.top().as_str().to_ascii_lowercase();
+        let sub = content_type.sub().as_str().to_ascii_lowercase();
+        self.content_types.iter().any(|accepted| {
+            match accepted.to_ascii_lowercase().split_once('/') {
+                Some((t, s)) => t == top && (s == "*" || s == sub),
+                None => false,
+            }
+        })
+    }
+}
+
+/// A file field of a `multipart/form-data` submission
+#[derive(Debug)]
+pub struct Upload {
+    pub filename: String,
+    pub content_type: String,
+    pub content: Vec<u8>,
+}
+
+impl Upload {
+    /// Browsers submit an empty field when no file is chosen
+    pub fn is_empty(&self) -> bool {
+        self.filename.is_empty() && self.content.is_empty()
+    }
+}
+
+/// Keep only the last component of the path submitted by the client, without control chars
+fn sanitize_filename(raw: &str) -> String {
+    let name = raw.rsplit(['/', '\\']).next().unwrap_or_default();
+    name.chars().filter(|c| !c.is_control()).take(255).collect()
+}
+
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for Upload {
+    fn from_value(_field: ValueField<'r>) -> form::Result<'r, Self> {
+        Err(form::Error::validation("attachments require multipart/form-data").into())
+    }
+
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        let filename = field
+            .file_name
+            .map(|f| sanitize_filename(f.dangerous_unsafe_unsanitized_raw().as_str()))
+            .unwrap_or_default();
+        if filename.is_empty() {
+            return Ok(Upload {
+                filename,
+                content_type: field.content_type.to_string(),
+                content: vec![],
+            });
+        }
+        let config = field
+            .request
+            .rocket()
+            .state::<AttachmentConfig>()
+            .cloned()
+            .unwrap_or_default();
+        if !config.accepts(&field.content_type) {
+            let msg = format!("attachment type {} not accepted", field.content_type);
+            return Err(form::Error::validation(msg).into());
+        }
+        let content = field.data.open(config.max_size).into_bytes().await?;
+        if !content.is_complete() {
+            let msg = format!("attachment bigger than {}", config.max_size);
+            return Err(form::Error::validation(msg).into());
+        }
+        Ok(Upload {
+            filename,
+            content_type: field.content_type.to_string(),
+            content: content.into_inner(),
+        })
+    }
+}
+
+/// Read and validate the attachments config, making it available as managed state
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Attachments", |rocket| async {
+        let config = match rocket
+            .figment()
+            .focus("attachments")
+            .extract::<AttachmentConfig>()
+        {
+            Ok(config) => config,
+            Err(e) => {
+                let e = Error::InvalidConfig(e.to_string());
+                println!("invalid attachments config: {:?}", e);
+                return Err(rocket);
+            }
+        };
+        Ok(rocket.manage(config))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::attachment::{sanitize_filename, AttachmentConfig};
+    use rocket::http::ContentType;
+
+    #[test]
+    fn test_accepts() {
+        let config = AttachmentConfig::default();
+        assert!(config.accepts(&ContentType::PDF));
+        assert!(config.accepts(&ContentType::PNG));
+        assert!(config.accepts(&ContentType::new("IMAGE", "JPEG")));
+        assert!(config.accepts(&ContentType::Plain));
+        assert!(!config.accepts(&ContentType::HTML));
+        assert!(!config.accepts(&ContentType::Binary));
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("cv.pdf"), "cv.pdf");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\Users\\me\\cv.pdf"), "cv.pdf");
+        assert_eq!(sanitize_filename("a\r\nb.txt"), "ab.txt");
+        assert_eq!(sanitize_filename("dir/"), "");
+    }
+}
@@ -0,0 +1,556 @@
+use crate::cln::{ClnClient, ClnConfig};
+use crate::db::{EmailRow, HoldRow, InvoiceRow};
+use crate::error::Result;
+use crate::hold::{self, HoldBackend, HoldConfig};
+use crate::lifecycle::Lifecycle;
+use crate::lnd::{LndClient, LndConfig};
+use crate::offer;
+use crate::queue::Queue;
+use crate::refund;
+use crate::{Db, Error};
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::{sha256, Hash};
+use chrono::Utc;
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::serde::{Deserialize, DeserializeOwned};
+use rocket::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Status of an invoice according to the backend which issued it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvoiceStatus {
+    Open,
+    /// Paid but held by the node, until settled or canceled by the server
+    Accepted,
+    /// Paid, with the hex preimage revealed by the payment
+    Settled(String),
+    Canceled,
+}
+
+/// Status of a payment sent by the backend with [`InvoiceBackend::pay_invoice`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentStatus {
+    Pending,
+    Complete,
+    Failed,
+}
+
+/// A payment of a BOLT12 offer created with [`InvoiceBackend::create_offer`]
+#[derive(Debug, Clone)]
+pub struct OfferPayment {
+    pub offer_id: String,
+    pub payment_hash: String,
+    /// The BOLT12 invoice paid, `lni1...`
+    pub invoice: String,
+    pub amount_msat: u64,
+    /// The note set by the payer in the invoice request
+    pub payer_note: Option<String>,
+}
+
+/// Source of the invoices paying for the emails
+#[rocket::async_trait]
+pub trait InvoiceBackend: Send + Sync {
+    /// Return an invoice of at least `amount_msat`, reserved for `email_row` which is added with
+    /// the payment hash of the invoice, both or none.
+    ///
+    /// The invoice commits to the sha256 of `hashed_description` instead of the default
+    /// description when the backend [supports it](InvoiceBackend::supports_description_hash)
+    async fn create_invoice(
+        &self,
+        db: &Db,
+        amount_msat: u64,
+        hashed_description: Option<&str>,
+        email_row: EmailRow,
+    ) -> Result<InvoiceRow>;
+
+    /// Whether `create_invoice` can commit to the hash of a description, as required by LNURL-pay
+    fn supports_description_hash(&self) -> bool {
+        false
+    }
+
+    /// Whether `create_invoice` returns invoices of exactly the requested amount
+    fn exact_amounts(&self) -> bool {
+        true
+    }
+
+    /// Status of the reserved invoice paying to `payment_hash`
+    async fn lookup_invoice(&self, db: &Db, payment_hash: &str) -> Result<InvoiceStatus>;
+
+    /// Send the payment hash of the settled invoices to `settled`, until the subscription ends.
+    /// The payments of hold invoices are also sent when accepted.
+    ///
+    /// Backends which can't notify settlements return immediately, their payments are notified
+    /// on `/invoice/paid`
+    async fn subscribe(&self, _settled: mpsc::Sender<String>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the backend can create BOLT12 offers
+    fn supports_offers(&self) -> bool {
+        false
+    }
+
+    /// Create a reusable BOLT12 offer of `amount_msat`, returns its id and its `lno1...` encoding
+    async fn create_offer(
+        &self,
+        _amount_msat: u64,
+        _description: &str,
+    ) -> Result<(String, String)> {
+        Err(Error::OffersUnsupported)
+    }
+
+    /// The offer payment settled with `payment_hash`, `None` if it didn't pay an offer
+    async fn lookup_offer_payment(&self, _payment_hash: &str) -> Result<Option<OfferPayment>> {
+        Ok(None)
+    }
+
+    /// Whether the backend can create hold invoices
+    fn supports_hold(&self) -> bool {
+        false
+    }
+
+    /// Create an invoice of `amount_msat` paying to `payment_hash`, whose payments are held until
+    /// settled or canceled, returns its bolt11. Commits to `hashed_description` like
+    /// `create_invoice`
+    async fn create_hold_invoice(
+        &self,
+        _amount_msat: u64,
+        _payment_hash: &str,
+        _hashed_description: Option<&str>,
+    ) -> Result<String> {
+        Err(Error::HoldUnsupported)
+    }
+
+    /// Settle the held payment revealing its hex `preimage`
+    async fn settle_hold_invoice(&self, _preimage: &str) -> Result<()> {
+        Err(Error::HoldUnsupported)
+    }
+
+    /// Cancel the held payment to `payment_hash`, refunding the payer
+    async fn cancel_hold_invoice(&self, _payment_hash: &str) -> Result<()> {
+        Err(Error::HoldUnsupported)
+    }
+
+    /// Whether the backend can pay invoices from the funds of the node
+    fn supports_payments(&self) -> bool {
+        false
+    }
+
+    /// Pay `bolt11` from the funds of the node, returns once the payment succeeded. Fails with
+    /// `PaymentFailed` if it definitely failed, other errors leave the payment uncertain until
+    /// looked up
+    async fn pay_invoice(&self, _bolt11: &str) -> Result<()> {
+        Err(Error::PaymentsUnsupported)
+    }
+
+    /// Status of the payment of `bolt11`, `None` if it was never attempted
+    async fn lookup_payment(&self, _bolt11: &str) -> Result<Option<PaymentStatus>> {
+        Err(Error::PaymentsUnsupported)
+    }
+}
+
+/// Default seconds before the invoices created by a node expire
+pub fn default_expiry() -> u64 {
+    24 * 60 * 60
+}
+
+/// Default description of the invoices created by a node
+pub fn default_description() -> String {
+    "pay2.email".to_string()
+}
+
+/// Which `InvoiceBackend` to use, from the optional `invoice_backend` key of the Rocket config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum BackendKind {
+    Pool,
+    Cln,
+    Lnd,
+}
+
+/// Invoices uploaded in advance with `/invoice` by `pay2email-node`
+pub struct PoolBackend;
+
+#[rocket::async_trait]
+impl InvoiceBackend for PoolBackend {
+    /// The invoices are uploaded in advance, `hashed_description` is ignored
+    async fn create_invoice(
+        &self,
+        db: &Db,
+        amount_msat: u64,
+        _hashed_description: Option<&str>,
+        email_row: EmailRow,
+    ) -> Result<InvoiceRow> {
+        let mut invoices = InvoiceRow::list_available_invoices(db, amount_msat as i64, 10)
+            .await?
+            .into_iter();
+        loop {
+            let mut invoice = invoices.next().ok_or(Error::InvoiceNotFound)?;
+            // another request may reserve the same invoice concurrently, only one succeeds
+            if invoice.reserve(db, email_row.clone()).await? {
+                return Ok(invoice);
+            }
+        }
+    }
+
+    /// The cheapest invoice of the pool may be of an higher amount
+    fn exact_amounts(&self) -> bool {
+        false
+    }
+
+    /// Payments of pool invoices are known only once notified on `/invoice/paid`, so they are
+    /// never settled here
+    async fn lookup_invoice(&self, db: &Db, payment_hash: &str) -> Result<InvoiceStatus> {
+        let invoice = InvoiceRow::get(db, payment_hash.to_string()).await?;
+        Ok(if invoice.expiration < Utc::now().naive_utc() {
+            InvoiceStatus::Canceled
+        } else {
+            InvoiceStatus::Open
+        })
+    }
+}
+
+/// Add the invoice `bolt11` just created by a node, reserved for `email_row`
+pub async fn add_reserved(db: &Db, bolt11: String, email_row: EmailRow) -> Result<InvoiceRow> {
+    let mut invoice = InvoiceRow::from_bolt11(bolt11)?;
+    invoice.state = Lifecycle::Reserved;
+    let email_row = EmailRow {
+        payment_hash: invoice.id.clone(),
+        ..email_row
+    };
+    InvoiceRow::add_with_email(db, invoice.clone(), email_row).await?;
+    Ok(invoice)
+}
+
+/// Hex payment hash of the hex `preimage`
+pub fn payment_hash(preimage: &str) -> Result<String> {
+    let preimage = Vec::<u8>::from_hex(preimage)?;
+    Ok(sha256::Hash::hash(&preimage).to_hex())
+}
+
+/// Set the invoice paying to `payment_hash` as paid and enqueue its email for delivery.
+///
+/// Settling an already paid invoice doesn't enqueue the email again
+pub async fn settle(
+    db: &Db,
+    queue: &Queue,
+    payment_hash: String,
+) -> Result<(InvoiceRow, EmailRow)> {
+    let mut invoice = InvoiceRow::get(db, payment_hash).await?;
+    invoice.set_state(db, Lifecycle::Paid).await?;
+
+    let mut email_row = EmailRow::get(db, invoice.id.clone()).await?;
+    if email_row.enqueue(db).await? {
+        queue.wake();
+    }
+    Ok((invoice, email_row))
+}
+
+/// Settle the payment with `payment_hash` notified by the backend.
+///
+/// Offer payments have no invoice in db until notified, they are added before settling. Payments of
+/// hold invoices are looked up, when accepted their email is enqueued without settling
+pub async fn settle_notified(
+    db: &Db,
+    backend: &dyn InvoiceBackend,
+    queue: &Queue,
+    payment_hash: String,
+) -> Result<(InvoiceRow, EmailRow)> {
+    if HoldRow::get(db, payment_hash.clone()).await.is_ok() {
+        if backend.lookup_invoice(db, &payment_hash).await? == InvoiceStatus::Accepted {
+            return hold::accept(db, queue, payment_hash).await;
+        }
+        return settle(db, queue, payment_hash).await;
+    }
+    match settle(db, queue, payment_hash.clone()).await {
+        Err(Error::Diesel(diesel::result::Error::NotFound)) => {
+            match backend.lookup_offer_payment(&payment_hash).await? {
+                Some(payment) => {
+                    offer::add_payment(db, payment).await?;
+                    settle(db, queue, payment_hash).await
+                }
+                None => Err(Error::Diesel(diesel::result::Error::NotFound)),
+            }
+        }
+        result => result,
+    }
+}
+
+/// Settle the invoices notified by the backend subscription, resubscribing after failures
+async fn run(db: Db, backend: Arc<dyn InvoiceBackend>, queue: Queue, shutdown: Shutdown) {
+    let (sender, mut receiver) = mpsc::channel(100);
+    let subscribed = backend.clone();
+    let subscriber = tokio::spawn(async move {
+        while let Err(e) = subscribed.subscribe(sender.clone()).await {
+            println!("invoice subscription: {:?}", e);
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    });
+    loop {
+        tokio::select! {
+            payment_hash = receiver.recv() => match payment_hash {
+                Some(payment_hash) => match settle_notified(
+                    &db,
+                    backend.as_ref(),
+                    &queue,
+                    payment_hash.clone(),
+                )
+                .await
+                {
+                    Ok(_) => println!("invoice {} settled", payment_hash),
+                    // invoices of the node not created for an email nor paying one of our offers
+                    Err(Error::Diesel(diesel::result::Error::NotFound)) => {}
+                    Err(e) => println!("settling invoice {}: {:?}", payment_hash, e),
+                },
+                None => break,
+            },
+            _ = shutdown.clone() => break,
+        }
+    }
+    subscriber.abort();
+}
+
+/// Reserved invoices expired since less than this are still reconciled, nodes keep reporting the
+/// payments of expired invoices
+const RECONCILE_GRACE_HOURS: i64 = 24;
+
+/// Settle the reserved invoices found paid by the backend, returns the number of settled invoices.
+///
+/// Reconciles the payments whose notification was missed, both push and pull use [`settle`]
+pub async fn reconcile(db: &Db, backend: &dyn InvoiceBackend, queue: &Queue) -> Result<usize> {
+    let mut settled = 0;
+    let grace = chrono::Duration::hours(RECONCILE_GRACE_HOURS);
+    for invoice in InvoiceRow::list_reserved(db, grace).await? {
+        let preimage = match backend.lookup_invoice(db, &invoice.id).await {
+            Ok(InvoiceStatus::Settled(preimage)) => preimage,
+            Ok(InvoiceStatus::Accepted) => {
+                if let Err(e) = hold::accept(db, queue, invoice.id.clone()).await {
+                    println!("accepting invoice {}: {:?}", invoice.id, e);
+                }
+                continue;
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                println!("looking up invoice {}: {:?}", invoice.id, e);
+                continue;
+            }
+        };
+        // the preimage proves the payment, like the ones notified on `/invoice/paid`
+        if payment_hash(&preimage).ok().as_ref() != Some(&invoice.id) {
+            println!("invoice {}: preimage not matching", invoice.id);
+            continue;
+        }
+        settle(db, queue, invoice.id.clone()).await?;
+        println!("invoice {} settled by reconciliation", invoice.id);
+        settled += 1;
+    }
+    Ok(settled)
+}
+
+/// Reconcile the reserved invoices, and the claimed refunds, every `interval`
+async fn run_reconciler(
+    db: Db,
+    backend: Arc<dyn InvoiceBackend>,
+    queue: Queue,
+    interval: Duration,
+    shutdown: Shutdown,
+) {
+    loop {
+        if let Err(e) = reconcile(&db, backend.as_ref(), &queue).await {
+            println!("invoice reconciliation: {:?}", e);
+        }
+        if backend.supports_payments() {
+            if let Err(e) = refund::reconcile(&db, backend.as_ref()).await {
+                println!("refund reconciliation: {:?}", e);
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            _ = shutdown.clone() => break,
+        }
+    }
+}
+
+/// Default seconds between reconciliations of the reserved invoices
+pub fn default_reconcile_interval() -> u64 {
+    60
+}
+
+/// The optional top level `key` of the config, `default` if missing. Invalid values are errors,
+/// so that typos aren't silently replaced by the default
+fn extract_or<T: DeserializeOwned>(figment: &Figment, key: &str, default: T) -> Result<T> {
+    if figment.find_value(key).is_err() {
+        return Ok(default);
+    }
+    figment
+        .extract_inner(key)
+        .map_err(|e| Error::InvalidConfig(e.to_string()))
+}
+
+/// The `InvoiceBackend` selected by `kind`, creating hold invoices if enabled in `hold`
+fn configured(
+    figment: &Figment,
+    kind: BackendKind,
+    hold: &HoldConfig,
+) -> Result<Arc<dyn InvoiceBackend>> {
+    let backend: Arc<dyn InvoiceBackend> = match kind {
+        BackendKind::Pool => Arc::new(PoolBackend),
+        BackendKind::Cln => {
+            let config = figment
+                .extract_inner::<ClnConfig>("cln")
+                .map_err(|e| Error::InvalidConfig(e.to_string()))?;
+            Arc::new(ClnClient::new(config))
+        }
+        BackendKind::Lnd => {
+            let config = figment
+                .extract_inner::<LndConfig>("lnd")
+                .map_err(|e| Error::InvalidConfig(e.to_string()))?;
+            Arc::new(LndClient::new(config)?)
+        }
+    };
+    if !hold.enabled {
+        Ok(backend)
+    } else if backend.supports_hold() {
+        Ok(Arc::new(HoldBackend::new(backend)))
+    } else {
+        Err(Error::HoldUnsupported)
+    }
+}
+
+/// Manage the configured `Arc<dyn InvoiceBackend>` and settle its payments from liftoff, nodes are
+/// also polled every `reconcile_interval` seconds for the payments of reserved invoices.
+///
+/// Attached before the stages using the backend
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Invoice Backend", |rocket| async {
+        let figment = rocket.figment();
+        let config = extract_or(figment, "invoice_backend", BackendKind::Pool).and_then(|kind| {
+            let interval = default_reconcile_interval();
+            let reconcile_interval = extract_or(figment, "reconcile_interval", interval)?;
+            let hold = extract_or(figment, "hold", HoldConfig::default())?;
+            Ok((configured(figment, kind, &hold)?, kind, reconcile_interval))
+        });
+        let (backend, kind, reconcile_interval) = match config {
+            Ok(config) => config,
+            Err(e) => {
+                println!("invalid invoice backend config: {:?}", e);
+                return Err(rocket);
+            }
+        };
+        // the pool can't be polled, its payments are only pushed
+        let reconcile_interval = (kind != BackendKind::Pool).then_some(reconcile_interval);
+        Ok(rocket
+            .manage(backend)
+            .attach(settlements(reconcile_interval)))
+    })
+}
+
+/// Settle the notified payments of the managed `Arc<dyn InvoiceBackend>` from liftoff, and if
+/// `reconcile_interval` is set poll it for the payments of the reserved invoices
+pub fn settlements(reconcile_interval: Option<u64>) -> AdHoc {
+    AdHoc::on_liftoff("Invoice Settlements", move |rocket| {
+        Box::pin(async move {
+            let db = Db::get_one(rocket).await.expect("database connection");
+            let backend = rocket
+                .state::<Arc<dyn InvoiceBackend>>()
+                .expect("invoice backend")
+                .clone();
+            let queue = rocket.state::<Queue>().expect("queue").clone();
+            if let Some(reconcile_interval) = reconcile_interval {
+                let db = Db::get_one(rocket).await.expect("database connection");
+                let interval = Duration::from_secs(reconcile_interval);
+                tokio::spawn(run_reconciler(
+                    db,
+                    backend.clone(),
+                    queue.clone(),
+                    interval,
+                    rocket.shutdown(),
+                ));
+            }
+            tokio::spawn(run(db, backend, queue, rocket.shutdown()));
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::{reconcile, InvoiceBackend};
+    use crate::db::invoices;
+    use crate::mailer::MemoryMailer;
+    use crate::queue::Queue;
+    use crate::{test_util, Db};
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::Serialize;
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    /// Whether the test instance with `key` set to `value` ignites
+    async fn ignites<T: Serialize>(key: &str, value: T) -> bool {
+        let figment = test_util::figment().merge((key, value));
+        match test_util::rocket(figment, MemoryMailer::default())
+            .ignite()
+            .await
+        {
+            Ok(_) => true,
+            // unhandled errors panic when dropped
+            Err(e) => {
+                e.kind();
+                false
+            }
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_config() {
+        assert!(ignites("invoice_backend", "pool").await);
+        assert!(ignites("reconcile_interval", 10).await);
+
+        // typos fail instead of falling back to the defaults
+        assert!(!ignites("invoice_backend", "lnd ").await);
+        assert!(!ignites("reconcile_interval", "1m").await);
+    }
+
+    #[rocket::async_test]
+    async fn test_reconcile_expired() {
+        let figment = test_util::figment()
+            .merge(("invoice_backend", "cln"))
+            .merge(("cln.rpc_file", test_util::cln_server()))
+            .merge(("reconcile_interval", 3600));
+        let mailer = MemoryMailer::default();
+        let client = Client::tracked(test_util::rocket(figment, mailer.clone()))
+            .await
+            .unwrap();
+        let json: Value = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body("to=a%40example.com&subject=Hi&message=Hello")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let payment_hash = json["payment_hash"].as_str().unwrap().to_string();
+
+        // paid just before expiring, the notification was missed
+        let db = Db::get_one(client.rocket()).await.unwrap();
+        let expired = Utc::now().naive_utc() - Duration::minutes(1);
+        db.run(move |conn| {
+            diesel::update(invoices::table.find(payment_hash))
+                .set(invoices::expiration.eq(expired))
+                .execute(conn)
+        })
+        .await
+        .unwrap();
+        let backend = client.rocket().state::<Arc<dyn InvoiceBackend>>().unwrap();
+        let queue = client.rocket().state::<Queue>().unwrap();
+        assert_eq!(reconcile(&db, backend.as_ref(), queue).await.unwrap(), 1);
+        test_util::wait_messages(&mailer, 1).await;
+    }
+}
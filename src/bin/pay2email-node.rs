@@ -0,0 +1,25 @@
+//! Top up the invoice pool of a pay2email server with invoices of a Core Lightning node.
+//!
+//! Runs once, or every `interval` seconds with `--daemon`. See [`NodeConfig`] for the config.
+
+use pay2email::node::{NodeConfig, Refiller};
+
+#[tokio::main]
+async fn main() {
+    let daemon = std::env::args().skip(1).any(|arg| arg == "--daemon");
+    let config: NodeConfig = NodeConfig::figment()
+        .extract()
+        .expect("invalid pay2email-node config");
+    let refiller = Refiller::new(config).expect("invalid pay2email-node config");
+    if daemon {
+        refiller.run().await;
+    } else {
+        match refiller.refill().await {
+            Ok(uploaded) => println!("uploaded {} invoices", uploaded),
+            Err(e) => {
+                eprintln!("refill failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
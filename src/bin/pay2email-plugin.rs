@@ -0,0 +1,10 @@
+//! Core Lightning plugin notifying the paid invoices to a pay2email server, retrying until they are
+//! acknowledged. See [`pay2email::plugin`] for the options.
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = pay2email::plugin::run(tokio::io::stdin(), tokio::io::stdout()).await {
+        eprintln!("pay2email-plugin: {:?}", e);
+        std::process::exit(1);
+    }
+}
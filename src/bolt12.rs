@@ -0,0 +1,322 @@
+//! Minimal codec of BOLT12 offers, the `lno1...` strings: a TLV stream in bech32 characters
+//! without checksum
+
+use crate::error::Result;
+use crate::Error;
+use std::fmt;
+use std::str::FromStr;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const HRP: &str = "lno";
+
+/// A BOLT12 offer, fields are named as the `offer_` TLV records without prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Offer {
+    pub chains: Vec<[u8; 32]>,
+    pub metadata: Option<Vec<u8>>,
+    /// ISO 4217 code of the amount, millisatoshi when missing
+    pub currency: Option<String>,
+    pub amount: Option<u64>,
+    pub description: Option<String>,
+    pub features: Option<Vec<u8>>,
+    /// Seconds from the epoch
+    pub absolute_expiry: Option<u64>,
+    /// Serialized blinded paths, kept as is
+    pub paths: Option<Vec<u8>>,
+    pub issuer: Option<String>,
+    pub quantity_max: Option<u64>,
+    pub node_id: Option<[u8; 33]>,
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::Bolt12(reason.to_string())
+}
+
+fn write_bigsize(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+/// Read a BigSize at `pos`, advancing it, refusing non minimal encodings
+fn read_bigsize(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let first = *data.get(*pos).ok_or_else(|| invalid("truncated"))?;
+    let (len, min) = match first {
+        0xfd => (2, 0xfd),
+        0xfe => (4, 0x10000),
+        0xff => (8, 0x1_0000_0000),
+        n => {
+            *pos += 1;
+            return Ok(n as u64);
+        }
+    };
+    let bytes = data
+        .get(*pos + 1..*pos + 1 + len)
+        .ok_or_else(|| invalid("truncated"))?;
+    let n = bytes.iter().fold(0u64, |n, b| n << 8 | *b as u64);
+    if n < min {
+        return Err(invalid("non minimal bigsize"));
+    }
+    *pos += 1 + len;
+    Ok(n)
+}
+
+/// Big endian without leading zeros
+fn write_tu64(n: u64) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let zeros = (n.leading_zeros() / 8) as usize;
+    bytes[zeros..].to_vec()
+}
+
+fn read_tu64(value: &[u8]) -> Result<u64> {
+    if value.len() > 8 || value.first() == Some(&0) {
+        return Err(invalid("invalid tu64"));
+    }
+    Ok(value.iter().fold(0u64, |n, b| n << 8 | *b as u64))
+}
+
+fn read_utf8(value: &[u8]) -> Result<String> {
+    String::from_utf8(value.to_vec()).map_err(|_| invalid("invalid utf8"))
+}
+
+impl Offer {
+    fn records(&self) -> Vec<(u64, Vec<u8>)> {
+        let mut records = vec![];
+        if !self.chains.is_empty() {
+            records.push((2, self.chains.concat()));
+        }
+        if let Some(metadata) = &self.metadata {
+            records.push((4, metadata.clone()));
+        }
+        if let Some(currency) = &self.currency {
+            records.push((6, currency.as_bytes().to_vec()));
+        }
+        if let Some(amount) = self.amount {
+            records.push((8, write_tu64(amount)));
+        }
+        if let Some(description) = &self.description {
+            records.push((10, description.as_bytes().to_vec()));
+        }
+        if let Some(features) = &self.features {
+            records.push((12, features.clone()));
+        }
+        if let Some(absolute_expiry) = self.absolute_expiry {
+            records.push((14, write_tu64(absolute_expiry)));
+        }
+        if let Some(paths) = &self.paths {
+            records.push((16, paths.clone()));
+        }
+        if let Some(issuer) = &self.issuer {
+            records.push((18, issuer.as_bytes().to_vec()));
+        }
+        if let Some(quantity_max) = self.quantity_max {
+            records.push((20, write_tu64(quantity_max)));
+        }
+        if let Some(node_id) = &self.node_id {
+            records.push((22, node_id.to_vec()));
+        }
+        records
+    }
+
+    fn from_records(data: &[u8]) -> Result<Offer> {
+        let mut offer = Offer::default();
+        let mut pos = 0;
+        let mut last_type = None;
+        while pos < data.len() {
+            let kind = read_bigsize(data, &mut pos)?;
+            let len = read_bigsize(data, &mut pos)? as usize;
+            let value = data
+                .get(pos..pos + len)
+                .ok_or_else(|| invalid("truncated"))?;
+            pos += len;
+            if matches!(last_type, Some(last) if kind <= last) {
+                return Err(invalid("records not in ascending order"));
+            }
+            last_type = Some(kind);
+            match kind {
+                2 => {
+                    let chains = value.chunks_exact(32);
+                    if !chains.remainder().is_empty() {
+                        return Err(invalid("invalid length"));
+                    }
+                    offer.chains = chains.map(|c| c.try_into().expect("32 bytes")).collect()
+                }
+                4 => offer.metadata = Some(value.to_vec()),
+                6 => offer.currency = Some(read_utf8(value)?),
+                8 => offer.amount = Some(read_tu64(value)?),
+                10 => offer.description = Some(read_utf8(value)?),
+                12 => offer.features = Some(value.to_vec()),
+                14 => offer.absolute_expiry = Some(read_tu64(value)?),
+                16 => offer.paths = Some(value.to_vec()),
+                18 => offer.issuer = Some(read_utf8(value)?),
+                20 => offer.quantity_max = Some(read_tu64(value)?),
+                22 if len == 33 => offer.node_id = Some(value.try_into().expect("33 bytes")),
+                22 => return Err(invalid("invalid length")),
+                // unknown odd records can be ignored
+                kind if kind % 2 == 1 => (),
+                _ => return Err(invalid("unknown even record")),
+            }
+        }
+        if offer.node_id.is_none() && offer.paths.is_none() {
+            return Err(invalid("missing node id"));
+        }
+        if offer.amount.is_some() && offer.description.is_none() {
+            return Err(invalid("missing description"));
+        }
+        if offer.currency.is_some() && offer.amount.is_none() {
+            return Err(invalid("currency without amount"));
+        }
+        Ok(offer)
+    }
+}
+
+impl fmt::Display for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut data = vec![];
+        for (kind, value) in self.records() {
+            write_bigsize(&mut data, kind);
+            write_bigsize(&mut data, value.len() as u64);
+            data.extend_from_slice(&value);
+        }
+        // 8 bits groups to 5 bits groups, padding the last with zeros
+        let mut encoded = format!("{}1", HRP);
+        let mut acc = 0u32;
+        let mut bits = 0;
+        for byte in data {
+            acc = acc << 8 | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(CHARSET[(acc >> bits) as usize & 31] as char);
+            }
+        }
+        if bits > 0 {
+            encoded.push(CHARSET[(acc << (5 - bits)) as usize & 31] as char);
+        }
+        f.write_str(&encoded)
+    }
+}
+
+impl FromStr for Offer {
+    type Err = Error;
+
+    /// Parse an offer, possibly split in parts joined by `+` and whitespace
+    fn from_str(s: &str) -> Result<Offer> {
+        let joined: String = s.split('+').map(|part| part.trim_start()).collect();
+        if joined.chars().any(|c| c.is_ascii_uppercase())
+            && joined.chars().any(|c| c.is_ascii_lowercase())
+        {
+            return Err(invalid("mixed case"));
+        }
+        let joined = joined.to_ascii_lowercase();
+        let data = joined
+            .strip_prefix(HRP)
+            .and_then(|data| data.strip_prefix('1'))
+            .ok_or_else(|| invalid("not an offer"))?;
+
+        let mut bytes = vec![];
+        let mut acc = 0u32;
+        let mut bits = 0;
+        for c in data.bytes() {
+            let value = CHARSET
+                .iter()
+                .position(|x| *x == c)
+                .ok_or_else(|| invalid("invalid character"))?;
+            acc = (acc << 5 | value as u32) & 0xfff;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((acc >> bits) as u8);
+            }
+        }
+        if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+            return Err(invalid("invalid padding"));
+        }
+        Offer::from_records(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bolt12::Offer;
+    use bitcoin_hashes::hex::{FromHex, ToHex};
+
+    #[test]
+    fn test_decode() {
+        // minimal offer of the BOLT12 test vectors
+        let s =
+            "lno1pgx9getnwss8vetrw3hhyuckyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg";
+        let offer: Offer = s.parse().unwrap();
+        assert_eq!(offer.description.as_deref(), Some("Test vectors"));
+        assert_eq!(
+            offer.node_id.unwrap().to_hex(),
+            "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619"
+        );
+        assert_eq!(offer.amount, None);
+        assert_eq!(offer.to_string(), s);
+
+        let split = "lno1pgx9getnwss8vetrw3hhyuckyypwa3eyt44h6txtxquqh7lz5djge4af+\n   gfjn7k4rgrkuag0jsd5xvxg";
+        assert_eq!(split.parse::<Offer>().unwrap(), offer);
+        assert_eq!(s.to_uppercase().parse::<Offer>().unwrap(), offer);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let node_id = Vec::<u8>::from_hex(&format!("02{}", "11".repeat(32))).unwrap();
+        let offer = Offer {
+            chains: vec![[0x6f; 32]],
+            metadata: Some(vec![1, 2, 3]),
+            amount: Some(21_000),
+            description: Some("pay2.email".to_string()),
+            absolute_expiry: Some(1_700_000_000),
+            issuer: Some("pay2.email".to_string()),
+            quantity_max: Some(1),
+            node_id: Some(node_id.try_into().unwrap()),
+            ..Offer::default()
+        };
+        let encoded = offer.to_string();
+        assert!(encoded.starts_with("lno1"));
+        assert_eq!(encoded.parse::<Offer>().unwrap(), offer);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let s =
+            "lno1pgx9getnwss8vetrw3hhyuckyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg";
+        assert!(s.replacen("lno", "lni", 1).parse::<Offer>().is_err());
+        assert!(s.replacen('p', "P", 1).parse::<Offer>().is_err());
+        assert!(format!("{}b", s).parse::<Offer>().is_err());
+
+        let missing_node_id = Offer {
+            description: Some("pay2.email".to_string()),
+            ..Offer::default()
+        };
+        assert!(missing_node_id.to_string().parse::<Offer>().is_err());
+        let missing_description = Offer {
+            amount: Some(1_000),
+            node_id: Some([2; 33]),
+            ..Offer::default()
+        };
+        assert!(missing_description.to_string().parse::<Offer>().is_err());
+
+        // unknown records after the node id: even ones must be understood, odd ones are ignored
+        let mut data = vec![22, 33];
+        data.extend_from_slice(&[2; 33]);
+        assert!(Offer::from_records(&[&data[..], &[24, 0]].concat()).is_err());
+        assert!(Offer::from_records(&[&data[..], &[25, 0]].concat()).is_ok());
+        assert!(Offer::from_records(&[&[1, 0], &data[..]].concat()).is_ok());
+        assert!(Offer::from_records(&[&data[..], &data[..]].concat()).is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use crate::backend::{add_reserved, InvoiceBackend, InvoiceStatus, OfferPayment, PaymentStatus};
+use crate::db::{EmailRow, InvoiceRow};
+use crate::error::Result;
+use crate::{Db, Error};
+use bitcoin_hashes::hex::ToHex;
+use rocket::serde::{Deserialize, DeserializeOwned};
+use serde_json::{json, Value};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+
+/// Core Lightning node creating an invoice for every email, read from the `cln` table of the Rocket
+/// config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ClnConfig {
+    /// Path of the JSON-RPC unix socket, eg. `~/.lightning/bitcoin/lightning-rpc`
+    pub rpc_file: PathBuf,
+
+    /// Seconds before the invoices expire
+    #[serde(default = "crate::backend::default_expiry")]
+    pub expiry: u64,
+
+    /// Description of the invoices
+    #[serde(default = "crate::backend::default_description")]
+    pub description: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct InvoiceResult {
+    bolt11: String,
+}
+
+/// Error codes of `pay` after which the payment definitely failed
+const PAY_FAILURE_CODES: [i64; 5] = [203, 205, 206, 207, 210];
+
+/// Minimal JSON-RPC client of a Core Lightning node
+pub struct ClnClient {
+    config: ClnConfig,
+    id: AtomicU64,
+}
+
+impl ClnClient {
+    pub fn new(config: ClnConfig) -> Self {
+        ClnClient {
+            config,
+            id: AtomicU64::new(0),
+        }
+    }
+
+    /// Call `method` with named `params`, using a new connection for every request
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+        let mut stream = UnixStream::connect(&self.config.rpc_file).await?;
+        stream.write_all(&serde_json::to_vec(&request)?).await?;
+
+        // the node doesn't close the connection after the response, read until it is complete
+        let mut buffer = vec![];
+        let mut chunk = [0u8; 4096];
+        let response = loop {
+            let read = stream.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+            match serde_json::from_slice::<RpcResponse<T>>(&buffer) {
+                Ok(response) => break response,
+                Err(e) if e.is_eof() => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        match (response.result, response.error) {
+            (_, Some(e)) => Err(Error::ClnRpc(e.code, e.message)),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(Error::ClnRpc(0, "empty response".to_string())),
+        }
+    }
+
+    /// Create an invoice of `amount_msat`, committing to the hash of `hashed_description` if any,
+    /// returns its bolt11
+    pub async fn invoice(
+        &self,
+        amount_msat: u64,
+        hashed_description: Option<&str>,
+    ) -> Result<String> {
+        self.invoice_expiring(amount_msat, self.config.expiry, hashed_description)
+            .await
+    }
+
+    /// Like [`ClnClient::invoice`], expiring in `expiry` seconds
+    pub async fn invoice_expiring(
+        &self,
+        amount_msat: u64,
+        expiry: u64,
+        hashed_description: Option<&str>,
+    ) -> Result<String> {
+        // labels must be unique on the node, also across the processes creating invoices
+        let label = format!("pay2email-{}", rand::random::<[u8; 16]>().to_hex());
+        let params = json!({
+            "amount_msat": amount_msat,
+            "label": label,
+            "description": hashed_description.unwrap_or(&self.config.description),
+            "deschashonly": hashed_description.is_some(),
+            "expiry": expiry,
+        });
+        let result: InvoiceResult = self.call("invoice", params).await?;
+        Ok(result.bolt11)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PayResult {
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ListPaysResult {
+    pays: Vec<PayResult>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct OfferResult {
+    offer_id: String,
+    bolt12: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ListedInvoice {
+    status: String,
+    payment_preimage: Option<String>,
+    /// Set on the invoices paying an offer of the node
+    local_offer_id: Option<String>,
+    bolt12: Option<String>,
+    amount_received_msat: Option<u64>,
+    invreq_payer_note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ListInvoicesResult {
+    invoices: Vec<ListedInvoice>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct WaitInvoiceResult {
+    payment_hash: String,
+    pay_index: u64,
+}
+
+#[rocket::async_trait]
+impl InvoiceBackend for ClnClient {
+    async fn create_invoice(
+        &self,
+        db: &Db,
+        amount_msat: u64,
+        hashed_description: Option<&str>,
+        email_row: EmailRow,
+    ) -> Result<InvoiceRow> {
+        let bolt11 = self.invoice(amount_msat, hashed_description).await?;
+        add_reserved(db, bolt11, email_row).await
+    }
+
+    fn supports_description_hash(&self) -> bool {
+        true
+    }
+
+    async fn lookup_invoice(&self, _db: &Db, payment_hash: &str) -> Result<InvoiceStatus> {
+        let result: ListInvoicesResult = self
+            .call("listinvoices", json!({ "payment_hash": payment_hash }))
+            .await?;
+        let invoice = result
+            .invoices
+            .into_iter()
+            .next()
+            .ok_or(Error::InvoiceNotFound)?;
+        Ok(match invoice.status.as_str() {
+            "paid" => InvoiceStatus::Settled(
+                invoice
+                    .payment_preimage
+                    .ok_or_else(|| Error::ClnRpc(0, "paid invoice without preimage".to_string()))?,
+            ),
+            "expired" => InvoiceStatus::Canceled,
+            _ => InvoiceStatus::Open,
+        })
+    }
+
+    fn supports_offers(&self) -> bool {
+        true
+    }
+
+    async fn create_offer(&self, amount_msat: u64, description: &str) -> Result<(String, String)> {
+        let params = json!({
+            "amount": format!("{}msat", amount_msat),
+            "description": description,
+        });
+        let result: OfferResult = self.call("offer", params).await?;
+        Ok((result.offer_id, result.bolt12))
+    }
+
+    fn supports_payments(&self) -> bool {
+        true
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<()> {
+        let result: PayResult = match self.call("pay", json!({ "bolt11": bolt11 })).await {
+            Ok(result) => result,
+            Err(Error::ClnRpc(code, message)) if PAY_FAILURE_CODES.contains(&code) => {
+                return Err(Error::PaymentFailed(message))
+            }
+            Err(e) => return Err(e),
+        };
+        match result.status.as_str() {
+            "complete" => Ok(()),
+            "failed" => Err(Error::PaymentFailed("payment failed".to_string())),
+            status => Err(Error::ClnRpc(0, format!("payment {}", status))),
+        }
+    }
+
+    /// A failed payment may have been attempted again, the invoice is paid if any attempt completed
+    async fn lookup_payment(&self, bolt11: &str) -> Result<Option<PaymentStatus>> {
+        let result: ListPaysResult = self.call("listpays", json!({ "bolt11": bolt11 })).await?;
+        let has = |status| result.pays.iter().any(|p| p.status == status);
+        Ok(if result.pays.is_empty() {
+            None
+        } else if has("complete") {
+            Some(PaymentStatus::Complete)
+        } else if has("pending") {
+            Some(PaymentStatus::Pending)
+        } else {
+            Some(PaymentStatus::Failed)
+        })
+    }
+
+    async fn lookup_offer_payment(&self, payment_hash: &str) -> Result<Option<OfferPayment>> {
+        let result: ListInvoicesResult = self
+            .call("listinvoices", json!({ "payment_hash": payment_hash }))
+            .await?;
+        let invoice = match result.invoices.into_iter().next() {
+            Some(invoice) if invoice.status == "paid" => invoice,
+            _ => return Ok(None),
+        };
+        Ok(match (invoice.local_offer_id, invoice.bolt12) {
+            (Some(offer_id), Some(bolt12)) => Some(OfferPayment {
+                offer_id,
+                payment_hash: payment_hash.to_string(),
+                invoice: bolt12,
+                amount_msat: invoice.amount_received_msat.unwrap_or_default(),
+                payer_note: invoice.invreq_payer_note,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Wait for paid invoices with `waitanyinvoice`, starting from the first paid invoice of the
+    /// node, settling is idempotent
+    async fn subscribe(&self, settled: mpsc::Sender<String>) -> Result<()> {
+        let mut lastpay_index = 0;
+        loop {
+            let result: WaitInvoiceResult = self
+                .call("waitanyinvoice", json!({ "lastpay_index": lastpay_index }))
+                .await?;
+            lastpay_index = result.pay_index;
+            if settled.send(result.payment_hash).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cln::{ClnClient, ClnConfig};
+    use crate::test_util;
+    use crate::Error;
+    use lightning_invoice::Invoice;
+
+    #[rocket::async_test]
+    async fn test_invoice() {
+        let rpc_file = test_util::cln_server();
+        let client = ClnClient::new(ClnConfig {
+            rpc_file,
+            expiry: 3600,
+            description: "pay2.email".to_string(),
+        });
+        let bolt11 = client.invoice(21_000, None).await.unwrap();
+        let invoice: Invoice = bolt11.parse().unwrap();
+        assert_eq!(invoice.amount_milli_satoshis(), Some(21_000));
+
+        let bolt11 = client.invoice(21_000, Some("metadata")).await.unwrap();
+        assert_eq!(
+            test_util::invoice_description_hash(&bolt11),
+            test_util::description_hash(Some("metadata"))
+        );
+
+        // concurrent invoices have different labels
+        let (a, b, c) = tokio::join!(
+            client.invoice(21_000, None),
+            client.invoice(21_000, None),
+            client.invoice(21_000, None)
+        );
+        assert!(a.is_ok() && b.is_ok() && c.is_ok());
+
+        // the stand-in node refuses zero amounts like the real one
+        assert!(matches!(
+            client.invoice(0, None).await,
+            Err(Error::ClnRpc(-32602, _))
+        ));
+    }
+}
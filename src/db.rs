@@ -1,11 +1,16 @@
 use crate::error::Result;
+use crate::lifecycle::Lifecycle;
 use crate::Db;
+use crate::Error;
+use bitcoin_hashes::hex::ToHex;
 use chrono::{NaiveDateTime, Utc};
 use diesel::dsl::count;
 use diesel::prelude::*;
+use lightning_invoice::Invoice;
 use rocket::serde::{Deserialize, Serialize};
 use rocket::{Build, Rocket};
 use rocket_sync_db_pools::diesel;
+use std::time::UNIX_EPOCH;
 
 #[derive(Debug, Clone, Deserialize, Serialize, Queryable, Insertable, Identifiable)]
 #[serde(crate = "rocket::serde")]
@@ -17,8 +22,12 @@ pub struct InvoiceRow {
     #[serde(with = "my_date_format")]
     pub expiration: NaiveDateTime,
 
-    pub paid: bool,
-    pub showed: bool,
+    pub state: Lifecycle,
+
+    pub amount_msat: Option<i64>,
+
+    #[serde(default, with = "my_option_date_format")]
+    pub paid_at: Option<NaiveDateTime>,
 }
 
 table! {
@@ -26,8 +35,45 @@ table! {
         id -> Text,
         bolt11 -> Text,
         expiration -> Timestamp,
-        paid -> Bool,
-        showed -> Bool,
+        state -> Text,
+        amount_msat -> Nullable<BigInt>,
+        paid_at -> Nullable<Timestamp>,
+    }
+}
+
+/// A token bucket of the rate limits, when kept in db
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "rate_limits"]
+pub struct RateLimitRow {
+    /// The client IP prefixed by `ip:` or the recipient prefixed by `to:`
+    pub key: String,
+    pub tokens: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+table! {
+    rate_limits (key) {
+        key -> Text,
+        tokens -> Double,
+        updated_at -> Timestamp,
+    }
+}
+
+/// A challenge issued by the server for the proof of work of a free email, spent by the first
+/// email using it
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "challenges"]
+pub struct ChallengeRow {
+    pub challenge: String, // 64
+    pub expires_at: NaiveDateTime,
+    pub spent_at: Option<NaiveDateTime>,
+}
+
+table! {
+    challenges (challenge) {
+        challenge -> Text,
+        expires_at -> Timestamp,
+        spent_at -> Nullable<Timestamp>,
     }
 }
 
@@ -42,7 +88,22 @@ pub struct EmailRow {
     pub to_email: String,
     pub subject: String,
     pub message: String,
-    pub sent: bool,
+    pub state: Lifecycle,
+
+    /// Number of failed delivery attempts
+    pub attempts: i32,
+
+    /// When the queue worker should try to send the email, `None` until the invoice is paid
+    #[serde(skip)]
+    pub next_attempt: Option<NaiveDateTime>,
+
+    pub last_error: Option<String>,
+
+    /// The transport which delivered the email, eg. `host:port` of the SMTP relay
+    pub relay: Option<String>,
+
+    /// The page containing the form which originated the email
+    pub referer: Option<String>,
 }
 
 table! {
@@ -53,11 +114,151 @@ table! {
         to_email -> Text,
         subject -> Text,
         message -> Text,
-        sent -> Bool,
+        state -> Text,
+        attempts -> Integer,
+        next_attempt -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+        relay -> Nullable<Text>,
+        referer -> Nullable<Text>,
+    }
+}
+
+/// A file uploaded with the form, attached to the email once the invoice is paid
+#[derive(Clone, Queryable, Insertable)]
+#[table_name = "attachments"]
+pub struct AttachmentRow {
+    pub id: Option<i32>,
+    pub payment_hash: String, // 64
+    pub filename: String,
+    pub content_type: String,
+    pub content: Vec<u8>,
+}
+
+table! {
+    attachments (id) {
+        id -> Nullable<Integer>,
+        payment_hash -> Text,
+        filename -> Text,
+        content_type -> Text,
+        content -> Binary,
+    }
+}
+
+/// A Lightning Address `name@domain`, the comments of its payments are sent to `to_email`
+#[derive(Debug, Clone, Deserialize, Serialize, Queryable, Insertable)]
+#[serde(crate = "rocket::serde")]
+#[table_name = "aliases"]
+pub struct AliasRow {
+    pub name: String,
+    pub to_email: String,
+    pub min_amount_msat: i64,
+}
+
+table! {
+    aliases (name) {
+        name -> Text,
+        to_email -> Text,
+        min_amount_msat -> BigInt,
+    }
+}
+
+/// A reusable BOLT12 offer, the payer notes of its payments are sent to `to_email`
+#[derive(Debug, Clone, Deserialize, Serialize, Queryable, Insertable)]
+#[serde(crate = "rocket::serde")]
+#[table_name = "offers"]
+pub struct OfferRow {
+    pub id: String, // hex of the offer id given by the node (64 chars)
+    pub bolt12: String,
+    pub to_email: String,
+    pub subject: String,
+    pub amount_msat: i64,
+}
+
+table! {
+    offers (id) {
+        id -> Text,
+        bolt12 -> Text,
+        to_email -> Text,
+        subject -> Text,
+        amount_msat -> BigInt,
+    }
+}
+
+/// The preimage of a hold invoice, revealed to the node only once the email it pays is delivered
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "holds"]
+pub struct HoldRow {
+    pub payment_hash: String, // 64
+    pub preimage: String,     // 64
+
+    /// When the node notified the payment is held, waiting to be settled or canceled
+    pub accepted_at: Option<NaiveDateTime>,
+
+    /// When the payment was settled or canceled, settled ones have their invoice paid
+    pub resolved_at: Option<NaiveDateTime>,
+}
+
+table! {
+    holds (payment_hash) {
+        payment_hash -> Text,
+        preimage -> Text,
+        accepted_at -> Nullable<Timestamp>,
+        resolved_at -> Nullable<Timestamp>,
+    }
+}
+
+/// The amount owed to the sender of an email which couldn't be delivered, claimed with
+/// LNURL-withdraw
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "refunds"]
+pub struct RefundRow {
+    pub payment_hash: String, // 64
+    pub amount_msat: i64,
+
+    /// Secret of the LNURL-withdraw link, created when the sender claims the refund
+    pub k1: Option<String>,
+
+    /// The invoice paid to refund the sender, set when claimed
+    pub bolt11: Option<String>,
+
+    pub paid_at: Option<NaiveDateTime>,
+}
+
+table! {
+    refunds (payment_hash) {
+        payment_hash -> Text,
+        amount_msat -> BigInt,
+        k1 -> Nullable<Text>,
+        bolt11 -> Nullable<Text>,
+        paid_at -> Nullable<Timestamp>,
     }
 }
 
+allow_tables_to_appear_in_same_query!(invoices, attachments);
+allow_tables_to_appear_in_same_query!(emails, attachments);
+
 impl InvoiceRow {
+    /// Parse `bolt11` into a new available invoice row, failing if the invoice is expired
+    pub fn from_bolt11(bolt11: String) -> Result<InvoiceRow> {
+        let invoice: Invoice = bolt11.parse()?;
+        if invoice.is_expired() {
+            return Err(Error::InvoiceExpired);
+        }
+        let time = invoice.timestamp() + invoice.expiry_time();
+        let expiration = NaiveDateTime::from_timestamp(
+            time.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            0,
+        );
+        Ok(InvoiceRow {
+            id: invoice.payment_hash().to_hex(),
+            bolt11,
+            expiration,
+            state: Lifecycle::Available,
+            amount_msat: invoice.amount_milli_satoshis().map(|a| a as i64),
+            paid_at: None,
+        })
+    }
+
     /// Get the invoice_row identified by `payment_hash`
     pub async fn get(db: &Db, payment_hash: String) -> Result<InvoiceRow> {
         Ok(db
@@ -80,59 +281,140 @@ impl InvoiceRow {
             .await?)
     }
 
-    /// Return the id of an invoice (payment hash) which is not payed, not expired and not showed
-    /// before. Set it to showed
-    pub async fn list_available_invoices(db: &Db, limit: i64) -> Result<Vec<InvoiceRow>> {
+    /// Add the given `invoice_row` together with the `email_row` it pays, both or none
+    pub async fn add_with_email(
+        db: &Db,
+        invoice_row: InvoiceRow,
+        email_row: EmailRow,
+    ) -> Result<()> {
+        Ok(db
+            .run(move |conn| {
+                conn.transaction(|| {
+                    diesel::insert_into(invoices::table)
+                        .values(invoice_row)
+                        .execute(conn)?;
+                    diesel::insert_into(emails::table)
+                        .values(email_row)
+                        .execute(conn)?;
+                    Ok::<_, diesel::result::Error>(())
+                })
+            })
+            .await?)
+    }
+
+    /// Reserve the available invoice for `email_row`, added with the payment hash of the invoice
+    /// in the same transaction.
+    ///
+    /// Returns `false` if the invoice was reserved concurrently, in which case the email isn't
+    /// added
+    pub async fn reserve(&mut self, db: &Db, email_row: EmailRow) -> Result<bool> {
+        let id = self.id.clone();
+        let email_row = EmailRow {
+            payment_hash: id.clone(),
+            ..email_row
+        };
+        let reserved = db
+            .run(move |conn| {
+                conn.transaction(|| {
+                    let updated = diesel::update(invoices::table.find(id))
+                        .filter(invoices::state.eq(Lifecycle::Available))
+                        .set(invoices::state.eq(Lifecycle::Reserved))
+                        .execute(conn)?;
+                    if updated == 1 {
+                        diesel::insert_into(emails::table)
+                            .values(email_row)
+                            .execute(conn)?;
+                    }
+                    Ok::<_, diesel::result::Error>(updated == 1)
+                })
+            })
+            .await?;
+        if reserved {
+            self.state = Lifecycle::Reserved;
+        }
+        Ok(reserved)
+    }
+
+    /// Return at most `limit` invoices which are available, not expired and of at least
+    /// `min_amount_msat`, cheapest first
+    pub async fn list_available_invoices(
+        db: &Db,
+        min_amount_msat: i64,
+        limit: i64,
+    ) -> Result<Vec<InvoiceRow>> {
         Ok(db
             .run(move |conn| {
                 invoices::table
-                    .filter(invoices::showed.eq(false))
-                    .filter(invoices::paid.eq(false))
+                    .filter(invoices::state.eq(Lifecycle::Available))
                     .filter(invoices::expiration.gt(max_expiration()))
+                    .filter(invoices::amount_msat.ge(min_amount_msat))
+                    .order(invoices::amount_msat.asc())
                     .limit(limit)
                     .load::<InvoiceRow>(conn)
             })
             .await?)
     }
 
-    /// List all available invoices, never showed, nor paid, nor expired
-    pub async fn count_available(db: &Db) -> Result<i64> {
+    /// Count the available invoices of at least `min_amount_msat`, never showed, nor paid, nor
+    /// expired, like the ones returned by `list_available_invoices`
+    pub async fn count_available(db: &Db, min_amount_msat: i64) -> Result<i64> {
         Ok(db
             .run(move |conn| {
                 invoices::table
                     .select(count(invoices::id))
-                    .filter(invoices::showed.eq(false))
-                    .filter(invoices::paid.eq(false))
+                    .filter(invoices::state.eq(Lifecycle::Available))
                     .filter(invoices::expiration.gt(max_expiration()))
+                    .filter(invoices::amount_msat.ge(min_amount_msat))
                     .first(conn)
             })
             .await?)
     }
 
-    /// Set the expired flag to true
-    pub async fn set_paid(&mut self, db: &Db) -> Result<()> {
-        let cloned = self.clone();
-        db.run(move |conn| {
-            diesel::update(&cloned)
-                .set(invoices::paid.eq(true))
-                .execute(conn)
-        })
-        .await?;
-        self.paid = true;
-        Ok(())
+    /// List the invoices reserved for an email and not yet paid, also the ones expired in the last
+    /// `grace` since they may have been paid just before expiring
+    pub async fn list_reserved(db: &Db, grace: chrono::Duration) -> Result<Vec<InvoiceRow>> {
+        Ok(db
+            .run(move |conn| {
+                invoices::table
+                    .filter(invoices::state.eq(Lifecycle::Reserved))
+                    .filter(invoices::expiration.gt(Utc::now().naive_utc() - grace))
+                    .load::<InvoiceRow>(conn)
+            })
+            .await?)
     }
 
-    /// Set the showed flag to true
-    pub async fn set_showed(&mut self, db: &Db) -> Result<()> {
-        let cloned = self.clone();
-        db.run(move |conn| {
-            diesel::update(&cloned)
-                .set(invoices::showed.eq(true))
-                .execute(conn)
-        })
-        .await?;
-        self.showed = true;
-        Ok(())
+    /// Move the invoice to the `next` state, checking the transition is valid. The payment time
+    /// is recorded when moving to `Paid`.
+    ///
+    /// Returns `false` if the invoice was already in `next` or a later state, also when changed
+    /// concurrently, in which case `self` is refreshed with the state in the db.
+    pub async fn set_state(&mut self, db: &Db, next: Lifecycle) -> Result<bool> {
+        let next = match self.state.transition(next)? {
+            Some(next) => next,
+            None => return Ok(false),
+        };
+        let id = self.id.clone();
+        let current = self.state;
+        let paid_at = match next {
+            Lifecycle::Paid => Some(Utc::now().naive_utc()),
+            _ => self.paid_at,
+        };
+        let updated = db
+            .run(move |conn| {
+                diesel::update(invoices::table.find(id))
+                    .filter(invoices::state.eq(current))
+                    .set((invoices::state.eq(next), invoices::paid_at.eq(paid_at)))
+                    .execute(conn)
+            })
+            .await?;
+        if updated == 1 {
+            self.state = next;
+            self.paid_at = paid_at;
+            Ok(true)
+        } else {
+            *self = InvoiceRow::get(db, self.id.clone()).await?;
+            Ok(false)
+        }
     }
 }
 
@@ -165,16 +447,110 @@ impl EmailRow {
             .await?)
     }
 
-    /// Set the sent flag to true
-    pub async fn set_sent(&mut self, db: &Db) -> Result<()> {
+    /// Move the email to the `next` state, checking the transition is valid.
+    ///
+    /// Returns `false` if the email was already in `next` or a later state, also when changed
+    /// concurrently, in which case `self` is refreshed with the state in the db.
+    pub async fn set_state(&mut self, db: &Db, next: Lifecycle) -> Result<bool> {
+        let next = match self.state.transition(next)? {
+            Some(next) => next,
+            None => return Ok(false),
+        };
+        let id = self.id;
+        let current = self.state;
+        let updated = db
+            .run(move |conn| {
+                diesel::update(emails::table.filter(emails::id.eq(id)))
+                    .filter(emails::state.eq(current))
+                    .set(emails::state.eq(next))
+                    .execute(conn)
+            })
+            .await?;
+        if updated == 1 {
+            self.state = next;
+            Ok(true)
+        } else {
+            self.state = EmailRow::get(db, self.payment_hash.clone()).await?.state;
+            Ok(false)
+        }
+    }
+
+    /// Move the email of a paid invoice to the queue, for immediate delivery by the queue worker.
+    ///
+    /// Returns `false` if the email was already queued or in a later state
+    pub async fn enqueue(&mut self, db: &Db) -> Result<bool> {
+        self.set_state(db, Lifecycle::Paid).await?;
+        if !self.set_state(db, Lifecycle::Queued).await? {
+            return Ok(false);
+        }
+        let cloned = self.clone();
+        let now = Utc::now().naive_utc();
+        db.run(move |conn| {
+            diesel::update(&cloned)
+                .set(emails::next_attempt.eq(now))
+                .execute(conn)
+        })
+        .await?;
+        self.next_attempt = Some(now);
+        Ok(true)
+    }
+
+    /// List at most `limit` queued emails, whose next attempt is due
+    pub async fn list_due(db: &Db, limit: i64) -> Result<Vec<EmailRow>> {
+        Ok(db
+            .run(move |conn| {
+                emails::table
+                    .filter(emails::state.eq(Lifecycle::Queued))
+                    .filter(emails::next_attempt.le(Utc::now().naive_utc()))
+                    .order(emails::next_attempt.asc())
+                    .limit(limit)
+                    .load::<EmailRow>(conn)
+            })
+            .await?)
+    }
+
+    /// Record a failed delivery attempt with its `error`. The next attempt is scheduled at
+    /// `next_attempt`, if `None` the email is moved to the failed state
+    pub async fn set_attempt_failed(
+        &mut self,
+        db: &Db,
+        error: String,
+        next_attempt: Option<NaiveDateTime>,
+    ) -> Result<()> {
         let cloned = self.clone();
+        let attempts = self.attempts + 1;
+        let last_error = error.clone();
         db.run(move |conn| {
             diesel::update(&cloned)
-                .set(emails::sent.eq(true))
+                .set((
+                    emails::attempts.eq(attempts),
+                    emails::next_attempt.eq(next_attempt),
+                    emails::last_error.eq(last_error),
+                ))
                 .execute(conn)
         })
         .await?;
-        self.sent = true;
+        self.attempts = attempts;
+        self.next_attempt = next_attempt;
+        self.last_error = Some(error);
+        if next_attempt.is_none() {
+            self.set_state(db, Lifecycle::Failed).await?;
+        }
+        Ok(())
+    }
+
+    /// Record the email has been delivered by `relay`
+    pub async fn set_delivered(&mut self, db: &Db, relay: String) -> Result<()> {
+        let cloned = self.clone();
+        let relay_cloned = relay.clone();
+        db.run(move |conn| {
+            diesel::update(&cloned)
+                .set(emails::relay.eq(relay_cloned))
+                .execute(conn)
+        })
+        .await?;
+        self.relay = Some(relay);
+        self.set_state(db, Lifecycle::Sent).await?;
         Ok(())
     }
 
@@ -184,13 +560,430 @@ impl EmailRow {
             .run(move |conn| {
                 emails::table
                     .select(count(emails::id))
-                    .filter(emails::sent.eq(true))
+                    .filter(emails::state.eq(Lifecycle::Sent))
                     .first(conn)
             })
             .await?)
     }
 }
 
+impl AliasRow {
+    /// Get the alias with the given `name`
+    pub async fn get(db: &Db, name: String) -> Result<AliasRow> {
+        Ok(db
+            .run(move |conn| aliases::table.find(name).get_result::<AliasRow>(conn))
+            .await?)
+    }
+
+    /// Add the given `alias_row` in db, replacing the alias with the same name
+    pub async fn add(db: &Db, alias_row: AliasRow) -> Result<usize> {
+        Ok(db
+            .run(move |conn| {
+                diesel::replace_into(aliases::table)
+                    .values(alias_row)
+                    .execute(conn)
+            })
+            .await?)
+    }
+}
+
+impl OfferRow {
+    /// Get the offer with the given `offer_id`
+    pub async fn get(db: &Db, offer_id: String) -> Result<OfferRow> {
+        Ok(db
+            .run(move |conn| offers::table.find(offer_id).get_result::<OfferRow>(conn))
+            .await?)
+    }
+
+    /// Add the given `offer_row` in db
+    pub async fn add(db: &Db, offer_row: OfferRow) -> Result<usize> {
+        Ok(db
+            .run(move |conn| {
+                diesel::insert_into(offers::table)
+                    .values(offer_row)
+                    .execute(conn)
+            })
+            .await?)
+    }
+}
+
+impl HoldRow {
+    /// Get the hold invoice paying to `payment_hash`
+    pub async fn get(db: &Db, payment_hash: String) -> Result<HoldRow> {
+        Ok(db
+            .run(move |conn| holds::table.find(payment_hash).get_result::<HoldRow>(conn))
+            .await?)
+    }
+
+    /// Add the given `hold_row` in db
+    pub async fn add(db: &Db, hold_row: HoldRow) -> Result<usize> {
+        Ok(db
+            .run(move |conn| {
+                diesel::insert_into(holds::table)
+                    .values(hold_row)
+                    .execute(conn)
+            })
+            .await?)
+    }
+
+    /// List the accepted payments not yet settled nor canceled
+    pub async fn list_accepted(db: &Db) -> Result<Vec<HoldRow>> {
+        Ok(db
+            .run(move |conn| {
+                holds::table
+                    .filter(holds::accepted_at.is_not_null())
+                    .filter(holds::resolved_at.is_null())
+                    .load::<HoldRow>(conn)
+            })
+            .await?)
+    }
+
+    /// Record the payment is held by the node, returns `false` if it was already recorded
+    pub async fn set_accepted(&mut self, db: &Db) -> Result<bool> {
+        let payment_hash = self.payment_hash.clone();
+        let now = Utc::now().naive_utc();
+        let updated = db
+            .run(move |conn| {
+                diesel::update(holds::table.find(payment_hash))
+                    .filter(holds::accepted_at.is_null())
+                    .set(holds::accepted_at.eq(now))
+                    .execute(conn)
+            })
+            .await?;
+        if updated == 1 {
+            self.accepted_at = Some(now);
+        }
+        Ok(updated == 1)
+    }
+
+    /// Record the payment has been settled or canceled
+    pub async fn set_resolved(&mut self, db: &Db) -> Result<()> {
+        let payment_hash = self.payment_hash.clone();
+        let now = Utc::now().naive_utc();
+        db.run(move |conn| {
+            diesel::update(holds::table.find(payment_hash))
+                .set(holds::resolved_at.eq(now))
+                .execute(conn)
+        })
+        .await?;
+        self.resolved_at = Some(now);
+        Ok(())
+    }
+}
+
+impl RefundRow {
+    /// Get the refund of the email paid to `payment_hash`
+    pub async fn get(db: &Db, payment_hash: String) -> Result<RefundRow> {
+        Ok(db
+            .run(move |conn| {
+                refunds::table
+                    .find(payment_hash)
+                    .get_result::<RefundRow>(conn)
+            })
+            .await?)
+    }
+
+    /// Get the refund claimed with the LNURL-withdraw secret `k1`
+    pub async fn get_by_k1(db: &Db, k1: String) -> Result<RefundRow> {
+        Ok(db
+            .run(move |conn| {
+                refunds::table
+                    .filter(refunds::k1.eq(k1))
+                    .first::<RefundRow>(conn)
+            })
+            .await?)
+    }
+
+    /// Add the given `refund_row` in db, a refund already recorded is kept
+    pub async fn add(db: &Db, refund_row: RefundRow) -> Result<usize> {
+        Ok(db
+            .run(move |conn| {
+                diesel::insert_or_ignore_into(refunds::table)
+                    .values(refund_row)
+                    .execute(conn)
+            })
+            .await?)
+    }
+
+    /// Set the LNURL-withdraw secret `k1` of the refund
+    pub async fn set_k1(&mut self, db: &Db, k1: String) -> Result<()> {
+        let payment_hash = self.payment_hash.clone();
+        let k1_cloned = k1.clone();
+        db.run(move |conn| {
+            diesel::update(refunds::table.find(payment_hash))
+                .set(refunds::k1.eq(k1_cloned))
+                .execute(conn)
+        })
+        .await?;
+        self.k1 = Some(k1);
+        Ok(())
+    }
+
+    /// Claim the refund to be paid with `bolt11`, returns `false` if it was already claimed
+    pub async fn claim(&mut self, db: &Db, bolt11: String) -> Result<bool> {
+        let payment_hash = self.payment_hash.clone();
+        let bolt11_cloned = bolt11.clone();
+        let updated = db
+            .run(move |conn| {
+                diesel::update(refunds::table.find(payment_hash))
+                    .filter(refunds::bolt11.is_null())
+                    .set(refunds::bolt11.eq(bolt11_cloned))
+                    .execute(conn)
+            })
+            .await?;
+        if updated == 1 {
+            self.bolt11 = Some(bolt11);
+        }
+        Ok(updated == 1)
+    }
+
+    /// The refunds claimed whose payment isn't recorded as paid
+    pub async fn list_claimed(db: &Db) -> Result<Vec<RefundRow>> {
+        Ok(db
+            .run(move |conn| {
+                refunds::table
+                    .filter(refunds::bolt11.is_not_null())
+                    .filter(refunds::paid_at.is_null())
+                    .load::<RefundRow>(conn)
+            })
+            .await?)
+    }
+
+    /// Release the claim of a refund whose payment failed, so that it can be claimed again. A claim
+    /// of another invoice is kept
+    pub async fn release(&mut self, db: &Db) -> Result<()> {
+        let payment_hash = self.payment_hash.clone();
+        let bolt11 = self.bolt11.clone();
+        db.run(move |conn| {
+            diesel::update(refunds::table.find(payment_hash))
+                .filter(refunds::paid_at.is_null())
+                .filter(refunds::bolt11.eq(bolt11))
+                .set(refunds::bolt11.eq(None::<String>))
+                .execute(conn)
+        })
+        .await?;
+        self.bolt11 = None;
+        Ok(())
+    }
+
+    /// Record the claimed invoice has been paid
+    pub async fn set_paid(&mut self, db: &Db) -> Result<()> {
+        let payment_hash = self.payment_hash.clone();
+        let now = Utc::now().naive_utc();
+        db.run(move |conn| {
+            diesel::update(refunds::table.find(payment_hash))
+                .set(refunds::paid_at.eq(now))
+                .execute(conn)
+        })
+        .await?;
+        self.paid_at = Some(now);
+        Ok(())
+    }
+}
+
+impl RateLimitRow {
+    /// Get the bucket `key`, `None` if it has never been used
+    pub async fn get(db: &Db, key: String) -> Result<Option<RateLimitRow>> {
+        Ok(db
+            .run(move |conn| {
+                rate_limits::table
+                    .find(key)
+                    .get_result::<RateLimitRow>(conn)
+                    .optional()
+            })
+            .await?)
+    }
+
+    /// Set the given `rate_limit_row` in db, replacing the bucket with the same key
+    pub async fn set(db: &Db, rate_limit_row: RateLimitRow) -> Result<usize> {
+        Ok(db
+            .run(move |conn| {
+                diesel::replace_into(rate_limits::table)
+                    .values(rate_limit_row)
+                    .execute(conn)
+            })
+            .await?)
+    }
+
+    /// Get the buckets `keys`, `None` if never used, and set the rows returned by `update` in a
+    /// single transaction, so that concurrent requests can't take the same tokens. Nothing is set
+    /// if `update` fails, with the key over the limit
+    pub async fn update<F>(
+        db: &Db,
+        keys: Vec<String>,
+        update: F,
+    ) -> Result<std::result::Result<(), String>>
+    where
+        F: FnOnce(Vec<Option<RateLimitRow>>) -> std::result::Result<Vec<RateLimitRow>, String>
+            + Send
+            + 'static,
+    {
+        Ok(db
+            .run(move |conn| {
+                conn.immediate_transaction(|| {
+                    let mut rows = vec![];
+                    for key in keys {
+                        let row = rate_limits::table
+                            .find(key)
+                            .get_result::<RateLimitRow>(conn)
+                            .optional()?;
+                        rows.push(row);
+                    }
+                    let rows = match update(rows) {
+                        Ok(rows) => rows,
+                        Err(key) => return Ok(Err(key)),
+                    };
+                    diesel::replace_into(rate_limits::table)
+                        .values(rows)
+                        .execute(conn)?;
+                    Ok::<_, diesel::result::Error>(Ok(()))
+                })
+            })
+            .await?)
+    }
+
+    /// Delete the buckets whose key starts with `prefix` not updated since `before`, returns the
+    /// number deleted
+    pub async fn delete_stale(db: &Db, prefix: &str, before: NaiveDateTime) -> Result<usize> {
+        let pattern = format!("{}%", prefix);
+        Ok(db
+            .run(move |conn| {
+                diesel::delete(
+                    rate_limits::table
+                        .filter(rate_limits::key.like(pattern))
+                        .filter(rate_limits::updated_at.lt(before)),
+                )
+                .execute(conn)
+            })
+            .await?)
+    }
+}
+
+impl ChallengeRow {
+    /// Add the given `challenge_row` in db, deleting the expired ones
+    pub async fn add(db: &Db, challenge_row: ChallengeRow) -> Result<usize> {
+        let now = Utc::now().naive_utc();
+        Ok(db
+            .run(move |conn| {
+                diesel::delete(challenges::table.filter(challenges::expires_at.lt(now)))
+                    .execute(conn)?;
+                diesel::insert_into(challenges::table)
+                    .values(challenge_row)
+                    .execute(conn)
+            })
+            .await?)
+    }
+
+    /// Spend the `challenge`, returns `false` if it's unknown, expired or already spent
+    pub async fn spend(db: &Db, challenge: String) -> Result<bool> {
+        let now = Utc::now().naive_utc();
+        let updated = db
+            .run(move |conn| {
+                diesel::update(challenges::table.find(challenge))
+                    .filter(challenges::spent_at.is_null())
+                    .filter(challenges::expires_at.ge(now))
+                    .set(challenges::spent_at.eq(now))
+                    .execute(conn)
+            })
+            .await?;
+        Ok(updated == 1)
+    }
+}
+
+impl AttachmentRow {
+    /// Add the given `attachment_rows` in db
+    pub async fn add(db: &Db, attachment_rows: Vec<AttachmentRow>) -> Result<usize> {
+        Ok(db
+            .run(move |conn| {
+                diesel::insert_into(attachments::table)
+                    .values(attachment_rows)
+                    .execute(conn)
+            })
+            .await?)
+    }
+
+    /// Return the attachments of the email associated with given `payment_hash`
+    pub async fn list(db: &Db, payment_hash: String) -> Result<Vec<AttachmentRow>> {
+        Ok(db
+            .run(move |conn| {
+                attachments::table
+                    .filter(attachments::payment_hash.eq(payment_hash))
+                    .order(attachments::id)
+                    .load::<AttachmentRow>(conn)
+            })
+            .await?)
+    }
+
+    /// Delete the attachments of the email associated with given `payment_hash`
+    pub async fn delete(db: &Db, payment_hash: String) -> Result<usize> {
+        Ok(db
+            .run(move |conn| {
+                diesel::delete(attachments::table)
+                    .filter(attachments::payment_hash.eq(payment_hash))
+                    .execute(conn)
+            })
+            .await?)
+    }
+
+    /// Delete the attachments of emails whose invoice expired without being paid and of the ones
+    /// failed permanently, eg. held payments canceled after a timeout
+    pub async fn purge(db: &Db) -> Result<usize> {
+        Ok(db
+            .run(move |conn| {
+                let expired = invoices::table
+                    .select(invoices::id)
+                    .filter(invoices::expiration.lt(Utc::now().naive_utc()))
+                    .filter(
+                        invoices::state.eq_any(vec![Lifecycle::Available, Lifecycle::Reserved]),
+                    );
+                let failed = emails::table
+                    .select(emails::payment_hash)
+                    .filter(emails::state.eq(Lifecycle::Failed));
+                let expired = diesel::delete(attachments::table)
+                    .filter(attachments::payment_hash.eq_any(expired))
+                    .execute(conn)?;
+                let failed = diesel::delete(attachments::table)
+                    .filter(attachments::payment_hash.eq_any(failed))
+                    .execute(conn)?;
+                Ok::<_, diesel::result::Error>(expired + failed)
+            })
+            .await?)
+    }
+}
+
+/// Invoices added before amounts were recorded have a null `amount_msat`, read it from the bolt11.
+///
+/// Amountless or unparsable invoices get 0, which no email matches, so that every row is parsed
+/// only once and not again at each start
+fn backfill_amounts(conn: &SqliteConnection) -> QueryResult<usize> {
+    let rows = invoices::table
+        .filter(invoices::amount_msat.is_null())
+        .select((invoices::id, invoices::bolt11))
+        .load::<(String, String)>(conn)?;
+    let backfilled = rows.len();
+    for (id, bolt11) in rows {
+        let amount_msat = match bolt11.parse::<Invoice>() {
+            Ok(invoice) => invoice.amount_milli_satoshis().unwrap_or(0) as i64,
+            Err(_) => 0,
+        };
+        diesel::update(invoices::table.find(id))
+            .set(invoices::amount_msat.eq(amount_msat))
+            .execute(conn)?;
+    }
+    Ok(backfilled)
+}
+
+/// The migrations were numbered without padding, that diesel sorts as strings (`10` before `2`).
+/// Pad the versions recorded by the databases migrated before, so that they aren't run again
+fn pad_migration_versions(conn: &SqliteConnection) -> QueryResult<usize> {
+    diesel_migrations::setup_database(conn)?;
+    diesel::sql_query(
+        "UPDATE __diesel_schema_migrations SET version = printf('%04d', version) \
+         WHERE length(version) < 4",
+    )
+    .execute(conn)
+}
+
 pub async fn run_migrations(rocket: Rocket<Build>) -> Rocket<Build> {
     // This macro from `diesel_migrations` defines an `embedded_migrations`
     // module containing a function named `run` that runs the migrations in the
@@ -198,9 +991,19 @@ pub async fn run_migrations(rocket: Rocket<Build>) -> Rocket<Build> {
     embed_migrations!("db/diesel/migrations");
 
     let conn = Db::get_one(&rocket).await.expect("database connection");
+    conn.run(|c| pad_migration_versions(c))
+        .await
+        .expect("pad migration versions");
     conn.run(|c| embedded_migrations::run(c))
         .await
         .expect("diesel migrations");
+    let backfilled = conn
+        .run(|c| backfill_amounts(c))
+        .await
+        .expect("backfill invoice amounts");
+    if backfilled > 0 {
+        println!("backfilled the amount of {} invoices", backfilled);
+    }
 
     rocket
 }
@@ -226,3 +1029,74 @@ mod my_date_format {
         NaiveDateTime::parse_from_str(&s, FORMAT).map_err(serde::de::Error::custom)
     }
 }
+
+mod my_option_date_format {
+    use chrono::NaiveDateTime;
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+    pub fn serialize<S>(date: &Option<NaiveDateTime>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date {
+            Some(date) => serializer.serialize_some(&format!("{}", date.format(FORMAT))),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<NaiveDateTime>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => NaiveDateTime::parse_from_str(&s, FORMAT)
+                .map(Some)
+                .map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mailer::MemoryMailer;
+    use crate::test_util;
+    use crate::Db;
+    use diesel::RunQueryDsl;
+    use diesel_migrations::MigrationConnection;
+    use rocket::local::asynchronous::Client;
+
+    #[rocket::async_test]
+    async fn test_migration_versions() {
+        let figment = test_util::figment();
+        let client = Client::tracked(test_util::rocket(figment.clone(), MemoryMailer::default()))
+            .await
+            .unwrap();
+        // a database migrated when the versions weren't padded
+        let db = Db::get_one(client.rocket()).await.unwrap();
+        db.run(|c| {
+            diesel::sql_query(
+                "UPDATE __diesel_schema_migrations SET version = CAST(CAST(version AS INTEGER) AS TEXT)",
+            )
+            .execute(c)
+        })
+        .await
+        .unwrap();
+        drop(db);
+        drop(client);
+
+        // the migrations aren't run again
+        let client = Client::tracked(test_util::rocket(figment, MemoryMailer::default()))
+            .await
+            .unwrap();
+        let db = Db::get_one(client.rocket()).await.unwrap();
+        let versions = db
+            .run(|c| c.previously_run_migration_versions())
+            .await
+            .unwrap();
+        assert!(versions.contains("0002"));
+        assert!(versions.contains("0013"));
+        assert!(versions.iter().all(|v| v.len() == 4));
+    }
+}
@@ -12,6 +12,11 @@ const PUBLIC_KEY: &str = "age1xxzrgrfjm3yrwh3u6a7exgrldked0pdauvr3mx870wl6xzrwm5
 #[post("/encrypt", data = "<plaintext>")]
 pub fn encrypt(plaintext: &str) -> Result<String, Error> {
     let rec = Recipient::from_str(&PUBLIC_KEY).unwrap();
+    encrypt_to(rec, plaintext)
+}
+
+/// Encrypt given `plaintext` for the public key `rec` and returns encrypted data bech32 encoded
+pub fn encrypt_to(rec: Recipient, plaintext: &str) -> Result<String, Error> {
     let encryptor = age::Encryptor::with_recipients(vec![Box::new(rec)]);
 
     let mut encrypted = vec![];
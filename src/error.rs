@@ -1,7 +1,8 @@
+use crate::lifecycle::Lifecycle;
 use age::{DecryptError, EncryptError};
 use lettre::address::AddressError;
-use lettre::transport::smtp;
-use lightning_invoice::ParseOrSemanticError;
+use lettre::transport::{file, sendmail, smtp};
+use lightning_invoice::{Currency, ParseOrSemanticError};
 use qr_code::bmp_monochrome::BmpError;
 use qr_code::types::QrError;
 use rocket::http::{ContentType, Status};
@@ -22,18 +23,57 @@ pub enum Error {
     Lettre(lettre::error::Error),
     Hex(bitcoin_hashes::hex::Error),
     Smtp(smtp::Error),
+    Sendmail(sendmail::Error),
+    File(file::Error),
+    Io(io::Error),
     Qr(QrError),
     Bmp(BmpError),
     Serde(serde_json::Error),
     InvalidContentType(ContentType),
     InvoiceExpired,
+    InvoicePayeeNotAllowed(String),
+    InvoiceWrongNetwork(Currency),
+    InvoiceAmountOutOfRange(Option<u64>),
+    InvalidAlias(String),
+    InvalidStamp(String),
+    InvalidRecipientOption(String),
+    Bolt12(String),
+    OffersUnsupported,
+    HoldUnsupported,
+    PaymentsUnsupported,
+    /// The payment definitely failed and can be attempted again
+    PaymentFailed(String),
+    RateLimited(String),
     MissingTo,
     OnlyOneTo,
     MissingSubject,
     OnlyOneSubject,
     EmptyMessage,
+    TooManyAttachments,
+    InvalidAttachment(String),
     Unauthorized,
     InvoiceNotFound,
+    InvalidConfig(String),
+    InvalidTransition(Lifecycle, Lifecycle),
+    NoRelayAvailable,
+    /// The recipient or the message was rejected, as it would be by any relay
+    Rejected(String),
+    ClnRpc(i64, String),
+    LndRest(u16, String),
+    Http(reqwest::Error),
+    Base64(base64::DecodeError),
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Self {
+        Error::Base64(e)
+    }
 }
 
 impl From<serde_json::Error> for Error {
@@ -66,6 +106,24 @@ impl From<smtp::Error> for Error {
     }
 }
 
+impl From<sendmail::Error> for Error {
+    fn from(e: sendmail::Error) -> Self {
+        Error::Sendmail(e)
+    }
+}
+
+impl From<file::Error> for Error {
+    fn from(e: file::Error) -> Self {
+        Error::File(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
 impl From<lettre::error::Error> for Error {
     fn from(e: lettre::error::Error) -> Self {
         Error::Lettre(e)
@@ -111,6 +169,26 @@ impl From<diesel::result::Error> for Error {
 impl<'r> Responder<'r, 'static> for Error {
     fn respond_to(self, _request: &'r Request<'_>) -> rocket::response::Result<'static> {
         println!("{:?}", self);
+        if let Error::InvoicePayeeNotAllowed(_)
+        | Error::InvoiceWrongNetwork(_)
+        | Error::InvoiceAmountOutOfRange(_)
+        | Error::InvalidAlias(_)
+        | Error::InvalidStamp(_) = self
+        {
+            // the uploader can tell invalid data from a temporary failure
+            let body = format!("invalid request: {:?}", self);
+            return Response::build()
+                .status(Status::UnprocessableEntity)
+                .sized_body(Some(body.len()), io::Cursor::new(body))
+                .ok();
+        }
+        if let Error::RateLimited(_) = self {
+            let body = "too many requests, try later";
+            return Response::build()
+                .status(Status::TooManyRequests)
+                .sized_body(Some(body.len()), io::Cursor::new(body))
+                .ok();
+        }
         let body = "Pay2Email service has been discontinued";
         Response::build()
             .status(Status::ServiceUnavailable)
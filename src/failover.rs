@@ -0,0 +1,337 @@
+use crate::error::Result;
+use crate::mailer::Mailer;
+use crate::Error;
+use lettre::transport::smtp;
+use lettre::Message;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Stop using a relay after `threshold` consecutive failures.
+///
+/// Once tripped, the relay is skipped for `cooldown`, then a single email is let through to probe
+/// it: a success closes the breaker, a failure trips it again for another `cooldown`.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Whether the relay can be used at `now`
+    pub fn allow(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => true,
+            Some(open_until) if now >= open_until && !state.probing => {
+                state.probing = true;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub fn success(&self) {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    pub fn failure(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        state.probing = false;
+        if state.failures >= self.threshold {
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+/// A relay in the failover list
+pub struct Relay {
+    pub name: String,
+    pub mailer: Arc<dyn Mailer>,
+    pub breaker: CircuitBreaker,
+}
+
+/// Try the relays in order, skipping the ones with a tripped circuit breaker.
+///
+/// A rejection of the recipient or the message is returned only once every relay rejected it,
+/// another relay may still deliver it
+pub struct FailoverMailer(pub Vec<Relay>);
+
+/// Rejections of the recipient or the message (like an unknown recipient) aren't going to succeed
+/// on the next attempts, and don't say anything about the health of the relay
+pub fn is_permanent(error: &Error) -> bool {
+    matches!(error, Error::Rejected(_))
+}
+
+/// Tell apart the SMTP errors rejecting the recipient or the message from the ones of the relay,
+/// like a failed authentication or relaying denied, even if permanent
+pub fn rejection(e: smtp::Error) -> Error {
+    let rejected = e.is_permanent()
+        && e.status()
+            .is_some_and(|code| rejects_message(&code.to_string(), &e.to_string()));
+    if rejected {
+        Error::Rejected(e.to_string())
+    } else {
+        Error::Smtp(e)
+    }
+}
+
+/// Whether the permanent reply `code` with `text` is about the address or the content of the
+/// message. The enhanced status code (RFC 3463) of the text is used if any, only the address
+/// (5.1.x), mailbox (5.2.x), message length (5.3.4) and content (5.6.x) ones being rejections,
+/// otherwise the mailbox codes 550-553 not mentioning the relay or the authentication
+fn rejects_message(code: &str, text: &str) -> bool {
+    let enhanced = text.split_whitespace().find_map(|word| {
+        let word = word.trim_end_matches(|c: char| !c.is_ascii_digit());
+        let parts: Vec<_> = word.split('.').collect();
+        let numeric = parts
+            .iter()
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()));
+        match parts.as_slice() {
+            ["5", subject, detail] if numeric => Some((*subject, *detail)),
+            _ => None,
+        }
+    });
+    match enhanced {
+        Some((subject, detail)) => {
+            matches!(subject, "1" | "2" | "6") || (subject, detail) == ("3", "4")
+        }
+        None => {
+            let text = text.to_lowercase();
+            matches!(code, "550" | "551" | "552" | "553")
+                && !text.contains("relay")
+                && !text.contains("auth")
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for FailoverMailer {
+    async fn send(&self, message: Message) -> Result<String> {
+        let mut last_error = None;
+        let mut rejection = None;
+        let mut skipped = false;
+        for relay in self.0.iter() {
+            if !relay.breaker.allow(Instant::now()) {
+                skipped = true;
+                continue;
+            }
+            match relay.mailer.send(message.clone()).await {
+                Ok(name) => {
+                    relay.breaker.success();
+                    return Ok(name);
+                }
+                Err(e) if is_permanent(&e) => {
+                    println!("relay {} rejected: {:?}", relay.name, e);
+                    relay.breaker.success();
+                    rejection = Some(e);
+                }
+                Err(e) => {
+                    println!("relay {} failed: {:?}", relay.name, e);
+                    relay.breaker.failure(Instant::now());
+                    last_error = Some(e);
+                }
+            }
+        }
+        // the relays which failed or were skipped may deliver it on the next attempts
+        match (last_error, rejection) {
+            (Some(e), _) => Err(e),
+            (None, Some(rejection)) if !skipped => Err(rejection),
+            _ => Err(Error::NoRelayAvailable),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Result;
+    use crate::failover::{rejects_message, CircuitBreaker, FailoverMailer, Relay};
+    use crate::mailer::Mailer;
+    use crate::Error;
+    use lettre::Message;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(breaker.allow(now));
+        breaker.failure(now);
+        assert!(breaker.allow(now));
+        breaker.failure(now);
+        assert!(!breaker.allow(now));
+        assert!(!breaker.allow(now + Duration::from_secs(59)));
+
+        // only one probe after the cooldown
+        let later = now + Duration::from_secs(60);
+        assert!(breaker.allow(later));
+        assert!(!breaker.allow(later));
+
+        // failed probe trips again
+        breaker.failure(later);
+        assert!(!breaker.allow(later + Duration::from_secs(59)));
+        assert!(breaker.allow(later + Duration::from_secs(60)));
+
+        // successful probe closes
+        breaker.success();
+        assert!(breaker.allow(later));
+        assert!(breaker.allow(later));
+    }
+
+    #[test]
+    fn test_rejects_message() {
+        assert!(rejects_message(
+            "550",
+            "5.1.1 <b@example.com>: user unknown"
+        ));
+        assert!(rejects_message("552", "5.2.2 mailbox full"));
+        assert!(rejects_message("554", "5.6.0 message content rejected"));
+        assert!(rejects_message("550", "no such user here"));
+        // the relay refusing the sender
+        assert!(!rejects_message(
+            "535",
+            "5.7.8 authentication credentials invalid"
+        ));
+        assert!(!rejects_message(
+            "554",
+            "5.7.1 <b@example.com>: relay access denied"
+        ));
+        assert!(!rejects_message("550", "relaying denied"));
+        assert!(!rejects_message("530", "authentication required"));
+        assert!(!rejects_message("554", "transaction failed"));
+    }
+
+    struct TestMailer {
+        name: &'static str,
+        up: AtomicBool,
+        calls: AtomicUsize,
+    }
+
+    #[rocket::async_trait]
+    impl Mailer for TestMailer {
+        async fn send(&self, _message: Message) -> Result<String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.up.load(Ordering::SeqCst) {
+                Ok(self.name.to_string())
+            } else {
+                Err(Error::Io(io::Error::other("down")))
+            }
+        }
+    }
+
+    fn relay(name: &'static str, up: bool) -> (Relay, Arc<TestMailer>) {
+        let mailer = Arc::new(TestMailer {
+            name,
+            up: AtomicBool::new(up),
+            calls: AtomicUsize::new(0),
+        });
+        let relay = Relay {
+            name: name.to_string(),
+            mailer: mailer.clone(),
+            breaker: CircuitBreaker::new(2, Duration::from_secs(3600)),
+        };
+        (relay, mailer)
+    }
+
+    #[rocket::async_test]
+    async fn test_failover() {
+        let message = Message::builder()
+            .from("a@example.com".parse().unwrap())
+            .to("b@example.com".parse().unwrap())
+            .body("Hello".to_string())
+            .unwrap();
+        let (first, first_mailer) = relay("first", false);
+        let (second, second_mailer) = relay("second", true);
+        let failover = FailoverMailer(vec![first, second]);
+
+        for _ in 0..3 {
+            assert_eq!(failover.send(message.clone()).await.unwrap(), "second");
+        }
+        // the first relay is tripped after 2 failures
+        assert_eq!(first_mailer.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(second_mailer.calls.load(Ordering::SeqCst), 3);
+
+        second_mailer.up.store(false, Ordering::SeqCst);
+        assert!(failover.send(message.clone()).await.is_err());
+        assert!(failover.send(message.clone()).await.is_err());
+        assert!(matches!(
+            failover.send(message).await,
+            Err(Error::NoRelayAvailable)
+        ));
+        assert_eq!(second_mailer.calls.load(Ordering::SeqCst), 5);
+    }
+
+    struct RejectingMailer(AtomicUsize);
+
+    #[rocket::async_trait]
+    impl Mailer for RejectingMailer {
+        async fn send(&self, _message: Message) -> Result<String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(Error::Rejected("550 5.1.1 user unknown".to_string()))
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_rejected() {
+        let message = Message::builder()
+            .from("a@example.com".parse().unwrap())
+            .to("b@example.com".parse().unwrap())
+            .body("Hello".to_string())
+            .unwrap();
+        let (down, down_mailer) = relay("down", false);
+        let rejecting = Arc::new(RejectingMailer(AtomicUsize::new(0)));
+        let (up, up_mailer) = relay("up", true);
+        let failover = FailoverMailer(vec![
+            down,
+            Relay {
+                name: "rejecting".to_string(),
+                mailer: rejecting.clone(),
+                breaker: CircuitBreaker::new(1, Duration::from_secs(3600)),
+            },
+            up,
+        ]);
+
+        // relay failures and rejections move on to the next relay
+        for _ in 0..3 {
+            assert_eq!(failover.send(message.clone()).await.unwrap(), "up");
+        }
+        assert_eq!(down_mailer.calls.load(Ordering::SeqCst), 2);
+        // rejections don't trip the breaker of the relay
+        assert_eq!(rejecting.0.load(Ordering::SeqCst), 3);
+        assert_eq!(up_mailer.calls.load(Ordering::SeqCst), 3);
+
+        // the rejection is returned once every relay rejected it
+        up_mailer.up.store(false, Ordering::SeqCst);
+        assert!(matches!(
+            failover.send(message.clone()).await,
+            Err(Error::Io(_))
+        ));
+        let failover = FailoverMailer(vec![Relay {
+            name: "rejecting".to_string(),
+            mailer: rejecting.clone(),
+            breaker: CircuitBreaker::new(1, Duration::from_secs(3600)),
+        }]);
+        assert!(matches!(
+            failover.send(message).await,
+            Err(Error::Rejected(_))
+        ));
+    }
+}
@@ -0,0 +1,448 @@
+use crate::backend::{
+    self, add_reserved, InvoiceBackend, InvoiceStatus, OfferPayment, PaymentStatus,
+};
+use crate::db::{EmailRow, HoldRow, InvoiceRow};
+use crate::error::Result;
+use crate::lifecycle::Lifecycle;
+use crate::queue::Queue;
+use crate::{Db, Error};
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::{sha256, Hash};
+use chrono::Utc;
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use rocket::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Hold invoices settings, read from the optional `hold` table of the Rocket config. Available as
+/// managed state. Durations are in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct HoldConfig {
+    /// Create hold invoices, settled only once the email is delivered. Requires a backend
+    /// supporting them
+    pub enabled: bool,
+
+    /// Held payments whose email is not delivered in time are canceled, before the node is forced
+    /// to by the expiry of the HTLC
+    pub timeout: u64,
+
+    /// How often the held payments are checked for the delivery of their email
+    pub interval: u64,
+}
+
+impl Default for HoldConfig {
+    fn default() -> Self {
+        HoldConfig {
+            enabled: false,
+            timeout: 60 * 60,
+            interval: 5,
+        }
+    }
+}
+
+/// Creates hold invoices on the wrapped backend, keeping their preimages in db
+pub struct HoldBackend(Arc<dyn InvoiceBackend>);
+
+impl HoldBackend {
+    pub fn new(backend: Arc<dyn InvoiceBackend>) -> Self {
+        HoldBackend(backend)
+    }
+}
+
+#[rocket::async_trait]
+impl InvoiceBackend for HoldBackend {
+    async fn create_invoice(
+        &self,
+        db: &Db,
+        amount_msat: u64,
+        hashed_description: Option<&str>,
+        email_row: EmailRow,
+    ) -> Result<InvoiceRow> {
+        let preimage: [u8; 32] = rand::random();
+        let payment_hash = sha256::Hash::hash(&preimage).to_hex();
+        let hold_row = HoldRow {
+            payment_hash: payment_hash.clone(),
+            preimage: preimage.to_hex(),
+            accepted_at: None,
+            resolved_at: None,
+        };
+        HoldRow::add(db, hold_row).await?;
+        let bolt11 = self
+            .0
+            .create_hold_invoice(amount_msat, &payment_hash, hashed_description)
+            .await?;
+        if InvoiceRow::from_bolt11(bolt11.clone())?.id != payment_hash {
+            return Err(Error::InvoiceNotFound);
+        }
+        add_reserved(db, bolt11, email_row).await
+    }
+
+    fn supports_description_hash(&self) -> bool {
+        self.0.supports_description_hash()
+    }
+
+    fn exact_amounts(&self) -> bool {
+        self.0.exact_amounts()
+    }
+
+    async fn lookup_invoice(&self, db: &Db, payment_hash: &str) -> Result<InvoiceStatus> {
+        self.0.lookup_invoice(db, payment_hash).await
+    }
+
+    async fn subscribe(&self, settled: mpsc::Sender<String>) -> Result<()> {
+        self.0.subscribe(settled).await
+    }
+
+    fn supports_offers(&self) -> bool {
+        self.0.supports_offers()
+    }
+
+    async fn create_offer(&self, amount_msat: u64, description: &str) -> Result<(String, String)> {
+        self.0.create_offer(amount_msat, description).await
+    }
+
+    async fn lookup_offer_payment(&self, payment_hash: &str) -> Result<Option<OfferPayment>> {
+        self.0.lookup_offer_payment(payment_hash).await
+    }
+
+    fn supports_hold(&self) -> bool {
+        true
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        amount_msat: u64,
+        payment_hash: &str,
+        hashed_description: Option<&str>,
+    ) -> Result<String> {
+        self.0
+            .create_hold_invoice(amount_msat, payment_hash, hashed_description)
+            .await
+    }
+
+    async fn settle_hold_invoice(&self, preimage: &str) -> Result<()> {
+        self.0.settle_hold_invoice(preimage).await
+    }
+
+    async fn cancel_hold_invoice(&self, payment_hash: &str) -> Result<()> {
+        self.0.cancel_hold_invoice(payment_hash).await
+    }
+
+    fn supports_payments(&self) -> bool {
+        self.0.supports_payments()
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<()> {
+        self.0.pay_invoice(bolt11).await
+    }
+
+    async fn lookup_payment(&self, bolt11: &str) -> Result<Option<PaymentStatus>> {
+        self.0.lookup_payment(bolt11).await
+    }
+}
+
+/// Enqueue the email of the held payment to `payment_hash`, its invoice stays reserved until the
+/// payment is settled.
+///
+/// Accepting an already accepted payment doesn't enqueue the email again
+pub async fn accept(
+    db: &Db,
+    queue: &Queue,
+    payment_hash: String,
+) -> Result<(InvoiceRow, EmailRow)> {
+    let mut hold_row = HoldRow::get(db, payment_hash.clone()).await?;
+    let invoice = InvoiceRow::get(db, payment_hash.clone()).await?;
+    let mut email_row = EmailRow::get(db, payment_hash).await?;
+    if hold_row.set_accepted(db).await? && email_row.enqueue(db).await? {
+        queue.wake();
+    }
+    Ok((invoice, email_row))
+}
+
+/// Settle the held payments whose email has been delivered and cancel the ones whose email failed
+/// or timed out, returns the number of resolved payments. A payment failing to resolve is retried
+/// at the next call
+pub async fn resolve(
+    db: &Db,
+    backend: &dyn InvoiceBackend,
+    queue: &Queue,
+    timeout: Duration,
+) -> Result<usize> {
+    let mut resolved = 0;
+    let timeout =
+        chrono::Duration::from_std(timeout).unwrap_or_else(|_| chrono::Duration::max_value());
+    for mut hold_row in HoldRow::list_accepted(db).await? {
+        let payment_hash = hold_row.payment_hash.clone();
+        match resolve_payment(db, backend, queue, &mut hold_row, timeout).await {
+            Ok(true) => resolved += 1,
+            Ok(false) => {}
+            Err(e) => println!("resolving held invoice {}: {:?}", payment_hash, e),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Settle or cancel the held payment of `hold_row` if its email is resolved, returns whether it
+/// was
+async fn resolve_payment(
+    db: &Db,
+    backend: &dyn InvoiceBackend,
+    queue: &Queue,
+    hold_row: &mut HoldRow,
+    timeout: chrono::Duration,
+) -> Result<bool> {
+    let payment_hash = hold_row.payment_hash.clone();
+    let mut email_row = EmailRow::get(db, payment_hash.clone()).await?;
+    let timed_out = matches!(
+        hold_row.accepted_at,
+        Some(accepted_at) if accepted_at + timeout < Utc::now().naive_utc()
+    );
+    let state = email_row.state;
+    match state {
+        Lifecycle::Sent => {
+            let result = backend.settle_hold_invoice(&hold_row.preimage).await;
+            or_already_resolved(db, backend, &payment_hash, result, true).await?;
+            backend::settle(db, queue, payment_hash.clone()).await?;
+            println!("held invoice {} settled", payment_hash);
+        }
+        Lifecycle::Failed => {
+            let result = backend.cancel_hold_invoice(&payment_hash).await;
+            or_already_resolved(db, backend, &payment_hash, result, false).await?;
+            println!("held invoice {} canceled", payment_hash);
+        }
+        // the email may be delivered concurrently, in which case it's not failed
+        _ if timed_out && email_row.set_state(db, Lifecycle::Failed).await? => {
+            let result = backend.cancel_hold_invoice(&payment_hash).await;
+            or_already_resolved(db, backend, &payment_hash, result, false).await?;
+            println!("held invoice {} canceled after timeout", payment_hash);
+        }
+        _ => return Ok(false),
+    }
+    hold_row.set_resolved(db).await?;
+    Ok(true)
+}
+
+/// The `result` of settling, or canceling if not `settled`, the held payment to `payment_hash`,
+/// successful if it failed because the node already did it, eg. before a restart
+async fn or_already_resolved(
+    db: &Db,
+    backend: &dyn InvoiceBackend,
+    payment_hash: &str,
+    result: Result<()>,
+    settled: bool,
+) -> Result<()> {
+    let e = match result {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    match backend.lookup_invoice(db, payment_hash).await {
+        Ok(InvoiceStatus::Settled(_)) if settled => Ok(()),
+        Ok(InvoiceStatus::Canceled) if !settled => Ok(()),
+        _ => Err(e),
+    }
+}
+
+/// Resolve the held payments every `interval`
+async fn run(
+    db: Db,
+    backend: Arc<dyn InvoiceBackend>,
+    queue: Queue,
+    config: HoldConfig,
+    shutdown: Shutdown,
+) {
+    let interval = Duration::from_secs(config.interval);
+    let timeout = Duration::from_secs(config.timeout);
+    loop {
+        if let Err(e) = resolve(&db, backend.as_ref(), &queue, timeout).await {
+            println!("held invoices: {:?}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            _ = shutdown.clone() => break,
+        }
+    }
+}
+
+/// Read the hold invoices config, making it available as managed state, and if enabled resolve the
+/// held payments from liftoff
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Hold Invoices", |rocket| async {
+        let config = match rocket.figment().focus("hold").extract::<HoldConfig>() {
+            Ok(config) => config,
+            Err(e) => {
+                let e = Error::InvalidConfig(e.to_string());
+                println!("invalid hold config: {:?}", e);
+                return Err(rocket);
+            }
+        };
+        let enabled = config.enabled;
+        let rocket = rocket.manage(config);
+        if !enabled {
+            return Ok(rocket);
+        }
+        Ok(
+            rocket.attach(AdHoc::on_liftoff("Hold Invoices Resolution", |rocket| {
+                Box::pin(async move {
+                    let db = Db::get_one(rocket).await.expect("database connection");
+                    let backend = rocket
+                        .state::<Arc<dyn InvoiceBackend>>()
+                        .expect("invoice backend")
+                        .clone();
+                    let queue = rocket.state::<Queue>().expect("queue").clone();
+                    let config = rocket.state::<HoldConfig>().expect("hold config").clone();
+                    tokio::spawn(run(db, backend, queue, config, rocket.shutdown()));
+                })
+            })),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::{self, InvoiceBackend, InvoiceStatus};
+    use crate::db::HoldRow;
+    use crate::hold::{self, HoldBackend};
+    use crate::mailer::{Mailer, MemoryMailer};
+    use crate::queue::Queue;
+    use crate::test_util::{self, FailingMailer, MockBackend};
+    use crate::Db;
+    use rocket::figment::Figment;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client;
+    use rocket::{Build, Rocket};
+    use serde_json::Value;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn figment() -> Figment {
+        test_util::figment()
+            .merge(("hold.enabled", true))
+            .merge(("hold.interval", 1))
+            .merge(("queue.max_attempts", 1))
+    }
+
+    fn rocket(mailer: Arc<dyn Mailer>, backend: Arc<MockBackend>) -> Rocket<Build> {
+        let backend = Arc::new(HoldBackend::new(backend));
+        test_util::rocket_with(figment(), mailer, backend)
+    }
+
+    /// Reserve an email, returns the payment hash of its hold invoice
+    async fn reserve(client: &Client) -> String {
+        let json: Value = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body("to=a%40example.com&subject=Hi&message=Hello")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        json["payment_hash"].as_str().unwrap().to_string()
+    }
+
+    async fn info(client: &Client, payment_hash: &str) -> Value {
+        client
+            .post("/info")
+            .body(payment_hash)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap()
+    }
+
+    /// Wait until the mock node has resolved the invoice paying to `payment_hash`
+    async fn wait_resolved(backend: &MockBackend, payment_hash: &str) -> InvoiceStatus {
+        for _ in 0..50 {
+            match backend.status(payment_hash) {
+                Some(InvoiceStatus::Accepted) => {}
+                Some(status) => return status,
+                None => panic!("unknown invoice"),
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("invoice {} not resolved", payment_hash)
+    }
+
+    #[rocket::async_test]
+    async fn test_hold_settled() {
+        let mailer = MemoryMailer::default();
+        let backend = Arc::new(MockBackend::default());
+        let client = Client::tracked(rocket(Arc::new(mailer.clone()), backend.clone()))
+            .await
+            .unwrap();
+        let payment_hash = reserve(&client).await;
+        assert_eq!(backend.status(&payment_hash), Some(InvoiceStatus::Open));
+        assert_eq!(info(&client, &payment_hash).await["state"], "reserved");
+
+        // the payment is held, the email is sent before the invoice is settled
+        backend.pay(&payment_hash);
+        let json = info(&client, &payment_hash).await;
+        assert_eq!(json["invoice_paid"], false);
+        assert_eq!(json["state"], "queued");
+        test_util::wait_messages(&mailer, 1).await;
+
+        match wait_resolved(&backend, &payment_hash).await {
+            InvoiceStatus::Settled(preimage) => {
+                assert_eq!(backend::payment_hash(&preimage).unwrap(), payment_hash)
+            }
+            status => panic!("unexpected status {:?}", status),
+        }
+        let json = info(&client, &payment_hash).await;
+        assert_eq!(json["invoice_paid"], true);
+        assert_eq!(json["email_sent"], true);
+    }
+
+    #[rocket::async_test]
+    async fn test_hold_canceled() {
+        let backend = Arc::new(MockBackend::default());
+        let client = Client::tracked(rocket(Arc::new(FailingMailer), backend.clone()))
+            .await
+            .unwrap();
+        let payment_hash = reserve(&client).await;
+        backend.pay(&payment_hash);
+        info(&client, &payment_hash).await;
+
+        // the only delivery attempt fails, the payer is refunded
+        assert_eq!(
+            wait_resolved(&backend, &payment_hash).await,
+            InvoiceStatus::Canceled
+        );
+        let json = info(&client, &payment_hash).await;
+        assert_eq!(json["invoice_paid"], false);
+        assert_eq!(json["state"], "failed");
+    }
+
+    #[rocket::async_test]
+    async fn test_resolve_errors() {
+        let mailer = MemoryMailer::default();
+        let backend = Arc::new(MockBackend::default());
+        let hold_backend = Arc::new(HoldBackend::new(backend.clone()));
+        let figment = figment().merge(("hold.enabled", false));
+        let rocket = test_util::rocket_with(figment, Arc::new(mailer.clone()), hold_backend);
+        let client = Client::tracked(rocket).await.unwrap();
+        let (failing, settled) = (reserve(&client).await, reserve(&client).await);
+        for payment_hash in [&failing, &settled] {
+            backend.pay(payment_hash);
+            info(&client, payment_hash).await;
+        }
+        test_util::wait_messages(&mailer, 2).await;
+
+        // the first payment can't be settled anymore, the second was settled before a restart
+        let db = Db::get_one(client.rocket()).await.unwrap();
+        backend.cancel_hold_invoice(&failing).await.unwrap();
+        let preimage = HoldRow::get(&db, settled.clone()).await.unwrap().preimage;
+        backend.settle_hold_invoice(&preimage).await.unwrap();
+
+        let queue = client.rocket().state::<Queue>().unwrap();
+        let timeout = Duration::from_secs(3600);
+        let resolved = hold::resolve(&db, backend.as_ref(), queue, timeout).await;
+        assert_eq!(resolved.unwrap(), 1);
+        assert_eq!(info(&client, &settled).await["invoice_paid"], true);
+        assert_eq!(info(&client, &failing).await["invoice_paid"], false);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::error::Result;
+use crate::Error;
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
+use rocket::serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The state of an invoice and of the email it pays for, persisted as text in the `state` column
+/// of both tables.
+///
+/// An invoice goes `Available` → `Reserved` (showed to a sender) → `Paid`.
+/// The email is created `Reserved` together with its invoice, then goes `Paid` → `Queued` when
+/// the payment is notified and finally `Sent` or `Failed` after the delivery attempts.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, AsExpression, FromSqlRow,
+)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+#[sql_type = "Text"]
+pub enum Lifecycle {
+    Available,
+    Reserved,
+    Paid,
+    Queued,
+    Sent,
+    Failed,
+}
+
+impl Lifecycle {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Lifecycle::Available => "available",
+            Lifecycle::Reserved => "reserved",
+            Lifecycle::Paid => "paid",
+            Lifecycle::Queued => "queued",
+            Lifecycle::Sent => "sent",
+            Lifecycle::Failed => "failed",
+        }
+    }
+
+    /// Position in the lifecycle, terminal states share the last one
+    fn rank(&self) -> u8 {
+        match self {
+            Lifecycle::Available => 0,
+            Lifecycle::Reserved => 1,
+            Lifecycle::Paid => 2,
+            Lifecycle::Queued => 3,
+            Lifecycle::Sent | Lifecycle::Failed => 4,
+        }
+    }
+
+    /// Check the transition from `self` to `next`.
+    ///
+    /// Returns `Some(next)` if the transition must be applied, `None` if `self` is already `next`
+    /// or a later state, so that repeated or late notifications are harmless no-ops, and an error
+    /// if the transition skips states or goes from a terminal state to the other.
+    pub fn transition(self, next: Lifecycle) -> Result<Option<Lifecycle>> {
+        if self == next || (self.rank() > next.rank()) {
+            Ok(None)
+        } else if self.rank() + 1 == next.rank() {
+            Ok(Some(next))
+        } else {
+            Err(Error::InvalidTransition(self, next))
+        }
+    }
+}
+
+impl fmt::Display for Lifecycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Lifecycle {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "available" => Lifecycle::Available,
+            "reserved" => Lifecycle::Reserved,
+            "paid" => Lifecycle::Paid,
+            "queued" => Lifecycle::Queued,
+            "sent" => Lifecycle::Sent,
+            "failed" => Lifecycle::Failed,
+            _ => return Err(format!("invalid lifecycle state: {}", s)),
+        })
+    }
+}
+
+impl<DB: Backend> ToSql<Text, DB> for Lifecycle
+where
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
+        self.as_str().to_sql(out)
+    }
+}
+
+impl<DB: Backend> FromSql<Text, DB> for Lifecycle
+where
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
+        Ok(String::from_sql(bytes)?.parse()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lifecycle::Lifecycle::*;
+
+    #[test]
+    fn test_transition() {
+        assert_eq!(Available.transition(Reserved).unwrap(), Some(Reserved));
+        assert_eq!(Reserved.transition(Paid).unwrap(), Some(Paid));
+        assert_eq!(Paid.transition(Queued).unwrap(), Some(Queued));
+        assert_eq!(Queued.transition(Sent).unwrap(), Some(Sent));
+        assert_eq!(Queued.transition(Failed).unwrap(), Some(Failed));
+
+        // repeated notifications
+        assert_eq!(Paid.transition(Paid).unwrap(), None);
+        assert_eq!(Sent.transition(Paid).unwrap(), None);
+        assert_eq!(Queued.transition(Queued).unwrap(), None);
+
+        // skipping states
+        assert!(Available.transition(Paid).is_err());
+        assert!(Reserved.transition(Sent).is_err());
+
+        // terminal states
+        assert!(Sent.transition(Failed).is_err());
+        assert!(Failed.transition(Sent).is_err());
+    }
+
+    #[test]
+    fn test_str_roundtrip() {
+        for state in [Available, Reserved, Paid, Queued, Sent, Failed] {
+            assert_eq!(state.as_str().parse::<super::Lifecycle>().unwrap(), state);
+            assert_eq!(
+                serde_json::to_string(&state).unwrap(),
+                format!("\"{}\"", state)
+            );
+        }
+        assert!("showed".parse::<super::Lifecycle>().is_err());
+    }
+}
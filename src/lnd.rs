@@ -0,0 +1,375 @@
+use crate::backend::{add_reserved, InvoiceBackend, InvoiceStatus, PaymentStatus};
+use crate::db::{EmailRow, InvoiceRow};
+use crate::error::Result;
+use crate::{Db, Error};
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::{sha256, Hash};
+use reqwest::{Certificate, Client, RequestBuilder, Response};
+use rocket::serde::{Deserialize, DeserializeOwned};
+use serde_json::{json, Value};
+use std::io;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+
+/// LND node creating an invoice for every email through its REST API, read from the `lnd` table
+/// of the Rocket config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LndConfig {
+    /// Base url of the REST API, eg. `https://localhost:8080`
+    pub url: String,
+
+    /// Macaroon with permissions to create and read invoices, eg. `invoice.macaroon`
+    pub macaroon_file: PathBuf,
+
+    /// Certificate of the node, when not signed by a trusted authority
+    pub tls_cert_file: Option<PathBuf>,
+
+    /// Seconds before the invoices expire
+    #[serde(default = "crate::backend::default_expiry")]
+    pub expiry: u64,
+
+    /// Description of the invoices
+    #[serde(default = "crate::backend::default_description")]
+    pub description: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AddInvoiceResult {
+    payment_request: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct LndInvoice {
+    /// base64 of the payment hash
+    r_hash: String,
+    /// base64 of the preimage, when settled
+    r_preimage: Option<String>,
+    state: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SendPaymentResult {
+    #[serde(default)]
+    payment_error: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct LndPayment {
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct StreamLine<T> {
+    result: Option<T>,
+    error: Option<Value>,
+}
+
+pub struct LndClient {
+    config: LndConfig,
+    macaroon: String,
+    client: Client,
+}
+
+impl LndClient {
+    pub fn new(config: LndConfig) -> Result<Self> {
+        let macaroon = std::fs::read(&config.macaroon_file)?.to_hex();
+        let mut builder = Client::builder();
+        if let Some(path) = config.tls_cert_file.as_ref() {
+            builder = builder.add_root_certificate(Certificate::from_pem(&std::fs::read(path)?)?);
+        }
+        Ok(LndClient {
+            macaroon,
+            client: builder.build()?,
+            config,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.url.trim_end_matches('/'), path)
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let response = request
+            .header("Grpc-Metadata-macaroon", &self.macaroon)
+            .send()
+            .await?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(response)
+        } else {
+            Err(Error::LndRest(status.as_u16(), response.text().await?))
+        }
+    }
+
+    async fn json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        Ok(self.send(request).await?.json().await?)
+    }
+
+    /// The `memo` field of the invoices, or their `description_hash` committing to
+    /// `hashed_description`
+    fn description(&self, hashed_description: Option<&str>) -> (&'static str, String) {
+        match hashed_description {
+            Some(description) => {
+                let hash = sha256::Hash::hash(description.as_bytes());
+                ("description_hash", base64::encode(hash))
+            }
+            None => ("memo", self.config.description.clone()),
+        }
+    }
+
+    /// Create an invoice of `amount_msat`, committing to the hash of `hashed_description` if any,
+    /// returns its bolt11
+    pub async fn invoice(
+        &self,
+        amount_msat: u64,
+        hashed_description: Option<&str>,
+    ) -> Result<String> {
+        let mut body = json!({
+            "value_msat": amount_msat.to_string(),
+            "expiry": self.config.expiry.to_string(),
+        });
+        let (key, description) = self.description(hashed_description);
+        body[key] = description.into();
+        let request = self.client.post(self.url("/v1/invoices")).json(&body);
+        let result: AddInvoiceResult = self.json(request).await?;
+        Ok(result.payment_request)
+    }
+}
+
+fn status(invoice: &LndInvoice) -> Result<InvoiceStatus> {
+    Ok(match invoice.state.as_str() {
+        "SETTLED" => {
+            let preimage = invoice.r_preimage.as_deref().unwrap_or_default();
+            InvoiceStatus::Settled(base64::decode(preimage)?.to_hex())
+        }
+        "ACCEPTED" => InvoiceStatus::Accepted,
+        "CANCELED" => InvoiceStatus::Canceled,
+        _ => InvoiceStatus::Open,
+    })
+}
+
+#[rocket::async_trait]
+impl InvoiceBackend for LndClient {
+    async fn create_invoice(
+        &self,
+        db: &Db,
+        amount_msat: u64,
+        hashed_description: Option<&str>,
+        email_row: EmailRow,
+    ) -> Result<InvoiceRow> {
+        let bolt11 = self.invoice(amount_msat, hashed_description).await?;
+        add_reserved(db, bolt11, email_row).await
+    }
+
+    fn supports_description_hash(&self) -> bool {
+        true
+    }
+
+    async fn lookup_invoice(&self, _db: &Db, payment_hash: &str) -> Result<InvoiceStatus> {
+        let path = format!("/v1/invoice/{}", payment_hash);
+        let invoice: LndInvoice = self.json(self.client.get(self.url(&path))).await?;
+        status(&invoice)
+    }
+
+    fn supports_hold(&self) -> bool {
+        true
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        amount_msat: u64,
+        payment_hash: &str,
+        hashed_description: Option<&str>,
+    ) -> Result<String> {
+        let mut body = json!({
+            "hash": base64::encode(Vec::<u8>::from_hex(payment_hash)?),
+            "value_msat": amount_msat.to_string(),
+            "expiry": self.config.expiry.to_string(),
+        });
+        let (key, description) = self.description(hashed_description);
+        body[key] = description.into();
+        let request = self.client.post(self.url("/v2/invoices/hodl")).json(&body);
+        let result: AddInvoiceResult = self.json(request).await?;
+        Ok(result.payment_request)
+    }
+
+    async fn settle_hold_invoice(&self, preimage: &str) -> Result<()> {
+        let body = json!({ "preimage": base64::encode(Vec::<u8>::from_hex(preimage)?) });
+        let request = self
+            .client
+            .post(self.url("/v2/invoices/settle"))
+            .json(&body);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    async fn cancel_hold_invoice(&self, payment_hash: &str) -> Result<()> {
+        let body = json!({ "payment_hash": base64::encode(Vec::<u8>::from_hex(payment_hash)?) });
+        let request = self
+            .client
+            .post(self.url("/v2/invoices/cancel"))
+            .json(&body);
+        self.send(request).await?;
+        Ok(())
+    }
+
+    fn supports_payments(&self) -> bool {
+        true
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<()> {
+        let body = json!({ "payment_request": bolt11 });
+        let request = self
+            .client
+            .post(self.url("/v1/channels/transactions"))
+            .json(&body);
+        let result: SendPaymentResult = self.json(request).await?;
+        if result.payment_error.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PaymentFailed(result.payment_error))
+        }
+    }
+
+    /// The first update of `TrackPaymentV2` is the current status of the payment
+    async fn lookup_payment(&self, bolt11: &str) -> Result<Option<PaymentStatus>> {
+        let payment_hash = Vec::<u8>::from_hex(&InvoiceRow::from_bolt11(bolt11.to_string())?.id)?;
+        let hash = base64::encode_config(payment_hash, base64::URL_SAFE);
+        let request = self
+            .client
+            .get(self.url(&format!("/v2/router/track/{}", hash)));
+        let mut response = match self.send(request).await {
+            Ok(response) => response,
+            Err(Error::LndRest(404, _)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut buffer = vec![];
+        let end = loop {
+            if let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                break end;
+            }
+            match response.chunk().await? {
+                Some(chunk) => buffer.extend_from_slice(&chunk),
+                None => break buffer.len(),
+            }
+        };
+        let line: StreamLine<LndPayment> = serde_json::from_slice(&buffer[..end])?;
+        match (line.result, line.error) {
+            (_, Some(error)) if error["http_code"] == 404 => Ok(None),
+            (_, Some(error)) => Err(Error::LndRest(0, error.to_string())),
+            (Some(payment), None) => Ok(Some(match payment.status.as_str() {
+                "SUCCEEDED" => PaymentStatus::Complete,
+                "FAILED" => PaymentStatus::Failed,
+                _ => PaymentStatus::Pending,
+            })),
+            (None, None) => Err(Error::LndRest(0, "empty payment update".to_string())),
+        }
+    }
+
+    /// Read the stream of invoice updates, one json object per line
+    async fn subscribe(&self, settled: mpsc::Sender<String>) -> Result<()> {
+        let request = self.client.get(self.url("/v1/invoices/subscribe"));
+        let mut response = self.send(request).await?;
+        let mut buffer = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let line: StreamLine<LndInvoice> = serde_json::from_slice(&line)?;
+                if let Some(error) = line.error {
+                    return Err(Error::LndRest(0, error.to_string()));
+                }
+                match line.result {
+                    Some(invoice) if invoice.state == "SETTLED" || invoice.state == "ACCEPTED" => {
+                        let payment_hash = base64::decode(&invoice.r_hash)?.to_hex();
+                        if settled.send(payment_hash).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::{InvoiceBackend, InvoiceStatus};
+    use crate::lnd::{status, LndClient, LndConfig, LndInvoice};
+    use crate::test_util;
+    use bitcoin_hashes::hex::ToHex;
+    use lightning_invoice::Invoice;
+
+    fn client(url: String) -> LndClient {
+        let macaroon_file = test_util::temp_path("pay2email-macaroon");
+        std::fs::write(&macaroon_file, [0xab, 0xcd]).unwrap();
+        LndClient::new(LndConfig {
+            url,
+            macaroon_file,
+            tls_cert_file: None,
+            expiry: 3600,
+            description: "pay2.email".to_string(),
+        })
+        .unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_invoice() {
+        let client = client(test_util::lnd_server());
+        let bolt11 = client.invoice(21_000, None).await.unwrap();
+        let invoice: Invoice = bolt11.parse().unwrap();
+        assert_eq!(invoice.amount_milli_satoshis(), Some(21_000));
+
+        let bolt11 = client.invoice(21_000, Some("metadata")).await.unwrap();
+        assert_eq!(
+            test_util::invoice_description_hash(&bolt11),
+            test_util::description_hash(Some("metadata"))
+        );
+
+        // the stand-in node requires the macaroon
+        let mut unauthorized = client;
+        unauthorized.macaroon = "00".to_string();
+        assert!(unauthorized.invoice(21_000, None).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_subscribe() {
+        let client = client(test_util::lnd_server());
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        // the stand-in node closes the stream after the updates
+        assert!(client.subscribe(sender).await.is_err());
+        assert_eq!(
+            receiver.recv().await,
+            Some(test_util::payment_hash(test_util::lnd_preimage(1)))
+        );
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[test]
+    fn test_status() {
+        let invoice = |state: &str, r_preimage: Option<String>| LndInvoice {
+            r_hash: String::new(),
+            r_preimage,
+            state: state.to_string(),
+        };
+        let status = |invoice| status(&invoice).unwrap();
+        assert_eq!(status(invoice("OPEN", None)), InvoiceStatus::Open);
+        assert_eq!(status(invoice("ACCEPTED", None)), InvoiceStatus::Accepted);
+        assert_eq!(
+            status(invoice("SETTLED", Some(base64::encode([1u8; 32])))),
+            InvoiceStatus::Settled([1u8; 32].to_hex())
+        );
+        assert_eq!(status(invoice("CANCELED", None)), InvoiceStatus::Canceled);
+    }
+}
@@ -0,0 +1,536 @@
+use crate::backend::InvoiceBackend;
+use crate::db::{AliasRow, EmailRow, InvoiceRow};
+use crate::error::Result;
+use crate::lifecycle::Lifecycle;
+use crate::ratelimit::RateLimit;
+use crate::routes::HttpAuth;
+use crate::{Db, Error};
+use bech32::{ToBase32, Variant};
+use chrono::Utc;
+use lettre::message::Mailboxes;
+use rocket::fairing::AdHoc;
+use rocket::response::status::Created;
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::State;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Maximum length in characters of the comments paid to the Lightning Addresses (LUD-12)
+const COMMENT_ALLOWED: usize = 500;
+
+/// LNURL settings, read from the optional `lnurl` table of the Rocket config. Available as managed
+/// state.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct LnurlConfig {
+    /// Url where the service is reachable by the wallets, used in the LNURL links. Its host is the
+    /// domain of the Lightning Addresses
+    pub public_url: String,
+
+    /// Maximum amount payable to the Lightning Addresses
+    pub max_sendable_msat: u64,
+}
+
+impl Default for LnurlConfig {
+    fn default() -> Self {
+        LnurlConfig {
+            public_url: "https://pay2.email".to_string(),
+            max_sendable_msat: 100_000_000,
+        }
+    }
+}
+
+impl LnurlConfig {
+    pub fn domain(&self) -> &str {
+        let url = self.public_url.split("://").last().unwrap_or_default();
+        url.split('/').next().unwrap_or_default()
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.public_url.trim_end_matches('/'), path)
+    }
+
+    /// The bech32 LNURL-pay link of the email paid by the invoice with `payment_hash`, `None` if
+    /// `backend` can't create the invoices of LNURL-pay
+    pub fn pay_link(
+        &self,
+        backend: &dyn InvoiceBackend,
+        payment_hash: &str,
+    ) -> Result<Option<String>> {
+        if !backend.supports_description_hash() {
+            return Ok(None);
+        }
+        encode(&self.url(&format!("/lnurlp/{}", payment_hash))).map(Some)
+    }
+}
+
+/// Encode `url` as bech32 LNURL, uppercase so that QR codes are smaller
+pub fn encode(url: &str) -> Result<String> {
+    let lnurl = bech32::encode("lnurl", url.as_bytes().to_base32(), Variant::Bech32)?;
+    Ok(lnurl.to_ascii_uppercase())
+}
+
+/// An LNURL error response, wallets show the `reason`
+pub fn error(reason: &str) -> Json<Value> {
+    Json(json!({ "status": "ERROR", "reason": reason }))
+}
+
+/// Description of the payments of the emails, shown by the wallets. Their invoices commit to its
+/// hash
+pub fn metadata() -> String {
+    json!([["text/plain", "Payment for an email sent through pay2.email"]]).to_string()
+}
+
+/// The invoice paying for an email, if still waiting for the payment
+async fn pending_invoice(db: &Db, payment_hash: &str) -> Option<InvoiceRow> {
+    let invoice = InvoiceRow::get(db, payment_hash.to_string()).await.ok()?;
+    let pending =
+        invoice.state == Lifecycle::Reserved && invoice.expiration > Utc::now().naive_utc();
+    pending.then_some(invoice)
+}
+
+/// LNURL-pay request of an email waiting for the payment, the amount is fixed to the invoice one
+#[get("/lnurlp/<payment_hash>")]
+async fn pay_request(db: Db, config: &State<LnurlConfig>, payment_hash: &str) -> Json<Value> {
+    let invoice = match pending_invoice(&db, payment_hash).await {
+        Some(invoice) => invoice,
+        None => return error("No email waiting for this payment"),
+    };
+    let amount_msat = invoice.amount_msat.unwrap_or_default();
+    Json(json!({
+        "tag": "payRequest",
+        "callback": config.url(&format!("/lnurlp/{}/callback", payment_hash)),
+        "minSendable": amount_msat,
+        "maxSendable": amount_msat,
+        "metadata": metadata(),
+    }))
+}
+
+/// LNURL-pay callback, returns the invoice reserved for the email, created committing to the hash
+/// of [`metadata`]
+#[get("/lnurlp/<payment_hash>/callback?<amount>")]
+async fn pay_callback(db: Db, payment_hash: &str, amount: u64) -> Json<Value> {
+    let invoice = match pending_invoice(&db, payment_hash).await {
+        Some(invoice) => invoice,
+        None => return error("No email waiting for this payment"),
+    };
+    if invoice.amount_msat != Some(amount as i64) {
+        return error("Amount must be the one of the request");
+    }
+    Json(json!({
+        "pr": invoice.bolt11,
+        "routes": [],
+        "successAction": {
+            "tag": "message",
+            "message": "Payment received, the email will be sent shortly",
+        },
+    }))
+}
+
+fn valid_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-' | '_' | '.'))
+}
+
+/// Add the Lightning Address `alias.name`, replacing the one with the same name
+#[post("/alias", data = "<alias>")]
+async fn alias_add(
+    db: Db,
+    alias: Json<AliasRow>,
+    _auth: HttpAuth,
+) -> Result<Created<Json<AliasRow>>> {
+    let alias = alias.into_inner();
+    if !valid_alias_name(&alias.name) || alias.min_amount_msat <= 0 {
+        return Err(Error::InvalidAlias(alias.name));
+    }
+    let _: Mailboxes = alias.to_email.parse()?;
+    AliasRow::add(&db, alias.clone()).await?;
+    let location = format!("/.well-known/lnurlp/{}", alias.name);
+    Ok(Created::new(location).body(Json(alias)))
+}
+
+/// Range of the amounts payable to `alias`, fixed to the minimum if the backend can't create
+/// invoices of any amount
+fn sendable(alias: &AliasRow, config: &LnurlConfig, backend: &dyn InvoiceBackend) -> (u64, u64) {
+    let min = alias.min_amount_msat as u64;
+    if backend.exact_amounts() {
+        (min, config.max_sendable_msat.max(min))
+    } else {
+        (min, min)
+    }
+}
+
+/// Description of the payments to the Lightning Address of `alias`, its invoices commit to its
+/// hash
+fn address_metadata(alias: &AliasRow, config: &LnurlConfig) -> String {
+    let address = format!("{}@{}", alias.name, config.domain());
+    json!([
+        ["text/plain", format!("Send an email to {}", address)],
+        ["text/identifier", address],
+    ])
+    .to_string()
+}
+
+/// LNURL-pay request of the Lightning Address `name@domain`
+#[get("/.well-known/lnurlp/<name>")]
+async fn address_request(
+    db: Db,
+    config: &State<LnurlConfig>,
+    backend: &State<Arc<dyn InvoiceBackend>>,
+    name: &str,
+) -> Json<Value> {
+    let alias = match AliasRow::get(&db, name.to_string()).await {
+        Ok(alias) => alias,
+        Err(_) => return error("Unknown address"),
+    };
+    let (min, max) = sendable(&alias, config, backend.as_ref());
+    Json(json!({
+        "tag": "payRequest",
+        "callback": config.url(&format!("/.well-known/lnurlp/{}/callback", alias.name)),
+        "minSendable": min,
+        "maxSendable": max,
+        "metadata": address_metadata(&alias, config),
+        "commentAllowed": COMMENT_ALLOWED,
+    }))
+}
+
+/// Reserve an invoice of `amount_msat` for the email sending `comment` to `alias`, if the backend
+/// creates invoices of exactly that amount
+async fn reserve_email(
+    db: &Db,
+    config: &LnurlConfig,
+    backend: &dyn InvoiceBackend,
+    alias: AliasRow,
+    amount_msat: u64,
+    comment: &str,
+) -> Result<InvoiceRow> {
+    // wallets refuse invoices of a different amount, checked before reserving one
+    if !backend.exact_amounts() {
+        return Err(Error::InvoiceAmountOutOfRange(None));
+    }
+    let message = match comment {
+        "" => "Payment without comment".to_string(),
+        comment => comment.to_string(),
+    };
+    let metadata = address_metadata(&alias, config);
+    let email_row = EmailRow {
+        id: None,
+        payment_hash: String::new(),
+        reply_to_email: None,
+        to_email: alias.to_email,
+        subject: format!("Lightning Address payment to {}", alias.name),
+        message,
+        state: Lifecycle::Reserved,
+        attempts: 0,
+        next_attempt: None,
+        last_error: None,
+        relay: None,
+        referer: None,
+    };
+    backend
+        .create_invoice(db, amount_msat, Some(&metadata), email_row)
+        .await
+}
+
+/// LNURL-pay callback of a Lightning Address, the comment is sent by email once paid. Every
+/// callback creates an invoice, limited like the form submissions
+#[get("/.well-known/lnurlp/<name>/callback?<amount>&<comment>")]
+async fn address_callback(
+    // first, releasing its database connection before the one of the route is taken
+    _limit: RateLimit<'_>,
+    db: Db,
+    config: &State<LnurlConfig>,
+    backend: &State<Arc<dyn InvoiceBackend>>,
+    name: &str,
+    amount: u64,
+    comment: Option<&str>,
+) -> Json<Value> {
+    let alias = match AliasRow::get(&db, name.to_string()).await {
+        Ok(alias) => alias,
+        Err(_) => return error("Unknown address"),
+    };
+    let backend = backend.as_ref();
+    let (min, max) = sendable(&alias, config, backend);
+    if !(min..=max).contains(&amount) {
+        return error("Amount out of range");
+    }
+    let comment = comment.unwrap_or_default();
+    if comment.chars().count() > COMMENT_ALLOWED {
+        return error("Comment too long");
+    }
+    let invoice = match reserve_email(&db, config, backend, alias, amount, comment).await {
+        Ok(invoice) => invoice,
+        Err(e) => {
+            println!("lightning address {}: {:?}", name, e);
+            return error("No invoice available, try later");
+        }
+    };
+    Json(json!({
+        "pr": invoice.bolt11,
+        "routes": [],
+        "successAction": {
+            "tag": "message",
+            "message": "Payment received, your comment will be sent by email",
+        },
+    }))
+}
+
+/// Read the LNURL config, making it available as managed state, and mount the LNURL-pay and
+/// Lightning Address routes if the invoice backend can create their invoices
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("LNURL", |rocket| async {
+        let config = match rocket.figment().focus("lnurl").extract::<LnurlConfig>() {
+            Ok(config) => config,
+            Err(e) => {
+                let e = Error::InvalidConfig(e.to_string());
+                println!("invalid lnurl config: {:?}", e);
+                return Err(rocket);
+            }
+        };
+        let supported = rocket
+            .state::<Arc<dyn InvoiceBackend>>()
+            .is_some_and(|backend| backend.supports_description_hash());
+        let rocket = rocket.manage(config);
+        if !supported {
+            return Ok(rocket);
+        }
+        Ok(rocket.mount(
+            "/",
+            routes![
+                pay_request,
+                pay_callback,
+                alias_add,
+                address_request,
+                address_callback
+            ],
+        ))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lnurl::encode;
+    use crate::mailer::MemoryMailer;
+    use crate::test_util::{self, HTTP_AUTH_BASIC};
+    use bech32::FromBase32;
+    use bitcoin_hashes::hex::ToHex;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_encode() {
+        // from LUD-01
+        let url = "https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df";
+        let expected = "LNURL1DP68GURN8GHJ7UM9WFMXJCM99E3K7MF0V9CXJ0M385EKVCENXC6R2C35XVUKXEFCV5MKVV34X5EKZD3EV56NYD3HXQURZEPEXEJXXEPNXSCRVWFNV9NXZCN9XQ6XYEFHVGCXXCMYXYMNSERXFQ5FNS";
+        assert_eq!(encode(url).unwrap(), expected);
+    }
+
+    #[rocket::async_test]
+    async fn test_pay() {
+        // the invoices of the pool can't commit to the metadata, LNURL isn't offered
+        let client = test_util::client(MemoryMailer::default()).await;
+        client
+            .post("/invoice")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(test_util::invoice([5u8; 32], 20_000))
+            .dispatch()
+            .await;
+        let json: Value = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body("to=a%40example.com&subject=Hi&message=Hello")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(json["lnurl"].is_null());
+        let path = format!("/lnurlp/{}", json["payment_hash"].as_str().unwrap());
+        let response = client.get(path).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let figment = test_util::figment()
+            .merge(("invoice_backend", "cln"))
+            .merge(("cln.rpc_file", test_util::cln_server()));
+        let client = Client::tracked(test_util::rocket(figment, MemoryMailer::default()))
+            .await
+            .unwrap();
+        let json: Value = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body("to=a%40example.com&subject=Hi&message=Hello")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let bolt11 = json["bolt11"].as_str().unwrap().to_string();
+        let amount_msat = json["amount_msat"].as_u64().unwrap();
+
+        let (hrp, data, _) = bech32::decode(json["lnurl"].as_str().unwrap()).unwrap();
+        assert_eq!(hrp, "lnurl");
+        let url = String::from_utf8(Vec::<u8>::from_base32(&data).unwrap()).unwrap();
+        let path = url.trim_start_matches("https://pay2.email");
+        let pay_request: Value = client.get(path).dispatch().await.into_json().await.unwrap();
+        assert_eq!(pay_request["tag"], "payRequest");
+        assert_eq!(pay_request["minSendable"], amount_msat);
+        assert_eq!(pay_request["maxSendable"], amount_msat);
+
+        let callback = pay_request["callback"].as_str().unwrap();
+        let callback = callback.trim_start_matches("https://pay2.email");
+        let response = client
+            .get(format!("{}?amount={}", callback, amount_msat))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let json: Value = response.into_json().await.unwrap();
+        assert_eq!(json["pr"], bolt11);
+        // LUD-06: the invoice commits to the metadata of the request
+        assert_eq!(
+            test_util::invoice_description_hash(&bolt11),
+            test_util::description_hash(pay_request["metadata"].as_str())
+        );
+        assert_eq!(json["successAction"]["tag"], "message");
+
+        let json: Value = client
+            .get(format!("{}?amount=1000", callback))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["status"], "ERROR");
+
+        let json: Value = client
+            .get(format!("/lnurlp/{}", "00".repeat(32)))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["status"], "ERROR");
+    }
+
+    #[rocket::async_test]
+    async fn test_lightning_address() {
+        let figment = test_util::figment()
+            .merge(("invoice_backend", "cln"))
+            .merge(("cln.rpc_file", test_util::cln_server()))
+            .merge(("rate_limit.ip.capacity", 2))
+            .merge(("rate_limit.ip.per_hour", 1));
+        let mailer = MemoryMailer::default();
+        let client = Client::tracked(test_util::rocket(figment, mailer.clone()))
+            .await
+            .unwrap();
+
+        let alias =
+            json!({ "name": "alice", "to_email": "alice@example.com", "min_amount_msat": 10_000 });
+        let response = client
+            .post("/alias")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .json(&alias)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Created);
+        let invalid =
+            json!({ "name": "Alice!", "to_email": "alice@example.com", "min_amount_msat": 10_000 });
+        let response = client
+            .post("/alias")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .json(&invalid)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        let pay_request: Value = client
+            .get("/.well-known/lnurlp/alice")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(pay_request["minSendable"], 10_000);
+        assert_eq!(pay_request["maxSendable"], 100_000_000);
+        assert!(pay_request["metadata"]
+            .as_str()
+            .unwrap()
+            .contains(r#"["text/identifier","alice@pay2.email"]"#));
+
+        let callback = "/.well-known/lnurlp/alice/callback";
+        let json: Value = client
+            .get(format!("{}?amount=5000", callback))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["status"], "ERROR");
+
+        let json: Value = client
+            .get(format!("{}?amount=25000&comment=Thanks%20Alice", callback))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let bolt11 = json["pr"].as_str().unwrap();
+        assert!(bolt11.starts_with("lnbc"));
+        assert_eq!(
+            test_util::invoice_description_hash(bolt11),
+            test_util::description_hash(pay_request["metadata"].as_str())
+        );
+
+        client
+            .post("/invoice/paid")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(test_util::cln_preimage(0).to_hex())
+            .dispatch()
+            .await;
+        test_util::wait_messages(&mailer, 1).await;
+        let messages = mailer.messages();
+        assert_eq!(
+            messages[0].envelope().to()[0].to_string(),
+            "alice@example.com"
+        );
+        let formatted = String::from_utf8(messages[0].formatted()).unwrap();
+        assert!(formatted.contains("Thanks Alice"));
+
+        let json: Value = client
+            .get("/.well-known/lnurlp/bob")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["status"], "ERROR");
+
+        // comments are limited in characters, not bytes
+        for (length, status) in [(500, None), (501, Some("ERROR"))] {
+            let comment = "%C3%A9".repeat(length);
+            let json: Value = client
+                .get(format!("{}?amount=25000&comment={}", callback, comment))
+                .dispatch()
+                .await
+                .into_json()
+                .await
+                .unwrap();
+            assert_eq!(json["status"].as_str(), status);
+        }
+
+        // every callback creates an invoice, limited by client IP
+        for expected in [Status::Ok, Status::Ok, Status::TooManyRequests] {
+            let response = client
+                .get(format!("{}?amount=25000", callback))
+                .remote("1.2.3.4:1000".parse().unwrap())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), expected);
+        }
+    }
+}
@@ -0,0 +1,595 @@
+use crate::db::{AttachmentRow, EmailRow, InvoiceRow};
+use crate::dkim::DkimSettings;
+use crate::error::Result;
+use crate::failover::{self, CircuitBreaker, FailoverMailer, Relay};
+use crate::smtp::SmtpConfig;
+use crate::template::{self, escape_html};
+use crate::Error;
+use lettre::message::dkim::DkimConfig;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, Mailboxes, MultiPart};
+use lettre::{
+    Address, AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport,
+    Message, Tokio1Executor,
+};
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Deliver a built email
+#[rocket::async_trait]
+pub trait Mailer: Send + Sync {
+    /// Returns the name of the transport which delivered the email
+    async fn send(&self, message: Message) -> Result<String>;
+}
+
+/// Deliver through an SMTP relay, reusing the connections of a single pooled transport
+pub struct SmtpMailer {
+    name: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+#[rocket::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, message: Message) -> Result<String> {
+        self.transport
+            .send(message)
+            .await
+            .map_err(failover::rejection)?;
+        Ok(self.name.clone())
+    }
+}
+
+/// Deliver with the local `sendmail` command
+pub struct SendmailMailer(AsyncSendmailTransport<Tokio1Executor>);
+
+#[rocket::async_trait]
+impl Mailer for SendmailMailer {
+    async fn send(&self, message: Message) -> Result<String> {
+        self.0.send(message).await?;
+        Ok("sendmail".to_string())
+    }
+}
+
+/// Write every email as a `.eml` file in a directory
+pub struct FileMailer(AsyncFileTransport<Tokio1Executor>);
+
+#[rocket::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, message: Message) -> Result<String> {
+        self.0.send(message).await?;
+        Ok("file".to_string())
+    }
+}
+
+/// Deliver in the `new` directory of a maildir, creating `tmp`, `new` and `cur` if missing
+pub struct MaildirMailer {
+    dir: PathBuf,
+    counter: AtomicU64,
+}
+
+impl MaildirMailer {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        for sub in ["tmp", "new", "cur"] {
+            std::fs::create_dir_all(dir.join(sub))?;
+        }
+        Ok(MaildirMailer {
+            dir,
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Unique file name as suggested in https://cr.yp.to/proto/maildir.html
+    fn unique_name(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        format!(
+            "{}.M{}P{}Q{}.pay2email",
+            now.as_secs(),
+            now.subsec_micros(),
+            std::process::id(),
+            self.counter.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for MaildirMailer {
+    async fn send(&self, message: Message) -> Result<String> {
+        let name = self.unique_name();
+        let tmp = self.dir.join("tmp").join(&name);
+        tokio::fs::write(&tmp, message.formatted()).await?;
+        tokio::fs::rename(&tmp, self.dir.join("new").join(&name)).await?;
+        Ok("maildir".to_string())
+    }
+}
+
+/// Keep the emails in memory, cloned instances share the same emails
+#[derive(Clone, Default)]
+pub struct MemoryMailer(Arc<Mutex<Vec<Message>>>);
+
+impl MemoryMailer {
+    /// The emails delivered so far
+    #[cfg(test)]
+    pub fn messages(&self) -> Vec<Message> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, message: Message) -> Result<String> {
+        self.0.lock().unwrap().push(message);
+        Ok("memory".to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Backend {
+    Smtp,
+    Sendmail,
+    File,
+    Maildir,
+    Memory,
+}
+
+/// Mailer configuration, read from the `mailer` table of the Rocket config, eg:
+///
+/// ```toml
+/// [default.mailer]
+/// backend = "smtp"
+/// from_address = "noreply@pay2.email"
+/// from_name = "Pay2.email"
+/// ```
+///
+/// `path` is the directory used by the `file` and `maildir` backends, `command` is the optional
+/// sendmail binary (by default `sendmail` in `PATH`). The `smtp` backend requires the `smtp` table,
+/// or an array of `smtp` tables to fail over across multiple relays.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MailerConfig {
+    pub backend: Backend,
+    pub from_address: String,
+    pub from_name: Option<String>,
+    pub path: Option<PathBuf>,
+    pub command: Option<String>,
+
+    /// Template of the plain text part, `static/email.txt` if missing
+    pub template_txt: Option<PathBuf>,
+
+    /// Template of the html part, `static/email.html` if missing
+    pub template_html: Option<PathBuf>,
+}
+
+impl MailerConfig {
+    /// The mailbox used in the `From` header of every email sent
+    pub fn from(&self) -> Result<Mailbox> {
+        let address: Address = self.from_address.parse()?;
+        Ok(Mailbox::new(self.from_name.clone(), address))
+    }
+
+    /// Read the configured templates, falling back to the default ones
+    pub fn templates(&self) -> Result<Templates> {
+        let mut templates = Templates::default();
+        if let Some(path) = self.template_txt.as_ref() {
+            templates.txt = std::fs::read_to_string(path)?;
+        }
+        if let Some(path) = self.template_html.as_ref() {
+            templates.html = std::fs::read_to_string(path)?;
+        }
+        Ok(templates)
+    }
+
+    fn path(&self) -> Result<PathBuf> {
+        self.path
+            .clone()
+            .ok_or_else(|| Error::InvalidConfig("mailer.path is required".to_string()))
+    }
+
+    /// Build the configured mailer, `smtp` relays are needed only by the smtp backend
+    pub fn mailer(&self, smtp: Vec<SmtpConfig>) -> Result<Arc<dyn Mailer>> {
+        Ok(match self.backend {
+            Backend::Smtp => {
+                if smtp.is_empty() {
+                    return Err(Error::InvalidConfig(
+                        "smtp backend requires the smtp table".to_string(),
+                    ));
+                }
+                let mut relays = vec![];
+                for config in smtp {
+                    config.validate()?;
+                    let name = format!("{}:{}", config.host, config.port);
+                    let transport = config.transport()?;
+                    relays.push(Relay {
+                        name: name.clone(),
+                        mailer: Arc::new(SmtpMailer { name, transport }),
+                        breaker: CircuitBreaker::new(
+                            config.failure_threshold,
+                            Duration::from_secs(config.retry_after),
+                        ),
+                    });
+                }
+                Arc::new(FailoverMailer(relays))
+            }
+            Backend::Sendmail => Arc::new(SendmailMailer(match self.command.as_ref() {
+                Some(command) => AsyncSendmailTransport::new_with_command(command),
+                None => AsyncSendmailTransport::new(),
+            })),
+            Backend::File => {
+                let path = self.path()?;
+                std::fs::create_dir_all(&path)?;
+                Arc::new(FileMailer(AsyncFileTransport::new(path)))
+            }
+            Backend::Maildir => Arc::new(MaildirMailer::new(self.path()?)?),
+            Backend::Memory => Arc::new(MemoryMailer::default()),
+        })
+    }
+}
+
+/// The `smtp` config may be a single relay or a list of relays, in order of preference
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum Relays {
+    One(SmtpConfig),
+    Many(Vec<SmtpConfig>),
+}
+
+impl From<Relays> for Vec<SmtpConfig> {
+    fn from(relays: Relays) -> Self {
+        match relays {
+            Relays::One(config) => vec![config],
+            Relays::Many(configs) => configs,
+        }
+    }
+}
+
+/// Templates of the email bodies, placeholders are replaced with the message and the metadata of
+/// its payment: `{{ MESSAGE }}`, `{{ SUBJECT }}`, `{{ REFERER }}`, `{{ PAID_AT }}`, `{{ AMOUNT }}`
+/// and `{{ PAYMENT_HASH }}`
+pub struct Templates {
+    pub txt: String,
+    pub html: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            txt: include_str!("../static/email.txt").to_string(),
+            html: include_str!("../static/email.html").to_string(),
+        }
+    }
+}
+
+impl Templates {
+    /// Render the plain text and the html bodies of the email in `email_row`, paid with
+    /// `invoice_row`
+    pub fn render(
+        &self,
+        email_row: &EmailRow,
+        invoice_row: Option<&InvoiceRow>,
+    ) -> (String, String) {
+        let not_available = "N/A".to_string();
+        let referer = email_row.referer.as_ref().unwrap_or(&not_available);
+        let paid_at = invoice_row
+            .and_then(|i| i.paid_at)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_else(|| not_available.clone());
+        let amount = invoice_row
+            .and_then(|i| i.amount_msat)
+            .map(format_amount)
+            .unwrap_or_else(|| not_available.clone());
+        let vars = [
+            ("MESSAGE", email_row.message.as_str()),
+            ("SUBJECT", email_row.subject.as_str()),
+            ("REFERER", referer.as_str()),
+            ("PAID_AT", paid_at.as_str()),
+            ("AMOUNT", amount.as_str()),
+            ("PAYMENT_HASH", email_row.payment_hash.as_str()),
+        ];
+        let txt = template::render(&self.txt, &vars);
+
+        let escaped: Vec<_> = vars.iter().map(|(k, v)| (*k, escape_html(v))).collect();
+        let escaped: Vec<_> = escaped.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let html = template::render(&self.html, &escaped);
+        (txt, html)
+    }
+}
+
+/// Format `amount_msat` in satoshi, with decimals only if needed
+fn format_amount(amount_msat: i64) -> String {
+    if amount_msat % 1000 == 0 {
+        format!("{} sat", amount_msat / 1000)
+    } else {
+        format!("{}.{:03} sat", amount_msat / 1000, amount_msat % 1000)
+    }
+}
+
+/// Build emails from db rows and deliver them with the configured mailer. Available as managed
+/// state.
+#[derive(Clone)]
+pub struct Outbox {
+    from: Mailbox,
+    mailer: Arc<dyn Mailer>,
+    dkim: Option<Arc<DkimConfig>>,
+    templates: Arc<Templates>,
+}
+
+impl Outbox {
+    pub fn new(from: Mailbox, mailer: Arc<dyn Mailer>) -> Self {
+        Outbox {
+            from,
+            mailer,
+            dkim: None,
+            templates: Arc::new(Templates::default()),
+        }
+    }
+
+    /// Render the email bodies with the given templates
+    pub fn with_templates(mut self, templates: Templates) -> Self {
+        self.templates = Arc::new(templates);
+        self
+    }
+
+    /// Sign every email with the given DKIM config
+    pub fn with_dkim(mut self, dkim: DkimConfig) -> Self {
+        self.dkim = Some(Arc::new(dkim));
+        self
+    }
+
+    /// Build the email contained in `email_row`, paid with `invoice_row`, with a plain text and an
+    /// html alternative body, followed by `attachments` if any
+    pub fn message(
+        &self,
+        email_row: &EmailRow,
+        invoice_row: Option<&InvoiceRow>,
+        attachments: &[AttachmentRow],
+    ) -> Result<Message> {
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(&email_row.subject);
+        for mbox in email_row.to_email.parse::<Mailboxes>()?.into_iter() {
+            builder = builder.to(mbox);
+        }
+        if let Some(reply_to) = email_row.reply_to_email.as_ref() {
+            builder = builder.reply_to(reply_to.parse()?)
+        }
+        let (txt, html) = self.templates.render(email_row, invoice_row);
+        let body = MultiPart::alternative_plain_html(txt, html);
+        let mut message = if attachments.is_empty() {
+            builder.multipart(body)?
+        } else {
+            let mut mixed = MultiPart::mixed().multipart(body);
+            for attachment in attachments {
+                let content_type = ContentType::parse(&attachment.content_type)
+                    .map_err(|e| Error::InvalidAttachment(format!("{:?}", e)))?;
+                mixed = mixed.singlepart(
+                    Attachment::new(attachment.filename.clone())
+                        .body(attachment.content.clone(), content_type),
+                );
+            }
+            builder.multipart(mixed)?
+        };
+        if let Some(dkim) = self.dkim.as_ref() {
+            message.sign(dkim);
+        }
+        Ok(message)
+    }
+
+    /// Build and deliver the email contained in `email_row`, returns the name of the transport
+    /// which delivered it
+    pub async fn send(
+        &self,
+        email_row: &EmailRow,
+        invoice_row: Option<&InvoiceRow>,
+        attachments: &[AttachmentRow],
+    ) -> Result<String> {
+        let message = self.message(email_row, invoice_row, attachments)?;
+        self.mailer.send(message).await
+    }
+}
+
+/// Read and validate the mailer config, making the `Outbox` available as managed state
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Mailer", |rocket| async {
+        let figment = rocket.figment();
+        let outbox = figment
+            .extract_inner::<MailerConfig>("mailer")
+            .map_err(|e| Error::InvalidConfig(e.to_string()))
+            .and_then(|config| {
+                let smtp = match config.backend {
+                    Backend::Smtp => figment
+                        .extract_inner::<Relays>("smtp")
+                        .map_err(|e| Error::InvalidConfig(e.to_string()))?
+                        .into(),
+                    _ => vec![],
+                };
+                let outbox = Outbox::new(config.from()?, config.mailer(smtp)?)
+                    .with_templates(config.templates()?);
+                if figment.find_value("dkim").is_ok() {
+                    let dkim = figment
+                        .extract_inner::<DkimSettings>("dkim")
+                        .map_err(|e| Error::InvalidConfig(e.to_string()))?;
+                    Ok(outbox.with_dkim(dkim.config()?))
+                } else {
+                    Ok(outbox)
+                }
+            });
+        match outbox {
+            Ok(outbox) => Ok(rocket.manage(outbox)),
+            Err(e) => {
+                println!("invalid mailer config: {:?}", e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::{EmailRow, InvoiceRow};
+    use crate::lifecycle::Lifecycle;
+    use crate::mailer::{
+        format_amount, Backend, MaildirMailer, MailerConfig, MemoryMailer, Outbox, Relays,
+        Templates,
+    };
+    use crate::smtp::SmtpConfig;
+    use chrono::NaiveDateTime;
+    use rocket::figment::providers::{Format, Toml};
+    use rocket::figment::Figment;
+    use std::sync::Arc;
+
+    fn email_row() -> EmailRow {
+        EmailRow {
+            id: Some(1),
+            payment_hash: "00".repeat(32),
+            reply_to_email: Some("Sender <sender@example.com>".to_string()),
+            to_email: "a@example.com, b@example.com".to_string(),
+            subject: "Subject".to_string(),
+            message: "Hello".to_string(),
+            state: Lifecycle::Queued,
+            attempts: 0,
+            next_attempt: None,
+            last_error: None,
+            relay: None,
+            referer: Some("https://example.com/contact".to_string()),
+        }
+    }
+
+    fn invoice_row() -> InvoiceRow {
+        InvoiceRow {
+            id: "00".repeat(32),
+            bolt11: "lnbc".to_string(),
+            expiration: NaiveDateTime::from_timestamp(1_600_000_000, 0),
+            state: Lifecycle::Paid,
+            amount_msat: Some(10_500),
+            paid_at: Some(NaiveDateTime::from_timestamp(1_600_000_000, 0)),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_memory_outbox() {
+        let memory = MemoryMailer::default();
+        let outbox = Outbox::new(
+            "Pay2.email <noreply@example.com>".parse().unwrap(),
+            Arc::new(memory.clone()),
+        );
+        outbox
+            .send(&email_row(), Some(&invoice_row()), &[])
+            .await
+            .unwrap();
+
+        let messages = memory.messages();
+        assert_eq!(messages.len(), 1);
+        let formatted = String::from_utf8(messages[0].formatted()).unwrap();
+        assert!(formatted.contains("From: Pay2.email <noreply@example.com>\r\n"));
+        assert!(formatted.contains("To: a@example.com, b@example.com\r\n"));
+        assert!(formatted.contains("Reply-To: Sender <sender@example.com>\r\n"));
+        assert!(formatted.contains("Subject: Subject\r\n"));
+        assert!(formatted.contains("Content-Type: multipart/alternative;"));
+        assert!(formatted.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(formatted.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(formatted.contains("\r\n\r\nHello\r\n"));
+    }
+
+    #[test]
+    fn test_templates() {
+        let mut email_row = email_row();
+        email_row.message = "<b>Hello</b> & bye".to_string();
+        let (txt, html) = Templates::default().render(&email_row, Some(&invoice_row()));
+        assert!(txt.starts_with("<b>Hello</b> & bye\n"));
+        assert!(txt.contains("from https://example.com/contact\n"));
+        assert!(txt.contains("Paid 10.500 sat on 2020-09-13 12:26:40 UTC\n"));
+        assert!(txt.contains(&format!("Payment hash: {}", "00".repeat(32))));
+        assert!(html.contains("&lt;b&gt;Hello&lt;/b&gt; &amp; bye"));
+        assert!(!html.contains("<b>"));
+
+        email_row.referer = None;
+        let (txt, _) = Templates::default().render(&email_row, None);
+        assert!(txt.contains("from N/A\nPaid N/A on N/A\n"));
+
+        let templates = Templates {
+            txt: "{{ SUBJECT }}: {{ MESSAGE }}".to_string(),
+            html: "<p>{{ SUBJECT }}</p>".to_string(),
+        };
+        let (txt, html) = templates.render(&email_row, None);
+        assert_eq!(txt, "Subject: <b>Hello</b> & bye");
+        assert_eq!(html, "<p>Subject</p>");
+    }
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(1_000), "1 sat");
+        assert_eq!(format_amount(1_001), "1.001 sat");
+        assert_eq!(format_amount(10), "0.010 sat");
+    }
+
+    #[rocket::async_test]
+    async fn test_maildir() {
+        let dir = std::env::temp_dir().join(format!("pay2email-maildir-{}", std::process::id()));
+        let maildir = MaildirMailer::new(dir.clone()).unwrap();
+        let outbox = Outbox::new("noreply@example.com".parse().unwrap(), Arc::new(maildir));
+        let message = outbox.message(&email_row(), None, &[]).unwrap();
+        outbox.mailer.send(message.clone()).await.unwrap();
+
+        let delivered: Vec<_> = std::fs::read_dir(dir.join("new")).unwrap().collect();
+        assert_eq!(delivered.len(), 1);
+        let content = std::fs::read(delivered[0].as_ref().unwrap().path()).unwrap();
+        assert_eq!(content, message.formatted());
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_config() {
+        let config = MailerConfig {
+            backend: Backend::Smtp,
+            from_address: "noreply@example.com".to_string(),
+            from_name: None,
+            path: None,
+            command: None,
+            template_txt: None,
+            template_html: None,
+        };
+        assert!(config.mailer(vec![]).is_err());
+
+        let config = MailerConfig {
+            backend: Backend::Maildir,
+            ..config
+        };
+        assert!(config.mailer(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_relays() {
+        let one = r#"
+            [smtp]
+            host = "a.example.com"
+            port = 587
+            tls = "starttls"
+        "#;
+        let many = r#"
+            [[smtp]]
+            host = "a.example.com"
+            port = 587
+            tls = "starttls"
+
+            [[smtp]]
+            host = "b.example.com"
+            port = 465
+            tls = "implicit"
+            failure_threshold = 5
+        "#;
+        for (toml, expected) in [(one, 1), (many, 2)] {
+            let relays: Vec<SmtpConfig> = Figment::from(Toml::string(toml))
+                .extract_inner::<Relays>("smtp")
+                .unwrap()
+                .into();
+            assert_eq!(relays.len(), expected);
+            assert_eq!(relays[0].host, "a.example.com");
+            assert_eq!(relays[0].failure_threshold, 3);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Keep the invoice pool of a pay2email server filled with invoices of a Core Lightning node, see
+//! the `pay2email-node` binary
+
+use crate::cln::{ClnClient, ClnConfig};
+use crate::error::Result;
+use crate::Error;
+use rand::Rng;
+use rocket::figment::providers::{Env, Format, Serialized, Toml};
+use rocket::figment::Figment;
+use rocket::serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Configuration of `pay2email-node`, read from `Node.toml` (or the file in the env var
+/// `PAY2EMAIL_NODE_CONFIG`) and overridden by env vars prefixed with `PAY2EMAIL_NODE_`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NodeConfig {
+    /// Base url of the pay2email server
+    pub server_url: String,
+
+    /// Credentials of the endpoints requiring authentication
+    pub http_user: String,
+    pub http_password: String,
+
+    /// Path of the Core Lightning JSON-RPC socket
+    pub rpc_file: PathBuf,
+
+    /// Number of available invoices to keep in the pool
+    pub target: i64,
+
+    /// The amount of every invoice is chosen randomly in this range. The defaults cover the
+    /// default prices of emails up to 5 recipients
+    pub min_amount_msat: u64,
+    pub max_amount_msat: u64,
+
+    /// Seconds before the invoices expire, plus a random jitter up to `expiry_jitter` so that
+    /// they don't expire at the same time
+    pub expiry: u64,
+    pub expiry_jitter: u64,
+
+    /// Description of the invoices
+    pub description: String,
+
+    /// Seconds between two refills when running as a daemon
+    pub interval: u64,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            server_url: "https://pay2.email".to_string(),
+            http_user: String::new(),
+            http_password: String::new(),
+            rpc_file: PathBuf::from("lightning-rpc"),
+            target: 60,
+            min_amount_msat: 20_000,
+            max_amount_msat: 60_000,
+            expiry: 504_800,
+            expiry_jitter: 200_000,
+            description: "pay2.email".to_string(),
+            interval: 600,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn figment() -> Figment {
+        Figment::from(Serialized::defaults(NodeConfig::default()))
+            .merge(Toml::file(Env::var_or(
+                "PAY2EMAIL_NODE_CONFIG",
+                "Node.toml",
+            )))
+            .merge(Env::prefixed("PAY2EMAIL_NODE_"))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.min_amount_msat == 0 || self.min_amount_msat > self.max_amount_msat {
+            return Err(Error::InvalidConfig(
+                "min_amount_msat must be positive and not above max_amount_msat".to_string(),
+            ));
+        }
+        // the server doesn't use invoices expiring within the hour
+        if self.expiry <= 60 * 60 {
+            return Err(Error::InvalidConfig(
+                "expiry must be above one hour".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Upload invoices created by the node until the pool of the server reaches the target size
+pub struct Refiller {
+    config: NodeConfig,
+    http: reqwest::Client,
+    cln: ClnClient,
+}
+
+impl Refiller {
+    pub fn new(config: NodeConfig) -> Result<Self> {
+        config.validate()?;
+        let cln = ClnClient::new(ClnConfig {
+            rpc_file: config.rpc_file.clone(),
+            expiry: config.expiry,
+            description: config.description.clone(),
+        });
+        Ok(Refiller {
+            config,
+            http: reqwest::Client::new(),
+            cln,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.server_url.trim_end_matches('/'), path)
+    }
+
+    /// Number of invoices available on the server, of at least `min_amount_msat`
+    async fn count(&self) -> Result<i64> {
+        let path = format!("/invoice/count?amount_msat={}", self.config.min_amount_msat);
+        let response = self.http.get(self.url(&path)).send().await?;
+        Ok(response.error_for_status()?.json().await?)
+    }
+
+    async fn upload(&self, bolt11: String) -> Result<()> {
+        self.http
+            .post(self.url("/invoice"))
+            .basic_auth(&self.config.http_user, Some(&self.config.http_password))
+            .body(bolt11)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Refill the pool once, returns the number of invoices uploaded
+    pub async fn refill(&self) -> Result<usize> {
+        let available = self.count().await?;
+        println!(
+            "{} invoices available, target {}",
+            available, self.config.target
+        );
+        let mut uploaded = 0;
+        for _ in available..self.config.target {
+            let (amount_msat, expiry) = {
+                let mut rng = rand::thread_rng();
+                let amount_msat =
+                    rng.gen_range(self.config.min_amount_msat..=self.config.max_amount_msat);
+                (
+                    amount_msat,
+                    self.config.expiry + rng.gen_range(0..=self.config.expiry_jitter),
+                )
+            };
+            let bolt11 = self.cln.invoice_expiring(amount_msat, expiry, None).await?;
+            self.upload(bolt11.clone()).await?;
+            println!(
+                "uploaded invoice of {} msat expiring in {}s: {}",
+                amount_msat, expiry, bolt11
+            );
+            uploaded += 1;
+        }
+        Ok(uploaded)
+    }
+
+    /// Refill the pool every `interval` seconds, forever
+    pub async fn run(&self) {
+        let interval = Duration::from_secs(self.config.interval);
+        loop {
+            if let Err(e) = self.refill().await {
+                println!("refill failed: {:?}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::node::{NodeConfig, Refiller};
+    use crate::test_util;
+    use lightning_invoice::Invoice;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_validate() {
+        let config = NodeConfig::default();
+        assert!(config.validate().is_ok());
+        let mut invalid = config.clone();
+        invalid.max_amount_msat = 10_000;
+        assert!(invalid.validate().is_err());
+        let mut invalid = config;
+        invalid.expiry = 60;
+        assert!(invalid.validate().is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_refill() {
+        let uploaded = Arc::new(Mutex::new(vec![]));
+        let server_uploaded = uploaded.clone();
+        let server_url = test_util::http_server(move |method, path, head, body| {
+            match (method, path) {
+                ("GET", "/invoice/count?amount_msat=20000") => (200, "1".to_string()),
+                // user `test`, password `test`
+                ("POST", "/invoice") if head.contains(test_util::HTTP_AUTH_BASIC) => {
+                    let bolt11 = String::from_utf8(body.to_vec()).unwrap();
+                    server_uploaded.lock().unwrap().push(bolt11);
+                    (201, "{}".to_string())
+                }
+                _ => (401, "".to_string()),
+            }
+        });
+        let config = NodeConfig {
+            server_url,
+            http_user: "test".to_string(),
+            http_password: "test".to_string(),
+            rpc_file: test_util::cln_server(),
+            target: 4,
+            min_amount_msat: 20_000,
+            max_amount_msat: 50_000,
+            ..NodeConfig::default()
+        };
+        let refiller = Refiller::new(config).unwrap();
+        assert_eq!(refiller.refill().await.unwrap(), 3);
+
+        let uploaded = uploaded.lock().unwrap();
+        assert_eq!(uploaded.len(), 3);
+        for bolt11 in uploaded.iter() {
+            let invoice: Invoice = bolt11.parse().unwrap();
+            let amount_msat = invoice.amount_milli_satoshis().unwrap();
+            assert!((20_000..=50_000).contains(&amount_msat));
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::backend::{InvoiceBackend, OfferPayment};
+use crate::bolt12::Offer;
+use crate::db::{EmailRow, InvoiceRow, OfferRow};
+use crate::error::Result;
+use crate::lifecycle::Lifecycle;
+use crate::pricing::PricingConfig;
+use crate::routes::HttpAuth;
+use crate::{qr, Db, Error};
+use chrono::Utc;
+use lettre::message::Mailboxes;
+use rocket::fairing::AdHoc;
+use rocket::response::status::Created;
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::State;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// A form paid with a BOLT12 offer, every payer note is sent to `to_email`
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct OfferRequest {
+    to_email: String,
+    /// Subject of the emails, also the description of the offer shown by the wallets
+    subject: String,
+    /// Price of every email, the base price when missing
+    amount_msat: Option<u64>,
+}
+
+/// Create an offer on the node for the form `request`, the offer can be paid many times
+#[post("/offer", data = "<request>")]
+async fn offer_add(
+    db: Db,
+    backend: &State<Arc<dyn InvoiceBackend>>,
+    pricing: &State<PricingConfig>,
+    request: Json<OfferRequest>,
+    _auth: HttpAuth,
+) -> Result<Created<Json<OfferRow>>> {
+    let request = request.into_inner();
+    let _: Mailboxes = request.to_email.parse()?;
+    if request.subject.is_empty() {
+        return Err(Error::MissingSubject);
+    }
+    let amount_msat = request.amount_msat.unwrap_or(pricing.base_msat);
+    let (offer_id, bolt12) = backend.create_offer(amount_msat, &request.subject).await?;
+
+    // the offer is published as is, check the node created what was requested
+    let offer: Offer = bolt12.parse()?;
+    if offer.amount != Some(amount_msat) || offer.currency.is_some() {
+        return Err(Error::Bolt12(format!("offer amount {:?}", offer.amount)));
+    }
+    let offer_row = OfferRow {
+        id: offer_id,
+        bolt12,
+        to_email: request.to_email,
+        subject: request.subject,
+        amount_msat: amount_msat as i64,
+    };
+    OfferRow::add(&db, offer_row.clone()).await?;
+    let location = format!("/offer/{}", offer_row.id);
+    Ok(Created::new(location).body(Json(offer_row)))
+}
+
+/// The offer with `offer_id` to publish in the form, without its recipient
+#[get("/offer/<offer_id>")]
+async fn offer_get(db: Db, offer_id: &str) -> Result<Json<Value>> {
+    let offer = OfferRow::get(&db, offer_id.to_string()).await?;
+    let qr = qr::create_bmp_base64_qr(&format!("lightning:{}", offer.bolt12.to_uppercase()))?;
+    Ok(Json(json!({
+        "offer_id": offer.id,
+        "bolt12": offer.bolt12,
+        "subject": offer.subject,
+        "amount_msat": offer.amount_msat,
+        "qr": qr,
+    })))
+}
+
+/// Add the invoice of the offer `payment` reserved for the email of its payer note, to be settled
+/// like the invoices created for the other emails.
+///
+/// Fails with `NotFound` if the payment is not for one of the offers in db
+pub async fn add_payment(db: &Db, payment: OfferPayment) -> Result<()> {
+    let offer = OfferRow::get(db, payment.offer_id).await?;
+    if payment.amount_msat < offer.amount_msat as u64 {
+        return Err(Error::InvoiceAmountOutOfRange(Some(payment.amount_msat)));
+    }
+    let invoice_row = InvoiceRow {
+        id: payment.payment_hash.clone(),
+        bolt11: payment.invoice,
+        // already paid, the invoice must not be shown again
+        expiration: Utc::now().naive_utc(),
+        state: Lifecycle::Reserved,
+        amount_msat: Some(payment.amount_msat as i64),
+        paid_at: None,
+    };
+    let message = match payment.payer_note {
+        Some(note) if !note.is_empty() => note,
+        _ => "Payment without payer note".to_string(),
+    };
+    let email_row = EmailRow {
+        id: None,
+        payment_hash: payment.payment_hash,
+        reply_to_email: None,
+        to_email: offer.to_email,
+        subject: offer.subject,
+        message,
+        state: Lifecycle::Reserved,
+        attempts: 0,
+        next_attempt: None,
+        last_error: None,
+        relay: None,
+        referer: None,
+    };
+    InvoiceRow::add_with_email(db, invoice_row, email_row).await
+}
+
+/// Mount the routes creating and showing the offers, if the backend supports them
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("BOLT12 Offers", |rocket| async {
+        let supported = rocket
+            .state::<Arc<dyn InvoiceBackend>>()
+            .is_some_and(|backend| backend.supports_offers());
+        if !supported {
+            return rocket;
+        }
+        rocket.mount("/", routes![offer_add, offer_get])
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::{self, OfferPayment};
+    use crate::bolt12::Offer;
+    use crate::mailer::MemoryMailer;
+    use crate::offer::add_payment;
+    use crate::queue::Queue;
+    use crate::test_util::{self, HTTP_AUTH_BASIC};
+    use crate::{Db, Error};
+    use rocket::http::{Header, Status};
+    use rocket::local::asynchronous::Client;
+    use serde_json::{json, Value};
+
+    #[rocket::async_test]
+    async fn test_offer() {
+        let figment = test_util::figment()
+            .merge(("invoice_backend", "cln"))
+            .merge(("cln.rpc_file", test_util::cln_server()));
+        let mailer = MemoryMailer::default();
+        let client = Client::tracked(test_util::rocket(figment, mailer.clone()))
+            .await
+            .unwrap();
+
+        let form = json!({ "to_email": "alice@example.com", "subject": "Contact form" });
+        let response = client.post("/offer").json(&form).dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .post("/offer")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .json(&form)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Created);
+        let json: Value = response.into_json().await.unwrap();
+        let offer_id = json["id"].as_str().unwrap().to_string();
+        let offer: Offer = json["bolt12"].as_str().unwrap().parse().unwrap();
+        assert_eq!(offer.amount, Some(20_000));
+        assert_eq!(offer.description.as_deref(), Some("Contact form"));
+
+        let json: Value = client
+            .get(format!("/offer/{}", offer_id))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["amount_msat"], 20_000);
+        assert!(json["qr"].as_str().unwrap().starts_with("data:image/bmp"));
+        assert!(json.get("to_email").is_none());
+
+        // two payments of the same offer, as notified by the node
+        let db = Db::get_one(client.rocket()).await.unwrap();
+        let queue = client.rocket().state::<Queue>().unwrap();
+        for (i, note) in ["Hello Alice", "Hi again"].iter().enumerate() {
+            let payment = OfferPayment {
+                offer_id: offer_id.clone(),
+                payment_hash: test_util::payment_hash([0x20 + i as u8; 32]),
+                invoice: "lni1".to_string(),
+                amount_msat: 20_000,
+                payer_note: Some(note.to_string()),
+            };
+            add_payment(&db, payment.clone()).await.unwrap();
+            backend::settle(&db, queue, payment.payment_hash)
+                .await
+                .unwrap();
+        }
+        test_util::wait_messages(&mailer, 2).await;
+        let messages = mailer.messages();
+        for (message, note) in messages.iter().zip(["Hello Alice", "Hi again"]) {
+            assert_eq!(message.envelope().to()[0].to_string(), "alice@example.com");
+            let formatted = String::from_utf8(message.formatted()).unwrap();
+            assert!(formatted.contains(note));
+            assert!(formatted.contains("Contact form"));
+        }
+
+        let unknown = OfferPayment {
+            offer_id: "00".repeat(32),
+            payment_hash: test_util::payment_hash([0x30; 32]),
+            invoice: "lni1".to_string(),
+            amount_msat: 20_000,
+            payer_note: None,
+        };
+        assert!(matches!(
+            add_payment(&db, unknown).await,
+            Err(Error::Diesel(diesel::result::Error::NotFound))
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn test_offers_unsupported() {
+        // the default pool backend can't create offers
+        let client = test_util::client(MemoryMailer::default()).await;
+        let form = json!({ "to_email": "alice@example.com", "subject": "Contact form" });
+        let response = client
+            .post("/offer")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .json(&form)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
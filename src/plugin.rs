@@ -0,0 +1,525 @@
+//! Core Lightning plugin notifying the paid invoices to a pay2email server, see the
+//! `pay2email-plugin` binary.
+//!
+//! The preimages of the paid invoices are written to a spool directory before being notified on
+//! `/invoice/paid`, they are removed only once the server acknowledges them, so that payments
+//! aren't lost while the server is unreachable.
+
+use crate::error::Result;
+use crate::Error;
+use bitcoin_hashes::hex::FromHex;
+use rocket::serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Notify};
+
+/// Options of the plugin, given in the node config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PluginConfig {
+    /// Url of the `/invoice/paid` endpoint
+    #[serde(rename = "pay2email-notify-url")]
+    pub notify_url: String,
+
+    /// Credentials of the endpoint
+    #[serde(rename = "pay2email-http-user")]
+    pub http_user: String,
+    #[serde(rename = "pay2email-http-password")]
+    pub http_password: String,
+
+    /// Directory of the preimages not yet acknowledged, relative to the node directory
+    #[serde(rename = "pay2email-spool-dir")]
+    pub spool_dir: PathBuf,
+
+    /// Only the invoices with labels starting with this prefix are notified. Empty by default, the
+    /// server ignores the payments of invoices unknown to it and the invoices uploaded before
+    /// the `pay2email-` labels have random ones
+    #[serde(rename = "pay2email-label-prefix")]
+    pub label_prefix: String,
+
+    /// Seconds between delivery attempts of the spooled preimages
+    #[serde(rename = "pay2email-retry-interval")]
+    pub retry_interval: u64,
+}
+
+/// Options with their defaults, as declared in the manifest
+fn options() -> Value {
+    let option = |name: &str, kind: &str, default: Value, description: &str| {
+        json!({
+            "name": name,
+            "type": kind,
+            "default": default,
+            "description": description,
+        })
+    };
+    json!([
+        option(
+            "pay2email-notify-url",
+            "string",
+            json!("https://pay2.email/invoice/paid"),
+            "The url where to notify the paid invoices",
+        ),
+        option(
+            "pay2email-http-user",
+            "string",
+            json!(""),
+            "User of the notify url",
+        ),
+        option(
+            "pay2email-http-password",
+            "string",
+            json!(""),
+            "Password of the notify url",
+        ),
+        option(
+            "pay2email-spool-dir",
+            "string",
+            json!("pay2email-spool"),
+            "Directory keeping the preimages until they are acknowledged",
+        ),
+        option(
+            "pay2email-label-prefix",
+            "string",
+            json!(""),
+            "Notify only the invoices with labels starting with this prefix",
+        ),
+        option(
+            "pay2email-retry-interval",
+            "int",
+            json!(60),
+            "Seconds between delivery attempts",
+        ),
+    ])
+}
+
+/// Directory containing a file for every preimage to notify, named as the hex preimage
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Spool { dir })
+    }
+
+    /// Durably add `preimage`, adding it twice keeps one copy
+    pub fn push(&self, preimage: &str) -> Result<()> {
+        // the preimage is used as file name
+        <[u8; 32]>::from_hex(preimage)?;
+        let tmp = self.dir.join(format!("{}.tmp", preimage));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(preimage.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(preimage))?;
+        Ok(())
+    }
+
+    /// The preimages not yet removed, oldest first
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if <[u8; 32]>::from_hex(&name).is_ok() {
+                entries.push((entry.metadata()?.modified()?, name));
+            }
+        }
+        entries.sort();
+        Ok(entries.into_iter().map(|(_, name)| name).collect())
+    }
+
+    pub fn remove(&self, preimage: &str) -> Result<()> {
+        fs::remove_file(self.dir.join(preimage))?;
+        Ok(())
+    }
+}
+
+/// Writes the messages to the node, so that the worker can log
+#[derive(Clone)]
+struct Output(mpsc::UnboundedSender<Value>);
+
+impl Output {
+    fn send(&self, message: Value) {
+        let _ = self.0.send(message);
+    }
+
+    fn log(&self, level: &str, message: String) {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "log",
+            "params": { "level": level, "message": message },
+        }));
+    }
+}
+
+struct Notifier {
+    config: PluginConfig,
+    http: reqwest::Client,
+    spool: Arc<Spool>,
+    wake: Arc<Notify>,
+    output: Output,
+}
+
+impl Notifier {
+    /// Notify `preimage`, any successful response acknowledges it
+    async fn notify(&self, preimage: &str) -> Result<()> {
+        self.http
+            .post(&self.config.notify_url)
+            .basic_auth(&self.config.http_user, Some(&self.config.http_password))
+            .body(preimage.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Deliver the spooled preimages, stopping at the first failure
+    async fn deliver(&self) -> Result<()> {
+        for preimage in self.spool.list()? {
+            self.notify(&preimage).await?;
+            self.spool.remove(&preimage)?;
+            self.output
+                .log("info", format!("notified preimage {}", preimage));
+        }
+        Ok(())
+    }
+
+    /// Deliver the spool when woken by a payment, or every `retry_interval` seconds
+    async fn run(self) {
+        let interval = Duration::from_secs(self.config.retry_interval);
+        loop {
+            if let Err(e) = self.deliver().await {
+                self.output
+                    .log("warn", format!("notification failed, will retry: {:?}", e));
+            }
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct InvoicePayment {
+    label: String,
+    preimage: String,
+}
+
+struct Plugin {
+    output: Output,
+    spool: Option<Arc<Spool>>,
+    config: Option<PluginConfig>,
+    wake: Arc<Notify>,
+    worker: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Plugin {
+    /// Handle a request or a notification of the node, returns the result of requests or `None`
+    /// if `method` is unknown
+    fn handle(&mut self, method: &str, params: Value) -> Result<Option<Value>> {
+        let result = match method {
+            "getmanifest" => json!({
+                "options": options(),
+                "rpcmethods": [],
+                "subscriptions": ["invoice_payment"],
+                "dynamic": false,
+            }),
+            "init" => self.init(params)?,
+            "invoice_payment" => {
+                // newer nodes send the payment in `payload`
+                let payment = params
+                    .get("invoice_payment")
+                    .or_else(|| params.get("payload"))
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                let payment: InvoicePayment = serde_json::from_value(payment)?;
+                self.spool_payment(payment)?;
+                Value::Null
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(result))
+    }
+
+    fn init(&mut self, params: Value) -> Result<Value> {
+        let options = params.get("options").cloned().unwrap_or(Value::Null);
+        let config: PluginConfig =
+            serde_json::from_value(options).map_err(|e| Error::InvalidConfig(e.to_string()))?;
+        let spool = Arc::new(Spool::open(config.spool_dir.clone())?);
+        self.output.log(
+            "info",
+            format!("notifying paid invoices to {}", config.notify_url),
+        );
+        let notifier = Notifier {
+            config: config.clone(),
+            http: reqwest::Client::new(),
+            spool: spool.clone(),
+            wake: self.wake.clone(),
+            output: self.output.clone(),
+        };
+        self.worker = Some(tokio::spawn(notifier.run()));
+        self.spool = Some(spool);
+        self.config = Some(config);
+        Ok(json!({}))
+    }
+
+    fn spool_payment(&self, payment: InvoicePayment) -> Result<()> {
+        let (config, spool) = match (self.config.as_ref(), self.spool.as_ref()) {
+            (Some(config), Some(spool)) => (config, spool),
+            _ => return Err(Error::InvalidConfig("payment before init".to_string())),
+        };
+        if !payment.label.starts_with(&config.label_prefix) {
+            return Ok(());
+        }
+        spool.push(&payment.preimage)?;
+        self.output.log(
+            "info",
+            format!("invoice {} paid, preimage spooled", payment.label),
+        );
+        self.wake.notify_one();
+        Ok(())
+    }
+}
+
+/// Run the plugin reading the messages of the node from `input` and writing to `output`, until
+/// `input` ends
+pub async fn run<R, W>(mut input: R, mut output: W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            let mut bytes = serde_json::to_vec(&message).expect("serializable");
+            bytes.extend_from_slice(b"\n\n");
+            if output.write_all(&bytes).await.is_err() || output.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+    let mut plugin = Plugin {
+        output: Output(sender),
+        spool: None,
+        config: None,
+        wake: Arc::new(Notify::new()),
+        worker: None,
+    };
+
+    // messages are json objects separated by blank lines
+    let mut buffer = vec![];
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read = input.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        let mut stream = serde_json::Deserializer::from_slice(&buffer).into_iter::<Value>();
+        let mut messages = vec![];
+        let consumed = loop {
+            match stream.next() {
+                Some(Ok(message)) => messages.push(message),
+                Some(Err(e)) if e.is_eof() => break stream.byte_offset(),
+                Some(Err(e)) => return Err(e.into()),
+                None => break stream.byte_offset(),
+            }
+        };
+        buffer.drain(..consumed);
+
+        for message in messages {
+            let method = message["method"].as_str().unwrap_or_default();
+            let params = message.get("params").cloned().unwrap_or(Value::Null);
+            let result = plugin.handle(method, params);
+            match (message.get("id"), result) {
+                (Some(id), Ok(Some(result))) => plugin.output.send(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": result,
+                })),
+                (Some(id), Ok(None)) => plugin.output.send(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": -32601, "message": format!("unknown method {}", method) },
+                })),
+                // the node may send notifications the plugin didn't subscribe to
+                (None, Ok(_)) => {}
+                (Some(id), Err(e)) => plugin.output.send(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": -32600, "message": format!("{:?}", e) },
+                })),
+                (None, Err(e)) => plugin
+                    .output
+                    .log("broken", format!("handling {}: {:?}", method, e)),
+            }
+        }
+    }
+
+    if let Some(worker) = plugin.worker.take() {
+        worker.abort();
+    }
+    drop(plugin);
+    let _ = writer.await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::plugin::{run, Spool};
+    use crate::test_util;
+    use bitcoin_hashes::hex::ToHex;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    async fn write(node: &mut DuplexStream, message: Value) {
+        let mut bytes = serde_json::to_vec(&message).unwrap();
+        bytes.extend_from_slice(b"\n\n");
+        node.write_all(&bytes).await.unwrap();
+    }
+
+    /// Read the next response of the plugin, skipping log notifications
+    async fn response(node: &mut DuplexStream, buffer: &mut Vec<u8>) -> Value {
+        loop {
+            while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+                let message: Value = serde_json::from_slice(&buffer[..end]).unwrap();
+                buffer.drain(..end + 2);
+                if message.get("id").is_some() {
+                    return message;
+                }
+            }
+            let mut chunk = [0u8; 4096];
+            let read = node.read(&mut chunk).await.unwrap();
+            assert_ne!(read, 0);
+            buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    #[test]
+    fn test_spool() {
+        let spool = Spool::open(test_util::temp_path("pay2email-spool")).unwrap();
+        assert!(spool.push("not hex").is_err());
+        let preimage = [1u8; 32].to_hex();
+        spool.push(&preimage).unwrap();
+        spool.push(&preimage).unwrap();
+        assert_eq!(spool.list().unwrap(), vec![preimage.clone()]);
+        spool.remove(&preimage).unwrap();
+        assert!(spool.list().unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_plugin() {
+        // the server is unavailable for the first request
+        let requests = Arc::new(AtomicUsize::new(0));
+        let notified = Arc::new(Mutex::new(vec![]));
+        let server_requests = requests.clone();
+        let server_notified = notified.clone();
+        let url = test_util::http_server(move |method, path, head, body| {
+            if server_requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                return (503, "".to_string());
+            }
+            match (method, path) {
+                ("POST", "/invoice/paid") if head.contains(test_util::HTTP_AUTH_BASIC) => {
+                    let preimage = String::from_utf8(body.to_vec()).unwrap();
+                    server_notified.lock().unwrap().push(preimage);
+                    (200, "{}".to_string())
+                }
+                _ => (401, "".to_string()),
+            }
+        });
+        let spool_dir = test_util::temp_path("pay2email-spool");
+        let (mut node, plugin) = tokio::io::duplex(64 * 1024);
+        let (plugin_input, plugin_output) = tokio::io::split(plugin);
+        let plugin = tokio::spawn(run(plugin_input, plugin_output));
+        let mut buffer = vec![];
+
+        write(
+            &mut node,
+            json!({"jsonrpc": "2.0", "id": 1, "method": "getmanifest", "params": {}}),
+        )
+        .await;
+        let manifest = response(&mut node, &mut buffer).await;
+        assert_eq!(manifest["id"], 1);
+        assert_eq!(
+            manifest["result"]["subscriptions"],
+            json!(["invoice_payment"])
+        );
+
+        let options = json!({
+            "pay2email-notify-url": format!("{}/invoice/paid", url),
+            "pay2email-http-user": "test",
+            "pay2email-http-password": "test",
+            "pay2email-spool-dir": spool_dir,
+            "pay2email-label-prefix": "pay2email-",
+            "pay2email-retry-interval": 1,
+        });
+        write(
+            &mut node,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "init",
+                "params": { "options": options, "configuration": {} },
+            }),
+        )
+        .await;
+        let init = response(&mut node, &mut buffer).await;
+        assert_eq!(init["result"], json!({}));
+
+        // unknown notifications are ignored, unknown requests are errors
+        write(
+            &mut node,
+            json!({"jsonrpc": "2.0", "method": "shutdown", "params": {}}),
+        )
+        .await;
+        write(
+            &mut node,
+            json!({"jsonrpc": "2.0", "id": 3, "method": "unknown", "params": {}}),
+        )
+        .await;
+        let unknown = response(&mut node, &mut buffer).await;
+        assert_eq!(unknown["id"], 3);
+        assert_eq!(unknown["error"]["code"], -32601);
+
+        let paid = [2u8; 32].to_hex();
+        let other = [3u8; 32].to_hex();
+        for (label, preimage) in [("pay2email-1", &paid), ("other", &other)] {
+            let payment = json!({"label": label, "preimage": preimage, "msat": "20000msat"});
+            write(
+                &mut node,
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "invoice_payment",
+                    "params": { "invoice_payment": payment },
+                }),
+            )
+            .await;
+        }
+
+        // delivered after the failure, then removed from the spool
+        let spool = Spool::open(spool_dir).unwrap();
+        for _ in 0..50 {
+            if !notified.lock().unwrap().is_empty() && spool.list().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*notified.lock().unwrap(), vec![paid]);
+        assert!(spool.list().unwrap().is_empty());
+        assert!(requests.load(Ordering::SeqCst) >= 2);
+
+        drop(node);
+        plugin.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use crate::error::Result;
+use crate::Error;
+use lightning_invoice::{Currency, Invoice};
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+
+/// Network of the invoices
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Network {
+    Bitcoin,
+    Testnet,
+    Signet,
+    Regtest,
+    Simnet,
+}
+
+impl Network {
+    fn currency(&self) -> Currency {
+        match self {
+            Network::Bitcoin => Currency::Bitcoin,
+            Network::Testnet => Currency::BitcoinTestnet,
+            Network::Signet => Currency::Signet,
+            Network::Regtest => Currency::Regtest,
+            Network::Simnet => Currency::Simnet,
+        }
+    }
+}
+
+/// Requirements of the invoices uploaded on `/invoice`, read from the optional `invoice_policy`
+/// table of the Rocket config. Available as managed state.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct InvoicePolicy {
+    /// Hex public keys of the nodes allowed as payee, any node when empty
+    pub payees: Vec<String>,
+
+    /// Network of the invoices
+    pub network: Network,
+
+    /// Range of the invoices amount, invoices without amount are refused
+    pub min_amount_msat: u64,
+    pub max_amount_msat: u64,
+}
+
+impl Default for InvoicePolicy {
+    fn default() -> Self {
+        InvoicePolicy {
+            payees: vec![],
+            network: Network::Bitcoin,
+            min_amount_msat: 1_000,
+            max_amount_msat: 10_000_000,
+        }
+    }
+}
+
+impl InvoicePolicy {
+    /// Check the invoice `bolt11` is payable to an allowed node, on the expected network and of an
+    /// amount in range
+    pub fn check(&self, bolt11: &str) -> Result<()> {
+        let invoice: Invoice = bolt11.parse()?;
+
+        let payee = invoice
+            .payee_pub_key()
+            .cloned()
+            .unwrap_or_else(|| invoice.recover_payee_pub_key())
+            .to_string();
+        if !self.payees.is_empty() && !self.payees.iter().any(|p| p.eq_ignore_ascii_case(&payee)) {
+            return Err(Error::InvoicePayeeNotAllowed(payee));
+        }
+
+        if invoice.currency() != self.network.currency() {
+            return Err(Error::InvoiceWrongNetwork(invoice.currency()));
+        }
+
+        let amount_msat = invoice.amount_milli_satoshis();
+        match amount_msat {
+            Some(a) if (self.min_amount_msat..=self.max_amount_msat).contains(&a) => Ok(()),
+            _ => Err(Error::InvoiceAmountOutOfRange(amount_msat)),
+        }
+    }
+}
+
+/// Read the invoice policy, making it available as managed state
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Invoice Policy", |rocket| async {
+        let config = match rocket
+            .figment()
+            .focus("invoice_policy")
+            .extract::<InvoicePolicy>()
+        {
+            Ok(config) => config,
+            Err(e) => {
+                let e = Error::InvalidConfig(e.to_string());
+                println!("invalid invoice policy: {:?}", e);
+                return Err(rocket);
+            }
+        };
+        Ok(rocket.manage(config))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::policy::{InvoicePolicy, Network};
+    use crate::test_util;
+    use crate::Error;
+    use lightning_invoice::Currency;
+
+    #[test]
+    fn test_check() {
+        let policy = InvoicePolicy::default();
+        assert!(policy.check(&test_util::invoice([1; 32], 20_000)).is_ok());
+        assert!(matches!(
+            policy.check(&test_util::invoice([1; 32], 500)),
+            Err(Error::InvoiceAmountOutOfRange(Some(500)))
+        ));
+        assert!(matches!(
+            policy.check(&test_util::invoice([1; 32], 20_000_000)),
+            Err(Error::InvoiceAmountOutOfRange(Some(20_000_000)))
+        ));
+        assert!(matches!(
+            policy.check(&test_util::invoice_without_amount([1; 32])),
+            Err(Error::InvoiceAmountOutOfRange(None))
+        ));
+
+        let testnet = test_util::invoice_on(Currency::BitcoinTestnet, [1; 32], 20_000);
+        assert!(matches!(
+            policy.check(&testnet),
+            Err(Error::InvoiceWrongNetwork(Currency::BitcoinTestnet))
+        ));
+        let policy = InvoicePolicy {
+            network: Network::Testnet,
+            ..InvoicePolicy::default()
+        };
+        assert!(policy.check(&testnet).is_ok());
+
+        let policy = InvoicePolicy {
+            payees: vec![test_util::node_pubkey().to_uppercase()],
+            ..InvoicePolicy::default()
+        };
+        assert!(policy.check(&test_util::invoice([1; 32], 20_000)).is_ok());
+        let policy = InvoicePolicy {
+            payees: vec!["02".repeat(33)],
+            ..InvoicePolicy::default()
+        };
+        assert!(matches!(
+            policy.check(&test_util::invoice([1; 32], 20_000)),
+            Err(Error::InvoicePayeeNotAllowed(payee)) if payee == test_util::node_pubkey()
+        ));
+    }
+}
@@ -0,0 +1,475 @@
+//! Hashcash proof of work, letting senders without a lightning wallet send emails for free to the
+//! recipients accepting it
+
+use crate::db::{ChallengeRow, EmailRow};
+use crate::encrypt::encrypt;
+use crate::error::Result;
+use crate::queue::Queue;
+use crate::ratelimit::RateLimit;
+use crate::routes::HttpAuth;
+use crate::{Db, Error};
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::{hmac, sha256, Hash, HashEngine};
+use chrono::Utc;
+use rocket::fairing::AdHoc;
+use rocket::http::Header;
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::State;
+use serde_json::{json, Value};
+
+/// Proof of work settings, read from the optional `pow` table of the Rocket config. Available as
+/// managed state.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct PowConfig {
+    /// Seconds a challenge can be used for after being issued
+    pub expiry: u64,
+    /// Difficulty required of every stamp, even if the recipient accepts less
+    pub min_bits: u32,
+    /// Highest difficulty the recipients can ask for
+    pub max_bits: u32,
+}
+
+impl Default for PowConfig {
+    fn default() -> Self {
+        PowConfig {
+            expiry: 10 * 60,
+            min_bits: 16,
+            max_bits: 32,
+        }
+    }
+}
+
+impl PowConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.min_bits > self.max_bits || self.max_bits > 256 {
+            return Err(Error::InvalidConfig(
+                "min_bits must not be above max_bits, nor max_bits above 256".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The difficulty required of a stamp for recipients accepting `bits`, at least `min_bits`
+    fn required_bits(&self, bits: u32) -> Result<u32> {
+        if bits > self.max_bits {
+            return Err(Error::InvalidStamp(format!(
+                "recipient difficulty higher than {} bits",
+                self.max_bits
+            )));
+        }
+        Ok(bits.max(self.min_bits))
+    }
+}
+
+/// The hash of a stamp, committing to the `challenge`, the `nonce`, the recipient field as
+/// submitted and the `message`.
+///
+/// Line breaks of the message are normalized, browsers submit them as `\r\n`
+pub fn stamp_hash(challenge: &str, nonce: u64, recipient: &str, message: &str) -> sha256::Hash {
+    let message = message.replace("\r\n", "\n");
+    let stamp = format!("{}:{}:{}:{}", challenge, nonce, recipient, message);
+    sha256::Hash::hash(stamp.as_bytes())
+}
+
+/// MAC of the opt-in of the recipients `to` to proofs of work of `bits`, keyed by a key derived
+/// from the server secret key
+fn opt_in_mac(to: &str, bits: u32) -> hmac::Hmac<sha256::Hash> {
+    let secret = std::env::var("AGE_SECRET_KEY").unwrap();
+    let key = sha256::Hash::hash(format!("pay2email pow opt-in:{}", secret).as_bytes());
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&key[..]);
+    engine.input(format!("{}\npow={}", to, bits).as_bytes());
+    hmac::Hmac::from_engine(engine)
+}
+
+/// The plaintext of `to_enc` for the recipients `to` accepting proofs of work of `bits`, followed
+/// by the line `mac=<hex>` authenticating the opt-in
+pub fn opt_in(to: &str, bits: u32) -> String {
+    format!(
+        "{}\npow={}\nmac={}",
+        to,
+        bits,
+        opt_in_mac(to, bits).to_hex()
+    )
+}
+
+/// Whether `mac` authenticates the opt-in of the recipients `to` to proofs of work of `bits`,
+/// compared in constant time
+pub fn verify_opt_in(to: &str, bits: u32, mac: &str) -> bool {
+    let expected = opt_in_mac(to, bits).to_hex();
+    expected.len() == mac.len()
+        && expected
+            .bytes()
+            .zip(mac.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Number of leading zero bits of `hash`
+fn difficulty(hash: &sha256::Hash) -> u32 {
+    let mut bits = 0;
+    for byte in hash.iter() {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Verify the stamp of `challenge` and `nonce` has the difficulty required by `config` for
+/// recipients accepting `bits` and spend its challenge, so that it can't be used twice. Returns the
+/// stamp hash, identifying the email in place of a payment hash
+pub async fn verify(
+    db: &Db,
+    config: &PowConfig,
+    challenge: &str,
+    nonce: u64,
+    recipient: &str,
+    message: &str,
+    bits: u32,
+) -> Result<String> {
+    let bits = config.required_bits(bits)?;
+    let hash = stamp_hash(challenge, nonce, recipient, message);
+    if difficulty(&hash) < bits {
+        return Err(Error::InvalidStamp(format!(
+            "difficulty lower than {} bits",
+            bits
+        )));
+    }
+    if !ChallengeRow::spend(db, challenge.to_string()).await? {
+        return Err(Error::InvalidStamp(
+            "challenge unknown, expired or already used".to_string(),
+        ));
+    }
+    Ok(hash.to_hex())
+}
+
+/// Add the email of a verified stamp and queue it for delivery, like a paid one
+pub async fn enqueue(db: &Db, queue: &Queue, email_row: EmailRow) -> Result<EmailRow> {
+    let payment_hash = email_row.payment_hash.clone();
+    EmailRow::add(db, email_row).await?;
+    let mut email_row = EmailRow::get(db, payment_hash).await?;
+    if email_row.enqueue(db).await? {
+        queue.wake();
+    }
+    Ok(email_row)
+}
+
+#[derive(Responder)]
+struct Challenge {
+    inner: Json<Value>,
+    cors: Header<'static>,
+}
+
+/// Encrypt the recipients `to` accepting proofs of work of `bits` in place of a payment. Only the
+/// operator can let senders skip the payment, `pow=` lines encrypted by anyone else are rejected
+#[post("/encrypt/pow?<bits>", data = "<to>")]
+fn encrypt_opt_in(to: &str, bits: u32, _auth: HttpAuth) -> Result<String> {
+    encrypt(&opt_in(to.trim(), bits))
+}
+
+/// Issue a challenge for the proof of work of an email, callable from the pages of the forms.
+/// Limited by client IP, since every challenge is stored until it expires
+#[get("/pow/challenge")]
+async fn challenge(_limit: RateLimit<'_>, db: Db, config: &State<PowConfig>) -> Result<Challenge> {
+    let challenge = rand::random::<[u8; 32]>().to_hex();
+    let expiry = chrono::Duration::seconds(config.expiry as i64);
+    let challenge_row = ChallengeRow {
+        challenge: challenge.clone(),
+        expires_at: Utc::now().naive_utc() + expiry,
+        spent_at: None,
+    };
+    ChallengeRow::add(&db, challenge_row).await?;
+    Ok(Challenge {
+        inner: Json(json!({
+            "challenge": challenge,
+            "expires_in": config.expiry,
+            "min_bits": config.min_bits,
+        })),
+        cors: Header::new("Access-Control-Allow-Origin", "*"),
+    })
+}
+
+/// Read the proof of work config, making it available as managed state, and mount the challenge
+/// and opt-in routes
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Proof of Work", |rocket| async {
+        let config = match rocket.figment().focus("pow").extract::<PowConfig>() {
+            Ok(config) => config,
+            Err(e) => {
+                let e = Error::InvalidConfig(e.to_string());
+                println!("invalid pow config: {:?}", e);
+                return Err(rocket);
+            }
+        };
+        if let Err(e) = config.validate() {
+            println!("invalid pow config: {:?}", e);
+            return Err(rocket);
+        }
+        Ok(rocket
+            .manage(config)
+            .mount("/", routes![challenge, encrypt_opt_in]))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mailer::MemoryMailer;
+    use crate::pow::{self, difficulty, stamp_hash, PowConfig};
+    use crate::test_util::{self, MockBackend};
+    use bitcoin_hashes::hex::FromHex;
+    use bitcoin_hashes::sha256;
+    use rocket::error::ErrorKind;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::{Client, LocalResponse};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_difficulty() {
+        let hash = |hex: &str| sha256::Hash::from_hex(&format!("{:0<64}", hex)).unwrap();
+        assert_eq!(difficulty(&hash("ff")), 0);
+        assert_eq!(difficulty(&hash("0f")), 4);
+        assert_eq!(difficulty(&hash("0001")), 15);
+        assert_eq!(difficulty(&hash("")), 256);
+        assert_ne!(
+            stamp_hash("c", 0, "to", "a\r\nb"),
+            stamp_hash("c", 1, "to", "a\r\nb")
+        );
+        assert_eq!(
+            stamp_hash("c", 0, "to", "a\r\nb"),
+            stamp_hash("c", 0, "to", "a\nb")
+        );
+    }
+
+    /// Find the nonce of a stamp of at least `bits` difficulty on a new challenge
+    async fn stamp(client: &Client, to_enc: &str, message: &str, bits: u32) -> (String, u64) {
+        let json: Value = client
+            .get("/pow/challenge")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let challenge = json["challenge"].as_str().unwrap().to_string();
+        let nonce = (0..)
+            .find(|nonce| difficulty(&stamp_hash(&challenge, *nonce, to_enc, message)) >= bits)
+            .unwrap();
+        (challenge, nonce)
+    }
+
+    #[test]
+    fn test_required_bits() {
+        let config = PowConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.required_bits(0).unwrap(), 16);
+        assert_eq!(config.required_bits(20).unwrap(), 20);
+        assert!(config.required_bits(33).is_err());
+        let invalid = PowConfig {
+            min_bits: 40,
+            ..config.clone()
+        };
+        assert!(invalid.validate().is_err());
+        let invalid = PowConfig {
+            max_bits: 257,
+            ..config
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    async fn post<'c>(client: &'c Client, body: String) -> LocalResponse<'c> {
+        client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body(body)
+            .dispatch()
+            .await
+    }
+
+    #[rocket::async_test]
+    async fn test_pow() {
+        let mailer = MemoryMailer::default();
+        let rocket = test_util::rocket_with(
+            test_util::figment().merge(("pow.min_bits", 4)),
+            Arc::new(mailer.clone()),
+            Arc::new(MockBackend::default()),
+        );
+        let client = Client::tracked(rocket).await.unwrap();
+        let to_enc = test_util::encrypted_opt_in("a@example.com", 8);
+        let form = |challenge: &str, nonce: u64| {
+            format!(
+                "to_enc={}&subject=Hi&message=Hello&pow_challenge={}&pow_nonce={}",
+                to_enc, challenge, nonce
+            )
+        };
+
+        let (challenge, nonce) = stamp(&client, &to_enc, "Hello", 8).await;
+        let json: Value = post(&client, form(&challenge, nonce))
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["state"], "queued");
+        assert!(json.get("bolt11").is_none());
+        let payment_hash = json["payment_hash"].as_str().unwrap();
+        test_util::wait_messages(&mailer, 1).await;
+        let messages = mailer.messages();
+        assert_eq!(messages[0].envelope().to()[0].to_string(), "a@example.com");
+        let info: Value = client
+            .post("/info")
+            .body(payment_hash)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(info["invoice_paid"], false);
+
+        // challenges are spent by the first email
+        let response = post(&client, form(&challenge, nonce)).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        // the stamp commits to the message
+        let (challenge, _) = stamp(&client, &to_enc, "Hello", 8).await;
+        let valid =
+            |nonce, message| difficulty(&stamp_hash(&challenge, nonce, &to_enc, message)) >= 8;
+        let nonce = (0..)
+            .find(|n| valid(*n, "Hello") && !valid(*n, "Spam"))
+            .unwrap();
+        let body = form(&challenge, nonce).replace("Hello", "Spam");
+        let response = post(&client, body).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        // the server requires at least min_bits, even of recipients accepting less
+        let to_enc = test_util::encrypted_opt_in("a@example.com", 0);
+        let (challenge, _) = stamp(&client, &to_enc, "Hello", 0).await;
+        let nonce = (0..)
+            .find(|n| difficulty(&stamp_hash(&challenge, *n, &to_enc, "Hello")) < 4)
+            .unwrap();
+        let body = format!(
+            "to_enc={}&subject=Hi&message=Hello&pow_challenge={}&pow_nonce={}",
+            to_enc, challenge, nonce
+        );
+        let response = post(&client, body).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        // recipients asking for more than max_bits can't be sent a stamp
+        let to_enc = test_util::encrypted_opt_in("a@example.com", 33);
+        let (challenge, nonce) = stamp(&client, &to_enc, "Hello", 4).await;
+        let body = format!(
+            "to_enc={}&subject=Hi&message=Hello&pow_challenge={}&pow_nonce={}",
+            to_enc, challenge, nonce
+        );
+        let response = post(&client, body).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        // opt-ins not authenticated by the server are invalid recipients
+        for plaintext in [
+            "a@example.com\npow=4".to_string(),
+            pow::opt_in("a@example.com", 4).replace("pow=4", "pow=5"),
+            pow::opt_in("b@example.com", 4).replace("b@", "a@"),
+        ] {
+            let to_enc = test_util::encrypted(&plaintext);
+            let (challenge, nonce) = stamp(&client, &to_enc, "Hello", 5).await;
+            let body = format!(
+                "to_enc={}&subject=Hi&message=Hello&pow_challenge={}&pow_nonce={}",
+                to_enc, challenge, nonce
+            );
+            let response = post(&client, body).await;
+            assert_ne!(response.status(), Status::Ok);
+        }
+
+        // recipients not accepting proofs of work must be paid
+        let to_enc = test_util::encrypted("a@example.com");
+        let (challenge, nonce) = stamp(&client, &to_enc, "Hello", 8).await;
+        let body = format!(
+            "to_enc={}&subject=Hi&message=Hello&pow_challenge={}&pow_nonce={}",
+            to_enc, challenge, nonce
+        );
+        let response = post(&client, body).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body = format!("to_enc={}&subject=Hi&message=Hello", to_enc);
+        let json: Value = post(&client, body).await.into_json().await.unwrap();
+        assert!(json["bolt11"].as_str().is_some());
+    }
+
+    #[rocket::async_test]
+    async fn test_encrypt_opt_in() {
+        std::env::set_var("AGE_SECRET_KEY", test_util::AGE_SECRET_KEY);
+        let rocket = test_util::rocket(test_util::figment(), MemoryMailer::default());
+        let client = Client::tracked(rocket).await.unwrap();
+        let response = client
+            .post("/encrypt/pow?bits=20")
+            .body("a@example.com")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .post("/encrypt/pow?bits=20")
+            .header(Header::new("authorization", test_util::HTTP_AUTH_BASIC))
+            .body("a@example.com")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_challenge_limited() {
+        let figment = test_util::figment()
+            .merge(("rate_limit.ip.capacity", 2))
+            .merge(("rate_limit.ip.per_hour", 1));
+        let rocket = test_util::rocket(figment, MemoryMailer::default());
+        let client = Client::tracked(rocket).await.unwrap();
+        for expected in [Status::Ok, Status::Ok, Status::TooManyRequests] {
+            let response = client
+                .get("/pow/challenge")
+                .remote("1.2.3.4:1000".parse().unwrap())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), expected);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_invalid_config() {
+        let figment = test_util::figment().merge(("pow.min_bits", 40));
+        let rocket = test_util::rocket(figment, MemoryMailer::default());
+        let error = rocket.ignite().await.err().unwrap();
+        assert!(matches!(error.kind(), ErrorKind::FailedFairings(_)));
+    }
+
+    #[rocket::async_test]
+    async fn test_stamped_escaped() {
+        let mailer = MemoryMailer::default();
+        let rocket = test_util::rocket_with(
+            test_util::figment().merge(("pow.min_bits", 4)),
+            Arc::new(mailer.clone()),
+            Arc::new(MockBackend::default()),
+        );
+        let client = Client::tracked(rocket).await.unwrap();
+        let to_enc = test_util::encrypted_opt_in("a@example.com", 4);
+        let message = "<script>alert(1)</script>";
+        let (challenge, nonce) = stamp(&client, &to_enc, message, 4).await;
+        let body = format!(
+            "to_enc={}&subject=Hi&message=%3Cscript%3Ealert(1)%3C%2Fscript%3E&reply_to=%3Cb%3E%40example.com&pow_challenge={}&pow_nonce={}",
+            to_enc, challenge, nonce
+        );
+        let html = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "text/html"))
+            .header(Header::new("referer", "javascript:alert(2)"))
+            .body(body)
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!html.contains("<script>alert"));
+        assert!(!html.contains("href=\"javascript:"));
+    }
+}
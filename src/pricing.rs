@@ -0,0 +1,90 @@
+use crate::Error;
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+
+/// Rates used to compute the price of an email, read from the optional `pricing` table of the
+/// Rocket config. Available as managed state. Amounts are in millisatoshi.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct PricingConfig {
+    /// Price of an email with one recipient, a message up to 1 KiB and no attachments
+    pub base_msat: u64,
+
+    /// Added for every recipient after the first
+    pub per_recipient_msat: u64,
+
+    /// Added for every started KiB of the message after the first
+    pub per_kib_msat: u64,
+
+    /// Added for every attachment
+    pub per_attachment_msat: u64,
+
+    /// Added for every started KiB of the attachments
+    pub per_attachment_kib_msat: u64,
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        PricingConfig {
+            base_msat: 20_000,
+            per_recipient_msat: 10_000,
+            per_kib_msat: 1_000,
+            per_attachment_msat: 10_000,
+            per_attachment_kib_msat: 100,
+        }
+    }
+}
+
+fn started_kib(bytes: usize) -> u64 {
+    (bytes as u64).div_ceil(1024)
+}
+
+impl PricingConfig {
+    /// The minimum amount to pay for an email of `message_len` bytes sent to `recipients`
+    /// mailboxes, with attachments of the given sizes in bytes
+    pub fn required_msat(
+        &self,
+        message_len: usize,
+        recipients: usize,
+        attachments: &[usize],
+    ) -> u64 {
+        let mut amount = self.base_msat;
+        amount += self.per_recipient_msat * (recipients.max(1) as u64 - 1);
+        amount += self.per_kib_msat * started_kib(message_len).saturating_sub(1);
+        for size in attachments {
+            amount += self.per_attachment_msat + self.per_attachment_kib_msat * started_kib(*size);
+        }
+        amount
+    }
+}
+
+/// Read the pricing config, making it available as managed state
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Pricing", |rocket| async {
+        let config = match rocket.figment().focus("pricing").extract::<PricingConfig>() {
+            Ok(config) => config,
+            Err(e) => {
+                let e = Error::InvalidConfig(e.to_string());
+                println!("invalid pricing config: {:?}", e);
+                return Err(rocket);
+            }
+        };
+        Ok(rocket.manage(config))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pricing::PricingConfig;
+
+    #[test]
+    fn test_required_msat() {
+        let config = PricingConfig::default();
+        assert_eq!(config.required_msat(0, 1, &[]), 20_000);
+        assert_eq!(config.required_msat(1024, 1, &[]), 20_000);
+        assert_eq!(config.required_msat(1025, 1, &[]), 21_000);
+        assert_eq!(config.required_msat(100, 0, &[]), 20_000);
+        assert_eq!(config.required_msat(100, 3, &[]), 40_000);
+        assert_eq!(config.required_msat(100, 1, &[1, 2048]), 40_300);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::backend::InvoiceBackend;
+use crate::db::{AttachmentRow, EmailRow, InvoiceRow};
+use crate::error::Result;
+use crate::failover;
+use crate::mailer::Outbox;
+use crate::refund;
+use crate::{Db, Error};
+use chrono::{NaiveDateTime, Utc};
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use rocket::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Email queue configuration, read from the optional `queue` table of the Rocket config.
+/// Durations are in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct QueueConfig {
+    /// How often the worker looks for emails to send, besides being woken up by paid invoices
+    pub interval: u64,
+
+    /// After this number of failed attempts the email is marked as permanently failed
+    pub max_attempts: i32,
+
+    /// Delay after the first failed attempt, doubled at every following failure
+    pub backoff_base: u64,
+
+    /// Maximum delay between two attempts
+    pub backoff_max: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            interval: 30,
+            max_attempts: 10,
+            backoff_base: 60,
+            backoff_max: 6 * 60 * 60,
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Delay before the next attempt, given the number of failed `attempts` so far (at least 1)
+    pub fn backoff(&self, attempts: i32) -> Duration {
+        let exp = (attempts.max(1) - 1).min(32) as u32;
+        let secs = self.backoff_base.saturating_mul(2u64.saturating_pow(exp));
+        Duration::from_secs(secs.min(self.backoff_max))
+    }
+
+    /// When to try again after the failure of the attempt number `attempts`, `None` if the email
+    /// must not be retried
+    pub fn next_attempt(&self, attempts: i32, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        if attempts >= self.max_attempts {
+            None
+        } else {
+            let backoff = chrono::Duration::from_std(self.backoff(attempts)).ok()?;
+            Some(now + backoff)
+        }
+    }
+}
+
+/// Managed state used to wake up the worker when an email is enqueued
+#[derive(Clone)]
+pub struct Queue {
+    config: QueueConfig,
+    notify: Arc<Notify>,
+}
+
+impl Queue {
+    /// Wake up the worker, so that enqueued emails are sent without waiting for the next interval
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Send due emails until none is left, returns the number of emails processed
+async fn process_due(
+    db: &Db,
+    outbox: &Outbox,
+    backend: &dyn InvoiceBackend,
+    config: &QueueConfig,
+) -> Result<usize> {
+    let mut processed = 0;
+    loop {
+        let due = EmailRow::list_due(db, 10).await?;
+        if due.is_empty() {
+            return Ok(processed);
+        }
+        for mut email_row in due {
+            processed += 1;
+            let invoice_row = InvoiceRow::get(db, email_row.payment_hash.clone())
+                .await
+                .ok();
+            let attachments = AttachmentRow::list(db, email_row.payment_hash.clone()).await?;
+            match outbox
+                .send(&email_row, invoice_row.as_ref(), &attachments)
+                .await
+            {
+                Ok(relay) => {
+                    email_row.set_delivered(db, relay).await?;
+                    AttachmentRow::delete(db, email_row.payment_hash.clone()).await?;
+                }
+                Err(e) => {
+                    // an unknown recipient isn't going to appear on the next attempts
+                    let next_attempt = if failover::is_permanent(&e) {
+                        None
+                    } else {
+                        config.next_attempt(email_row.attempts + 1, Utc::now().naive_utc())
+                    };
+                    println!(
+                        "email {:?} attempt {} failed: {:?}, next attempt: {:?}",
+                        email_row.id,
+                        email_row.attempts + 1,
+                        e,
+                        next_attempt
+                    );
+                    if next_attempt.is_none() {
+                        AttachmentRow::delete(db, email_row.payment_hash.clone()).await?;
+                    }
+                    email_row
+                        .set_attempt_failed(db, format!("{:?}", e), next_attempt)
+                        .await?;
+                    // refunded only if every relay rejected it, not if the relays kept failing
+                    // until the last attempt
+                    if failover::is_permanent(&e) {
+                        if let Some(invoice_row) = invoice_row.as_ref() {
+                            refund::record(db, backend, invoice_row).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn run(
+    db: Db,
+    outbox: Outbox,
+    backend: Arc<dyn InvoiceBackend>,
+    queue: Queue,
+    shutdown: Shutdown,
+) {
+    let interval = Duration::from_secs(queue.config.interval);
+    loop {
+        if let Err(e) = process_due(&db, &outbox, backend.as_ref(), &queue.config).await {
+            println!("email queue: {:?}", e);
+        }
+        match AttachmentRow::purge(&db).await {
+            Ok(0) => {}
+            Ok(purged) => println!("purged {} attachments of unsent emails", purged),
+            Err(e) => println!("purging attachments: {:?}", e),
+        }
+        tokio::select! {
+            _ = queue.notify.notified() => {},
+            _ = tokio::time::sleep(interval) => {},
+            _ = shutdown.clone() => break,
+        }
+    }
+}
+
+/// Manage the email queue and spawn the worker sending paid emails at liftoff
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Email Queue", |rocket| async {
+        let config = match rocket.figment().focus("queue").extract::<QueueConfig>() {
+            Ok(config) => config,
+            Err(e) => {
+                println!(
+                    "invalid queue config: {:?}",
+                    Error::InvalidConfig(e.to_string())
+                );
+                return Err(rocket);
+            }
+        };
+        let queue = Queue {
+            config,
+            notify: Arc::new(Notify::new()),
+        };
+        Ok(rocket
+            .manage(queue)
+            .attach(AdHoc::on_liftoff("Email Queue Worker", |rocket| {
+                Box::pin(async move {
+                    let db = Db::get_one(rocket).await.expect("database connection");
+                    let outbox = rocket.state::<Outbox>().expect("outbox").clone();
+                    let backend = rocket
+                        .state::<Arc<dyn InvoiceBackend>>()
+                        .expect("invoice backend")
+                        .clone();
+                    let queue = rocket.state::<Queue>().expect("queue").clone();
+                    tokio::spawn(run(db, outbox, backend, queue, rocket.shutdown()));
+                })
+            })))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::queue::QueueConfig;
+    use chrono::NaiveDateTime;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let config = QueueConfig {
+            interval: 30,
+            max_attempts: 5,
+            backoff_base: 60,
+            backoff_max: 300,
+        };
+        assert_eq!(config.backoff(0), Duration::from_secs(60));
+        assert_eq!(config.backoff(1), Duration::from_secs(60));
+        assert_eq!(config.backoff(2), Duration::from_secs(120));
+        assert_eq!(config.backoff(3), Duration::from_secs(240));
+        assert_eq!(config.backoff(4), Duration::from_secs(300));
+        assert_eq!(config.backoff(1000), Duration::from_secs(300));
+
+        let now = NaiveDateTime::from_timestamp(1_600_000_000, 0);
+        assert_eq!(
+            config.next_attempt(2, now),
+            Some(NaiveDateTime::from_timestamp(1_600_000_120, 0))
+        );
+        assert_eq!(config.next_attempt(5, now), None);
+    }
+}
@@ -0,0 +1,502 @@
+use crate::db::RateLimitRow;
+use crate::error::Result;
+use crate::{Db, Error};
+use chrono::{NaiveDateTime, Utc};
+use lettre::message::Mailboxes;
+use rocket::fairing::AdHoc;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::Deserialize;
+use rocket::Request;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Buckets kept in memory above this number are pruned of the full ones, then of the least
+/// recently updated ones
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+/// Minutes between the deletions of the buckets of the database full again
+const PRUNE_INTERVAL_MINUTES: i64 = 60;
+
+/// A token bucket holding up to `capacity` requests, refilled at `per_hour` requests per hour.
+/// Both must be given when overriding a limit
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Limit {
+    pub capacity: u32,
+    pub per_hour: u32,
+}
+
+impl Limit {
+    fn refill(&self, tokens: f64, elapsed_secs: f64) -> f64 {
+        let refilled = tokens + elapsed_secs.max(0.0) * self.per_hour as f64 / 3600.0;
+        refilled.min(self.capacity as f64)
+    }
+
+    /// Time after which an empty bucket is full again, `None` if never refilled
+    fn full_after(&self) -> Option<chrono::Duration> {
+        let secs = (self.capacity as i64 * 3600).checked_div(self.per_hour as i64)?;
+        Some(chrono::Duration::seconds(secs + 1))
+    }
+}
+
+/// Where the buckets are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Storage {
+    /// Lost at restart, not shared between instances
+    Memory,
+    /// The `rate_limits` table of the database
+    Sqlite,
+}
+
+/// Rate limits of the form submissions, read from the optional `rate_limit` table of the Rocket
+/// config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct RateLimitConfig {
+    pub storage: Storage,
+
+    /// Limit of every client IP
+    pub ip: Limit,
+
+    /// Limit of every recipient, counted after decryption
+    pub recipient: Limit,
+
+    /// Whether the client IP is read from the Rocket `ip_header` set by a reverse proxy, instead
+    /// of the address of the connection. Clients connecting directly could set it to anything
+    pub trusted_proxy: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            storage: Storage::Memory,
+            ip: Limit {
+                capacity: 20,
+                per_hour: 60,
+            },
+            recipient: Limit {
+                capacity: 10,
+                per_hour: 30,
+            },
+            trusted_proxy: false,
+        }
+    }
+}
+
+fn secs(duration: chrono::Duration) -> f64 {
+    duration.num_milliseconds() as f64 / 1000.0
+}
+
+/// Tokens left in a bucket at `updated_at`
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: NaiveDateTime,
+}
+
+impl Bucket {
+    fn full(limit: &Limit, now: NaiveDateTime) -> Self {
+        Bucket {
+            tokens: limit.capacity as f64,
+            updated_at: now,
+        }
+    }
+
+    /// Take a token at `now` if there is one left after refilling, returns whether it was taken
+    fn take(&mut self, limit: &Limit, now: NaiveDateTime) -> bool {
+        self.tokens = limit.refill(self.tokens, secs(now - self.updated_at));
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Key of the bucket of the client `ip`. An IPv6 client usually controls a whole /64, limited as one
+fn ip_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("ip:{}", ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => format!("ip:{}", ip),
+            None => {
+                let s = ip.segments();
+                format!("ip:{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+            }
+        },
+    }
+}
+
+/// Token buckets keyed by client IP and by recipient. Available as managed state.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    memory: Mutex<HashMap<String, (Limit, Bucket)>>,
+    /// Last deletion of the buckets of the database full again
+    pruned_at: Mutex<NaiveDateTime>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            memory: Mutex::new(HashMap::new()),
+            pruned_at: Mutex::new(NaiveDateTime::from_timestamp(0, 0)),
+        }
+    }
+
+    /// Take a token of every memory bucket of `keys` if none is empty, otherwise fails with the
+    /// first empty one
+    fn take_memory(
+        &self,
+        keys: &[String],
+        limit: Limit,
+        now: NaiveDateTime,
+    ) -> std::result::Result<(), String> {
+        let mut buckets = self.memory.lock().unwrap();
+        let missing =
+            |buckets: &HashMap<_, _>| keys.iter().filter(|k| !buckets.contains_key(*k)).count();
+        if buckets.len() + missing(&buckets) > MAX_MEMORY_BUCKETS {
+            // full buckets are the same as missing ones
+            buckets.retain(|_, (limit, b)| {
+                limit.refill(b.tokens, secs(now - b.updated_at)) < limit.capacity as f64
+            });
+        }
+        if buckets.len() + missing(&buckets) > MAX_MEMORY_BUCKETS {
+            // a tenth at a time, so that they aren't sorted at every new key
+            let mut updated: Vec<NaiveDateTime> =
+                buckets.values().map(|(_, b)| b.updated_at).collect();
+            updated.sort_unstable();
+            if let Some(oldest) = updated.get(MAX_MEMORY_BUCKETS / 10).copied() {
+                buckets.retain(|_, (_, b)| b.updated_at > oldest);
+            }
+        }
+        let mut taken = vec![];
+        for key in keys {
+            let mut bucket = match buckets.get(key) {
+                Some((_, bucket)) => *bucket,
+                None => Bucket::full(&limit, now),
+            };
+            if !bucket.take(&limit, now) {
+                return Err(key.clone());
+            }
+            taken.push((key.clone(), (limit, bucket)));
+        }
+        buckets.extend(taken);
+        Ok(())
+    }
+
+    /// Delete the buckets of the database full again, at most every `PRUNE_INTERVAL_MINUTES`
+    async fn prune(&self, db: &Db, now: NaiveDateTime) -> Result<()> {
+        {
+            let mut pruned_at = self.pruned_at.lock().unwrap();
+            if now - *pruned_at < chrono::Duration::minutes(PRUNE_INTERVAL_MINUTES) {
+                return Ok(());
+            }
+            *pruned_at = now;
+        }
+        for (prefix, limit) in [("ip:", self.config.ip), ("to:", self.config.recipient)] {
+            if let Some(full_after) = limit.full_after() {
+                RateLimitRow::delete_stale(db, prefix, now - full_after).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a token of every bucket of `keys`, failing with `RateLimited` without taking any if
+    /// one of them is empty
+    async fn take(&self, db: &Db, mut keys: Vec<String>, limit: Limit) -> Result<()> {
+        keys.sort();
+        keys.dedup();
+        let now = Utc::now().naive_utc();
+        let taken = match self.config.storage {
+            Storage::Memory => self.take_memory(&keys, limit, now),
+            Storage::Sqlite => {
+                self.prune(db, now).await?;
+                let read = keys.clone();
+                let update = move |rows: Vec<Option<RateLimitRow>>| {
+                    let mut updated = vec![];
+                    for (key, row) in keys.into_iter().zip(rows) {
+                        let mut bucket = match row {
+                            Some(row) => Bucket {
+                                tokens: row.tokens,
+                                updated_at: row.updated_at,
+                            },
+                            None => Bucket::full(&limit, now),
+                        };
+                        if !bucket.take(&limit, now) {
+                            return Err(key);
+                        }
+                        updated.push(RateLimitRow {
+                            key,
+                            tokens: bucket.tokens,
+                            updated_at: bucket.updated_at,
+                        });
+                    }
+                    Ok(updated)
+                };
+                RateLimitRow::update(db, read, update).await?
+            }
+        };
+        taken.map_err(Error::RateLimited)
+    }
+
+    /// Take a token of every recipient of `to`, or of none if one of them is over the limit
+    pub async fn recipients(&self, db: &Db, to: &Mailboxes) -> Result<()> {
+        let keys = to
+            .iter()
+            .map(|mailbox| format!("to:{}", mailbox.email.to_string().to_lowercase()))
+            .collect();
+        self.take(db, keys, self.config.recipient).await
+    }
+}
+
+/// Request guard taking a token of the client IP bucket, requests over the limit fail with
+/// `429 Too Many Requests`. The client IP is the address of the connection, or the `ip_header`
+/// with a trusted proxy. Requests of unknown IP aren't limited.
+pub struct RateLimit<'r>(pub &'r RateLimiter);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit<'r> {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let limiter = match request.rocket().state::<RateLimiter>() {
+            Some(limiter) => limiter,
+            None => return Outcome::Forward(()),
+        };
+        let ip = if limiter.config.trusted_proxy {
+            request.client_ip()
+        } else {
+            request.remote().map(|remote| remote.ip())
+        };
+        if let Some(ip) = ip {
+            let db = match request.guard::<Db>().await {
+                Outcome::Success(db) => db,
+                _ => return Outcome::Forward(()),
+            };
+            let keys = vec![ip_key(ip)];
+            if let Err(e) = limiter.take(&db, keys, limiter.config.ip).await {
+                return Outcome::Failure((rocket::http::Status::TooManyRequests, e));
+            }
+        }
+        Outcome::Success(RateLimit(limiter))
+    }
+}
+
+/// Read the rate limits, making the `RateLimiter` available as managed state
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Rate Limits", |rocket| async {
+        let config = match rocket
+            .figment()
+            .focus("rate_limit")
+            .extract::<RateLimitConfig>()
+        {
+            Ok(config) => config,
+            Err(e) => {
+                let e = Error::InvalidConfig(e.to_string());
+                println!("invalid rate limit config: {:?}", e);
+                return Err(rocket);
+            }
+        };
+        Ok(rocket.manage(RateLimiter::new(config)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::RateLimitRow;
+    use crate::mailer::MemoryMailer;
+    use crate::ratelimit::MAX_MEMORY_BUCKETS;
+    use crate::ratelimit::{ip_key, Bucket, Limit, RateLimitConfig, RateLimiter};
+    use crate::test_util::{self, MockBackend};
+    use crate::Db;
+    use chrono::{Duration, NaiveDateTime, Utc};
+    use rocket::figment::Figment;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_bucket() {
+        let limit = Limit {
+            capacity: 2,
+            per_hour: 60,
+        };
+        let start = NaiveDateTime::from_timestamp(1_700_000_000, 0);
+        let mut bucket = Bucket::full(&limit, start);
+        assert!(bucket.take(&limit, start));
+        assert!(bucket.take(&limit, start));
+        assert!(!bucket.take(&limit, start));
+        assert!(!bucket.take(&limit, start + Duration::seconds(59)));
+        assert!(bucket.take(&limit, start + Duration::seconds(60)));
+        assert!(!bucket.take(&limit, start + Duration::seconds(60)));
+
+        // refilled up to the capacity
+        let later = start + Duration::hours(1);
+        assert!(bucket.take(&limit, later));
+        assert!(bucket.take(&limit, later));
+        assert!(!bucket.take(&limit, later));
+    }
+
+    #[test]
+    fn test_ip_key() {
+        assert_eq!(ip_key("1.2.3.4".parse().unwrap()), "ip:1.2.3.4");
+        assert_eq!(ip_key("::ffff:1.2.3.4".parse().unwrap()), "ip:1.2.3.4");
+        let key = ip_key("2001:db8:1:2:3:4:5:6".parse().unwrap());
+        assert_eq!(key, "ip:2001:db8:1:2::/64");
+        assert_eq!(ip_key("2001:db8:1:2::ffff".parse().unwrap()), key);
+        assert_ne!(ip_key("2001:db8:1:3::1".parse().unwrap()), key);
+    }
+
+    #[test]
+    fn test_memory_bound() {
+        let limiter = RateLimiter::new(RateLimitConfig::default());
+        let limit = Limit {
+            capacity: 1,
+            per_hour: 1,
+        };
+        let start = NaiveDateTime::from_timestamp(1_700_000_000, 0);
+        for i in 0..=MAX_MEMORY_BUCKETS as i64 {
+            let now = start + Duration::seconds(i);
+            assert!(limiter
+                .take_memory(&[format!("ip:{}", i)], limit, now)
+                .is_ok());
+        }
+        let buckets = limiter.memory.lock().unwrap();
+        assert!(buckets.len() <= MAX_MEMORY_BUCKETS);
+        // the least recently updated are dropped
+        assert!(!buckets.contains_key("ip:0"));
+        assert!(buckets.contains_key(&format!("ip:{}", MAX_MEMORY_BUCKETS)));
+    }
+
+    async fn post(client: &Client, ip: &str, to: &str) -> Status {
+        post_via(client, ip, None, to).await
+    }
+
+    /// Post from `ip`, through a proxy setting `X-Real-IP` to `real_ip` if any
+    async fn post_via(client: &Client, ip: &str, real_ip: Option<&str>, to: &str) -> Status {
+        let mut request = client
+            .post("/")
+            .remote(format!("{}:1000", ip).parse().unwrap())
+            .header(ContentType::Form)
+            .body(format!("to={}&subject=Hi&message=Hello", to));
+        if let Some(real_ip) = real_ip {
+            request = request.header(Header::new("X-Real-IP", real_ip.to_string()));
+        }
+        request.dispatch().await.status()
+    }
+
+    async fn client(figment: Figment) -> Client {
+        let mailer = Arc::new(MemoryMailer::default());
+        let rocket = test_util::rocket_with(figment, mailer, Arc::new(MockBackend::default()));
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_ip_limit() {
+        let figment = test_util::figment()
+            .merge(("rate_limit.ip.capacity", 2))
+            .merge(("rate_limit.ip.per_hour", 1));
+        let client = client(figment).await;
+        assert_eq!(
+            post(&client, "1.2.3.4", "a%40example.com").await,
+            Status::Ok
+        );
+        assert_eq!(
+            post(&client, "1.2.3.4", "b%40example.com").await,
+            Status::Ok
+        );
+        assert_eq!(
+            post(&client, "1.2.3.4", "c%40example.com").await,
+            Status::TooManyRequests
+        );
+        assert_eq!(
+            post(&client, "5.6.7.8", "c%40example.com").await,
+            Status::Ok
+        );
+
+        // the header of the proxy isn't trusted by default
+        assert_eq!(
+            post_via(&client, "1.2.3.4", Some("9.9.9.9"), "c%40example.com").await,
+            Status::TooManyRequests
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_trusted_proxy() {
+        let figment = test_util::figment()
+            .merge(("rate_limit.ip.capacity", 1))
+            .merge(("rate_limit.ip.per_hour", 1))
+            .merge(("rate_limit.trusted_proxy", true));
+        let client = client(figment).await;
+        let proxy = "10.0.0.1";
+        for (real_ip, expected) in [
+            ("1.2.3.4", Status::Ok),
+            ("1.2.3.4", Status::TooManyRequests),
+            ("5.6.7.8", Status::Ok),
+        ] {
+            let to = "a%40example.com";
+            assert_eq!(post_via(&client, proxy, Some(real_ip), to).await, expected);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_recipient_limit() {
+        for storage in ["memory", "sqlite"] {
+            let figment = test_util::figment()
+                .merge(("rate_limit.storage", storage))
+                .merge(("rate_limit.recipient.capacity", 1))
+                .merge(("rate_limit.recipient.per_hour", 1));
+            let client = client(figment).await;
+            assert_eq!(
+                post(&client, "1.2.3.4", "a%40example.com").await,
+                Status::Ok
+            );
+            // recipients are counted case insensitively, from any IP
+            assert_eq!(
+                post(&client, "5.6.7.8", "A%40Example.com").await,
+                Status::TooManyRequests
+            );
+            // no token is taken if one of the recipients is over the limit
+            assert_eq!(
+                post(&client, "5.6.7.8", "0%40example.com%2C+a%40example.com").await,
+                Status::TooManyRequests
+            );
+            assert_eq!(
+                post(&client, "5.6.7.8", "0%40example.com").await,
+                Status::Ok
+            );
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_sqlite_prune() {
+        let figment = test_util::figment().merge(("rate_limit.storage", "sqlite"));
+        let client = client(figment).await;
+        let db = Db::get_one(client.rocket()).await.unwrap();
+        let now = Utc::now().naive_utc();
+        for (key, updated_at) in [("to:old@example.com", 1), ("to:new@example.com", 0)] {
+            let row = RateLimitRow {
+                key: key.to_string(),
+                tokens: 0.0,
+                updated_at: now - Duration::days(updated_at),
+            };
+            RateLimitRow::set(&db, row).await.unwrap();
+        }
+        drop(db);
+
+        // the buckets full again are deleted
+        assert_eq!(
+            post(&client, "1.2.3.4", "a%40example.com").await,
+            Status::Ok
+        );
+        let db = Db::get_one(client.rocket()).await.unwrap();
+        let get = |key: &str| RateLimitRow::get(&db, key.to_string());
+        assert!(get("to:old@example.com").await.unwrap().is_none());
+        assert!(get("to:new@example.com").await.unwrap().is_some());
+    }
+}
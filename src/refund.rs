@@ -0,0 +1,331 @@
+use crate::backend::{self, InvoiceBackend, PaymentStatus};
+use crate::db::{InvoiceRow, RefundRow};
+use crate::error::Result;
+use crate::lifecycle::Lifecycle;
+use crate::lnurl::{self, LnurlConfig};
+use crate::{qr, Db, Error};
+use bitcoin_hashes::hex::ToHex;
+use lightning_invoice::Invoice;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Record the refund of the email paid with `invoice_row`, which couldn't be delivered.
+///
+/// Nothing is owed if the invoice isn't paid, like held payments which are canceled instead, and
+/// nothing is recorded if `backend` can't pay the refunds
+pub async fn record(db: &Db, backend: &dyn InvoiceBackend, invoice_row: &InvoiceRow) -> Result<()> {
+    if !backend.supports_payments() {
+        return Ok(());
+    }
+    let amount_msat = match invoice_row.amount_msat {
+        Some(amount_msat) if invoice_row.state == Lifecycle::Paid => amount_msat,
+        _ => return Ok(()),
+    };
+    let refund_row = RefundRow {
+        payment_hash: invoice_row.id.clone(),
+        amount_msat,
+        k1: None,
+        bolt11: None,
+        paid_at: None,
+    };
+    RefundRow::add(db, refund_row).await?;
+    println!(
+        "refund of {} msat owed for invoice {}",
+        amount_msat, invoice_row.id
+    );
+    Ok(())
+}
+
+/// The LNURL-withdraw link of the refund owed for the email paid with `preimage`, which proves
+/// the sender paid it
+#[post("/refund", data = "<preimage>")]
+async fn refund_link(
+    db: Db,
+    config: &State<LnurlConfig>,
+    preimage: String,
+) -> Result<Option<Json<Value>>> {
+    let payment_hash = backend::payment_hash(preimage.trim())?;
+    let mut refund = match RefundRow::get(&db, payment_hash).await {
+        Ok(refund) => refund,
+        Err(_) => return Ok(None),
+    };
+    let k1 = match refund.k1.clone() {
+        Some(k1) => k1,
+        None => {
+            let k1 = rand::random::<[u8; 32]>().to_hex();
+            refund.set_k1(&db, k1.clone()).await?;
+            k1
+        }
+    };
+    let lnurl = lnurl::encode(&config.url(&format!("/lnurlw/{}", k1)))?;
+    let qr = qr::create_bmp_base64_qr(&format!("lightning:{}", lnurl))?;
+    Ok(Some(Json(json!({
+        "payment_hash": refund.payment_hash,
+        "amount_msat": refund.amount_msat,
+        "paid": refund.paid_at.is_some(),
+        "lnurl": lnurl,
+        "qr": qr,
+    }))))
+}
+
+/// LNURL-withdraw request of the refund with secret `k1`, of exactly the amount owed
+#[get("/lnurlw/<k1>")]
+async fn withdraw_request(db: Db, config: &State<LnurlConfig>, k1: &str) -> Json<Value> {
+    let refund = match RefundRow::get_by_k1(&db, k1.to_string()).await {
+        Ok(refund) if refund.bolt11.is_none() => refund,
+        Ok(_) => return lnurl::error("Refund already claimed"),
+        Err(_) => return lnurl::error("Unknown refund"),
+    };
+    Json(json!({
+        "tag": "withdrawRequest",
+        "callback": config.url("/lnurlw/callback"),
+        "k1": k1,
+        "defaultDescription": "Pay2.email refund",
+        "minWithdrawable": refund.amount_msat,
+        "maxWithdrawable": refund.amount_msat,
+    }))
+}
+
+/// LNURL-withdraw callback, the refund is paid to `pr` before answering.
+///
+/// The claim is released only if the payment definitely failed, an uncertain payment is looked up
+/// by [`reconcile`]
+#[get("/lnurlw/callback?<k1>&<pr>")]
+async fn withdraw_callback(
+    db: Db,
+    backend: &State<Arc<dyn InvoiceBackend>>,
+    k1: &str,
+    pr: &str,
+) -> Json<Value> {
+    let mut refund = match RefundRow::get_by_k1(&db, k1.to_string()).await {
+        Ok(refund) => refund,
+        Err(_) => return lnurl::error("Unknown refund"),
+    };
+    let invoice: Invoice = match pr.parse() {
+        Ok(invoice) => invoice,
+        Err(_) => return lnurl::error("Invalid invoice"),
+    };
+    if invoice.is_expired() || invoice.amount_milli_satoshis() != Some(refund.amount_msat as u64) {
+        return lnurl::error("Invoice expired or of the wrong amount");
+    }
+    // concurrent callbacks can't both pay
+    match refund.claim(&db, pr.to_string()).await {
+        Ok(true) => (),
+        Ok(false) => return lnurl::error("Refund already claimed"),
+        Err(e) => {
+            println!("claiming refund {}: {:?}", refund.payment_hash, e);
+            return lnurl::error("Refund not available, try later");
+        }
+    }
+    match backend.pay_invoice(pr).await {
+        Ok(()) => {}
+        Err(Error::PaymentFailed(e)) => {
+            println!("paying refund {}: {}", refund.payment_hash, e);
+            if let Err(e) = refund.release(&db).await {
+                println!("releasing refund {}: {:?}", refund.payment_hash, e);
+            }
+            return lnurl::error("Refund payment failed, try later");
+        }
+        Err(e) => {
+            println!(
+                "paying refund {}, looked up later: {:?}",
+                refund.payment_hash, e
+            );
+            return lnurl::error("Refund payment pending");
+        }
+    }
+    if let Err(e) = refund.set_paid(&db).await {
+        println!("refund {} paid, not recorded: {:?}", refund.payment_hash, e);
+    }
+    println!("refund {} paid", refund.payment_hash);
+    Json(json!({ "status": "OK" }))
+}
+
+/// Record the payments of the claimed refunds completed since claimed, and release the claims whose
+/// payment failed or was never attempted before the invoice expired. Returns the number of refunds
+/// paid or released
+pub async fn reconcile(db: &Db, backend: &dyn InvoiceBackend) -> Result<usize> {
+    let mut reconciled = 0;
+    for mut refund in RefundRow::list_claimed(db).await? {
+        let bolt11 = refund.bolt11.clone().unwrap_or_default();
+        let expired = match bolt11.parse::<Invoice>() {
+            Ok(invoice) => invoice.is_expired(),
+            Err(_) => true,
+        };
+        let result = match backend.lookup_payment(&bolt11).await {
+            Ok(Some(PaymentStatus::Complete)) => refund.set_paid(db).await.map(|_| "paid"),
+            Ok(Some(PaymentStatus::Failed)) => refund.release(db).await.map(|_| "released"),
+            Ok(None) if expired => refund.release(db).await.map(|_| "released"),
+            Ok(_) => continue,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(outcome) => {
+                println!(
+                    "refund {} {} by reconciliation",
+                    refund.payment_hash, outcome
+                );
+                reconciled += 1;
+            }
+            Err(e) => println!("reconciling refund {}: {:?}", refund.payment_hash, e),
+        }
+    }
+    Ok(reconciled)
+}
+
+/// Mount the routes claiming the refunds with LNURL-withdraw, if the invoice backend can pay them
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Refunds", |rocket| async {
+        let supported = rocket
+            .state::<Arc<dyn InvoiceBackend>>()
+            .is_some_and(|backend| backend.supports_payments());
+        if !supported {
+            return rocket;
+        }
+        rocket.mount(
+            "/",
+            routes![refund_link, withdraw_request, withdraw_callback],
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::{InvoiceStatus, PaymentStatus};
+    use crate::refund;
+    use crate::test_util::{self, FailingMailer, MockBackend, RejectingMailer};
+    use crate::Db;
+    use bech32::FromBase32;
+    use bitcoin_hashes::hex::ToHex;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn get_json(client: &Client, uri: String) -> Value {
+        client.get(uri).dispatch().await.into_json().await.unwrap()
+    }
+
+    /// Send an email, paid at once, and wait until its delivery failed. Returns its preimage
+    async fn failed_email(client: &Client, backend: &MockBackend) -> String {
+        let json: Value = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body("to=a%40example.com&subject=Hi&message=Hello")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let payment_hash = json["payment_hash"].as_str().unwrap().to_string();
+        let preimage = match backend.status(&payment_hash) {
+            Some(InvoiceStatus::Settled(preimage)) => preimage,
+            status => panic!("unexpected status {:?}", status),
+        };
+
+        // paid, looked up by the status page, then the only delivery attempt fails
+        let mut state = Value::Null;
+        for _ in 0..50 {
+            let json: Value = client
+                .post("/info")
+                .body(&payment_hash)
+                .dispatch()
+                .await
+                .into_json()
+                .await
+                .unwrap();
+            state = json["state"].clone();
+            if state == "failed" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(state, "failed");
+        preimage
+    }
+
+    #[rocket::async_test]
+    async fn test_refund() {
+        let backend = Arc::new(MockBackend::default());
+        let figment = test_util::figment().merge(("queue.max_attempts", 1));
+        let rocket = test_util::rocket_with(figment, Arc::new(RejectingMailer), backend.clone());
+        let client = Client::tracked(rocket).await.unwrap();
+        let preimage = failed_email(&client, &backend).await;
+
+        let response = client
+            .post("/refund")
+            .body([1u8; 32].to_hex())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        let json: Value = client
+            .post("/refund")
+            .body(&preimage)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["amount_msat"], 20_000);
+        assert_eq!(json["paid"], false);
+        let (_, data, _) = bech32::decode(json["lnurl"].as_str().unwrap()).unwrap();
+        let url = String::from_utf8(Vec::<u8>::from_base32(&data).unwrap()).unwrap();
+        let path = url.trim_start_matches("https://pay2.email").to_string();
+
+        let withdraw = get_json(&client, path).await;
+        assert_eq!(withdraw["tag"], "withdrawRequest");
+        assert_eq!(withdraw["maxWithdrawable"], 20_000);
+        let k1 = withdraw["k1"].as_str().unwrap();
+
+        let callback = |amount_msat| {
+            let pr = test_util::invoice([7u8; 32], amount_msat);
+            (pr.clone(), format!("/lnurlw/callback?k1={}&pr={}", k1, pr))
+        };
+        let (_, uri) = callback(10_000);
+        assert_eq!(get_json(&client, uri).await["status"], "ERROR");
+        assert!(backend.paid().is_empty());
+        let (pr, uri) = callback(20_000);
+
+        // the claim of a failed payment is released, the one of an uncertain payment is kept
+        backend.set_outcome(PaymentStatus::Failed);
+        assert_eq!(get_json(&client, uri.clone()).await["status"], "ERROR");
+        backend.set_outcome(PaymentStatus::Pending);
+        assert_eq!(get_json(&client, uri.clone()).await["status"], "ERROR");
+        let json = get_json(&client, uri).await;
+        assert_eq!(json["reason"], "Refund already claimed");
+        assert!(backend.paid().is_empty());
+
+        let db = Db::get_one(client.rocket()).await.unwrap();
+        assert_eq!(refund::reconcile(&db, backend.as_ref()).await.unwrap(), 0);
+        backend.complete_payments();
+        assert_eq!(refund::reconcile(&db, backend.as_ref()).await.unwrap(), 1);
+        assert_eq!(backend.paid(), vec![pr]);
+
+        let json: Value = client
+            .post("/refund")
+            .body(&preimage)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["paid"], true);
+    }
+
+    #[rocket::async_test]
+    async fn test_relay_failures_not_refunded() {
+        // only the emails rejected by every relay are refunded
+        let backend = Arc::new(MockBackend::default());
+        let figment = test_util::figment().merge(("queue.max_attempts", 1));
+        let rocket = test_util::rocket_with(figment, Arc::new(FailingMailer), backend.clone());
+        let client = Client::tracked(rocket).await.unwrap();
+        let preimage = failed_email(&client, &backend).await;
+        let response = client.post("/refund").body(&preimage).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
@@ -1,28 +1,36 @@
+use crate::api;
+use crate::attachment::{self, AttachmentConfig, Upload};
+use crate::backend::{self, InvoiceBackend, InvoiceStatus};
 use crate::db::run_migrations;
-use crate::db::{EmailRow, InvoiceRow};
+use crate::db::{AttachmentRow, EmailRow, InvoiceRow};
 use crate::encrypt::decrypt;
 use crate::error::Result;
+use crate::hold;
+use crate::lifecycle::Lifecycle;
+use crate::lnurl::{self, LnurlConfig};
+use crate::offer;
+use crate::policy::{self, InvoicePolicy};
+use crate::pow::{self, PowConfig};
+use crate::pricing::{self, PricingConfig};
+use crate::queue::Queue;
+use crate::ratelimit::{self, RateLimit, RateLimiter};
+use crate::refund;
+use crate::template::{self, escape_html};
 use crate::{qr, Db, Error};
-use bitcoin_hashes::hex::{FromHex, ToHex};
-use bitcoin_hashes::{sha256, Hash};
-use chrono::NaiveDateTime;
 use lettre::message::{Mailbox, Mailboxes};
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::transport::smtp::client::{Tls, TlsParameters};
-use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
-use lightning_invoice::Invoice;
 use rocket::fairing::AdHoc;
 use rocket::form::{DataField, Form, FromFormField, ValueField};
-use rocket::http::{ContentType, Status};
+use rocket::http::{Accept, ContentType, MediaType, Status};
 use rocket::request::{FromRequest, Outcome};
 use rocket::response::status::Created;
 use rocket::serde::json::Json;
-use rocket::{form, Request};
+use rocket::{form, Request, State};
 use serde::Serialize;
+use serde_json::json;
 use std::env;
-use std::time::UNIX_EPOCH;
+use std::sync::Arc;
 
-struct HttpAuth;
+pub(crate) struct HttpAuth;
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for HttpAuth {
@@ -38,25 +46,17 @@ impl<'r> FromRequest<'r> for HttpAuth {
     }
 }
 
-/// Add an invoice in the database
+/// Add an invoice in the database, if allowed by the invoice policy
 #[post("/invoice", data = "<bolt11>")]
-async fn invoice_add(db: Db, bolt11: String, _auth: HttpAuth) -> Result<Created<Json<InvoiceRow>>> {
-    let invoice: Invoice = bolt11.parse()?;
-    let time = invoice.timestamp() + invoice.expiry_time();
-    let expiration =
-        NaiveDateTime::from_timestamp(time.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64, 0);
-
-    if invoice.is_expired() {
-        return Err(Error::InvoiceExpired);
-    }
-
-    let invoice_row = InvoiceRow {
-        id: invoice.payment_hash().to_hex(),
-        bolt11,
-        expiration,
-        paid: false,
-        showed: false,
-    };
+async fn invoice_add(
+    db: Db,
+    policy: &State<InvoicePolicy>,
+    bolt11: String,
+    _auth: HttpAuth,
+) -> Result<Created<Json<InvoiceRow>>> {
+    // invoices without amount are refused, as out of range
+    policy.check(&bolt11)?;
+    let invoice_row = InvoiceRow::from_bolt11(bolt11)?;
     println!("invoice: {:?}", invoice_row);
 
     InvoiceRow::add(&db, invoice_row.clone()).await?;
@@ -64,16 +64,27 @@ async fn invoice_add(db: Db, bolt11: String, _auth: HttpAuth) -> Result<Created<
     Ok(Created::new("/").body(Json(invoice_row)))
 }
 
-/// Returns all the invoices available, checking they are not expired or near expiration
-#[get("/invoice/count")]
-async fn invoice_count(db: Db) -> Result<Json<i64>> {
-    Ok(Json(InvoiceRow::count_available(&db).await?))
+/// Returns the invoices available for an email of `amount_msat`, by default the price of the
+/// cheapest email, checking they are not expired or near expiration
+#[get("/invoice/count?<amount_msat>")]
+async fn invoice_count(
+    db: Db,
+    pricing: &State<PricingConfig>,
+    amount_msat: Option<u64>,
+) -> Result<Json<i64>> {
+    let amount_msat = amount_msat.unwrap_or(pricing.base_msat);
+    Ok(Json(
+        InvoiceRow::count_available(&db, amount_msat as i64).await?,
+    ))
 }
 
-/// Returns true if there are more than 10 invoices
+/// Returns true if there are more than 10 invoices for the cheapest email
 #[get("/invoice/count/enough")]
-async fn invoice_enough(db: Db) -> Result<Json<bool>> {
-    Ok(Json(InvoiceRow::count_available(&db).await? > 10))
+async fn invoice_enough(db: Db, pricing: &State<PricingConfig>) -> Result<Json<bool>> {
+    let amount_msat = pricing.base_msat as i64;
+    Ok(Json(
+        InvoiceRow::count_available(&db, amount_msat).await? > 10,
+    ))
 }
 
 /// Returns email sent
@@ -82,78 +93,110 @@ async fn email_sent(db: Db, _auth: HttpAuth) -> Result<Json<i64>> {
     Ok(Json(EmailRow::count_sent(&db).await?))
 }
 
-/// Set the invoice to paid, send the email
+/// Set the invoice to paid, enqueue the email for delivery.
+///
+/// Notifying an already paid invoice doesn't enqueue the email again and returns the current state
 #[post("/invoice/paid", data = "<preimage>")]
-async fn invoice_paid(db: Db, preimage: String, _auth: HttpAuth) -> Result<Json<InvoiceRow>> {
-    let preimage = Vec::<u8>::from_hex(&preimage)?;
-    let payment_hash = sha256::Hash::hash(&preimage);
-    let mut invoice = InvoiceRow::get(&db, payment_hash.into_inner().to_hex()).await?;
-    invoice.set_paid(&db).await?;
-
-    let mut email_row = EmailRow::get(&db, invoice.id.clone()).await?;
-    send_email(&email_row).await?;
-    email_row.set_sent(&db).await?;
+async fn invoice_paid(
+    db: Db,
+    queue: &State<Queue>,
+    preimage: String,
+    _auth: HttpAuth,
+) -> Result<Json<Info>> {
+    let payment_hash = backend::payment_hash(&preimage)?;
+    let (invoice, email_row) = backend::settle(&db, queue, payment_hash).await?;
 
-    Ok(Json(invoice))
+    Ok(Json(Info::new(&invoice, Some(&email_row))))
 }
 
 #[derive(Serialize)]
-struct Info {
+pub(crate) struct Info {
     payment_hash: String,
     invoice_paid: bool,
     email_sent: bool,
+    state: Lifecycle,
 }
 
-/// get info if the invoice is paid and the mail sent
-#[post("/info", data = "<payment_hash>")]
-async fn info(db: Db, payment_hash: String) -> Option<Json<Info>> {
-    let invoice_row = InvoiceRow::get(&db, payment_hash.clone()).await.ok()?;
-    if invoice_row.paid {
-        // gonna check if email is sent only if invoice is paid because it surely comes first
-        let email_row = EmailRow::get(&db, payment_hash.clone()).await.ok()?;
-
-        let info = Info {
-            email_sent: email_row.sent,
-            invoice_paid: invoice_row.paid,
-            payment_hash,
+impl Info {
+    fn new(invoice_row: &InvoiceRow, email_row: Option<&EmailRow>) -> Self {
+        // the email state is more advanced once the invoice is paid, or its payment held
+        let state = match email_row {
+            Some(email_row)
+                if invoice_row.state == Lifecycle::Paid
+                    || email_row.state != Lifecycle::Reserved =>
+            {
+                email_row.state
+            }
+            _ => invoice_row.state,
         };
-        Some(Json(info))
-    } else {
-        let info = Info {
-            email_sent: false,
+        Info {
+            payment_hash: invoice_row.id.clone(),
+            invoice_paid: invoice_row.state == Lifecycle::Paid,
+            email_sent: state == Lifecycle::Sent,
+            state,
+        }
+    }
+
+    /// Info of an email sent with a proof of work, identified by its stamp hash
+    fn stamped(email_row: &EmailRow) -> Self {
+        Info {
+            payment_hash: email_row.payment_hash.clone(),
             invoice_paid: false,
-            payment_hash,
-        };
-        Some(Json(info))
+            email_sent: email_row.state == Lifecycle::Sent,
+            state: email_row.state,
+        }
     }
 }
 
-pub(crate) async fn send_email(email_row: &EmailRow) -> Result<()> {
-    let mut builder = Message::builder()
-        .from("Pay2.email <noreply@pay2.email>".parse()?)
-        .subject(&email_row.subject);
-    for mbox in email_row.to_email.parse::<Mailboxes>()?.into_iter() {
-        builder = builder.to(mbox);
-    }
-    if let Some(reply_to) = email_row.reply_to_email.as_ref() {
-        builder = builder.reply_to(reply_to.parse()?)
+/// get info if the invoice is paid and the mail sent
+///
+/// A reserved invoice is looked up in the backend, so that a missed payment notification doesn't
+/// leave the payer waiting
+pub(crate) async fn lookup_info(
+    db: &Db,
+    backend: &dyn InvoiceBackend,
+    queue: &Queue,
+    payment_hash: String,
+) -> Option<Info> {
+    let mut invoice_row = match InvoiceRow::get(db, payment_hash.clone()).await {
+        Ok(invoice_row) => invoice_row,
+        // emails sent with a proof of work have no invoice
+        Err(_) => {
+            let email_row = EmailRow::get(db, payment_hash).await.ok()?;
+            return Some(Info::stamped(&email_row));
+        }
+    };
+    if invoice_row.state == Lifecycle::Reserved {
+        match backend.lookup_invoice(db, &payment_hash).await {
+            Ok(InvoiceStatus::Settled(preimage))
+                if backend::payment_hash(&preimage).ok().as_ref() == Some(&payment_hash) =>
+            {
+                let (invoice, email_row) = backend::settle(db, queue, payment_hash).await.ok()?;
+                return Some(Info::new(&invoice, Some(&email_row)));
+            }
+            Ok(InvoiceStatus::Accepted) => {
+                let (invoice, email_row) = hold::accept(db, queue, payment_hash).await.ok()?;
+                return Some(Info::new(&invoice, Some(&email_row)));
+            }
+            Ok(_) => (),
+            Err(e) => println!("looking up invoice {}: {:?}", payment_hash, e),
+        }
+        invoice_row = InvoiceRow::get(db, payment_hash.clone()).await.ok()?;
     }
-    let email = builder.body(email_row.message.clone())?;
-
-    let password = env::var("SMTP_PASSWORD").unwrap();
-    let creds = Credentials::new("noreply@pay2.email".to_string(), password);
-
-    let relay = "smtp.improvmx.com";
-    let tls_parameters = TlsParameters::new(relay.into())?;
-    let relay = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(relay)
-        .port(587)
-        .tls(Tls::Required(tls_parameters));
-
-    let mailer: AsyncSmtpTransport<Tokio1Executor> = relay.credentials(creds).build();
+    // the email of a held payment is sent before its invoice is paid
+    let email_row = EmailRow::get(db, payment_hash).await.ok();
+    Some(Info::new(&invoice_row, email_row.as_ref()))
+}
 
-    // Send the email
-    mailer.send(email).await?;
-    Ok(())
+#[post("/info", data = "<payment_hash>")]
+async fn info(
+    db: Db,
+    backend: &State<Arc<dyn InvoiceBackend>>,
+    queue: &State<Queue>,
+    payment_hash: String,
+) -> Option<Json<Info>> {
+    let info = lookup_info(&db, backend.as_ref(), queue, payment_hash).await?;
+    Some(Json(info))
 }
 
 #[derive(FromForm, Debug)]
@@ -169,13 +212,23 @@ pub struct SendData<'r> {
     to: Option<EMails>,
 
     /// Encrypted recipient
-    to_enc: Option<Encrypted<EMails>>,
+    to_enc: Option<Encrypted<Recipients>>,
 
     /// Email subkect in clear text, use `subject_enc` for encrypted version
     subject: Option<String>,
 
     /// Encrypted subject
     subject_enc: Option<Encrypted<String>>,
+
+    /// Files attached to the email, only in `multipart/form-data` submissions
+    attachments: Vec<Upload>,
+
+    /// Challenge of the proof of work sending the email without paying, issued by
+    /// `/pow/challenge`
+    pow_challenge: Option<String>,
+
+    /// Nonce of the proof of work, see [`pow::stamp_hash`]
+    pow_nonce: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -187,84 +240,168 @@ struct EMail(Mailbox);
 #[derive(Debug)]
 struct EMails(Mailboxes);
 
-#[rocket::async_trait]
-impl<'r> FromFormField<'r> for EMail {
-    fn from_value(field: ValueField<'r>) -> form::Result<'r, Self> {
-        let m: Mailbox = field
-            .value
+/// The recipients of `to_enc`, their plaintext may be followed by the lines of [`pow::opt_in`]
+/// accepting proofs of work of at least `bits` difficulty in place of a payment
+#[derive(Debug)]
+pub(crate) struct Recipients {
+    pub to: Mailboxes,
+    pow_bits: Option<u32>,
+
+    /// The field as submitted, which the proofs of work commit to
+    encrypted: String,
+}
+
+/// Read the whole content of a `multipart/form-data` field, up to the `string` limit
+async fn read_data<'r>(field: DataField<'r, '_>) -> form::Result<'r, String> {
+    <String as FromFormField>::from_data(field).await
+}
+
+fn decrypt_value<'v>(value: &str) -> form::Result<'v, String> {
+    decrypt(value).map_err(|e| {
+        form::Error::validation(format!("Cannot decrypt email field: {:?} ", e)).into()
+    })
+}
+
+impl EMail {
+    fn parse<'v>(value: &str) -> form::Result<'v, Self> {
+        let m: Mailbox = value
             .parse()
             .map_err(|e| form::Error::validation(format!("Cannot parse email: {:?}", e)))?;
         Ok(EMail(m))
     }
+}
+
+impl EMails {
+    fn parse<'v>(value: &str) -> form::Result<'v, Self> {
+        let m: Mailboxes = value
+            .parse()
+            .map_err(|e| form::Error::validation(format!("Cannot parse email: {:?}", e)))?;
+        Ok(EMails(m))
+    }
+}
 
-    async fn from_data(_field: DataField<'r, '_>) -> form::Result<'r, Self> {
-        todo!("async not implemented yet")
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for EMail {
+    fn from_value(field: ValueField<'r>) -> form::Result<'r, Self> {
+        EMail::parse(field.value)
+    }
+
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        EMail::parse(&read_data(field).await?)
     }
 }
 
 #[rocket::async_trait]
 impl<'r> FromFormField<'r> for Encrypted<EMail> {
     fn from_value(field: ValueField<'r>) -> form::Result<'r, Self> {
-        let decrypted_value = decrypt(field.value).map_err(|e| {
-            form::Error::validation(format!("Cannot decrypt email field: {:?} ", e))
-        })?;
-        let m: Mailbox = decrypted_value
-            .parse()
-            .map_err(|e| form::Error::validation(format!("Cannot parse v: {:?}", e)))?;
-        Ok(Encrypted(EMail(m)))
+        Ok(Encrypted(EMail::parse(&decrypt_value(field.value)?)?))
     }
 
-    async fn from_data(_field: DataField<'r, '_>) -> form::Result<'r, Self> {
-        todo!("async not implemented yet")
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        Ok(Encrypted(EMail::parse(&decrypt_value(
+            &read_data(field).await?,
+        )?)?))
     }
 }
 
 #[rocket::async_trait]
 impl<'r> FromFormField<'r> for EMails {
     fn from_value(field: ValueField<'r>) -> form::Result<'r, Self> {
-        let m: Mailboxes = field
-            .value
-            .parse()
-            .map_err(|e| form::Error::validation(format!("Cannot parse email: {:?}", e)))?;
-        Ok(EMails(m))
+        EMails::parse(field.value)
     }
 
-    async fn from_data(_field: DataField<'r, '_>) -> form::Result<'r, Self> {
-        todo!("async not implemented yet")
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        EMails::parse(&read_data(field).await?)
+    }
+}
+
+impl Recipients {
+    pub(crate) fn decrypt(encrypted: &str) -> Result<Self> {
+        let plaintext = decrypt(encrypted)?;
+        let mut lines = plaintext.lines();
+        let first = lines.next().unwrap_or_default();
+        let to = first.parse()?;
+        let (mut pow_bits, mut mac) = (None, None);
+        for line in lines.map(str::trim).filter(|l| !l.is_empty()) {
+            let invalid = || Error::InvalidRecipientOption(line.to_string());
+            match line.split_once('=') {
+                Some(("pow", bits)) => pow_bits = Some(bits.trim().parse().map_err(|_| invalid())?),
+                Some(("mac", value)) => mac = Some(value.trim()),
+                _ => return Err(invalid()),
+            }
+        }
+        // anyone can encrypt a `pow=` line, only the ones authenticated by the server are honoured
+        if let Some(bits) = pow_bits {
+            if !mac.is_some_and(|mac| pow::verify_opt_in(first, bits, mac)) {
+                return Err(Error::InvalidRecipientOption(format!(
+                    "pow={} without a valid mac",
+                    bits
+                )));
+            }
+        }
+        Ok(Recipients {
+            to,
+            pow_bits,
+            encrypted: encrypted.to_string(),
+        })
+    }
+
+    fn parse<'v>(encrypted: &str) -> form::Result<'v, Self> {
+        Recipients::decrypt(encrypted).map_err(|e| {
+            form::Error::validation(format!("Cannot decrypt email field: {:?} ", e)).into()
+        })
+    }
+
+    /// The difficulty of the proofs of work accepted by the recipients, and the field they commit
+    /// to
+    pub(crate) fn accepted_pow(&self) -> Option<(u32, String)> {
+        self.pow_bits.map(|bits| (bits, self.encrypted.clone()))
     }
 }
 
 #[rocket::async_trait]
-impl<'r> FromFormField<'r> for Encrypted<EMails> {
+impl<'r> FromFormField<'r> for Encrypted<Recipients> {
     fn from_value(field: ValueField<'r>) -> form::Result<'r, Self> {
-        let decrypted_value = decrypt(field.value).map_err(|e| {
-            form::Error::validation(format!("Cannot decrypt email field: {:?} ", e))
-        })?;
-        let m: Mailboxes = decrypted_value
-            .parse()
-            .map_err(|e| form::Error::validation(format!("Cannot parse v: {:?}", e)))?;
-        Ok(Encrypted(EMails(m)))
+        Ok(Encrypted(Recipients::parse(field.value)?))
     }
 
-    async fn from_data(_field: DataField<'r, '_>) -> form::Result<'r, Self> {
-        todo!("async not implemented yet")
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        Ok(Encrypted(Recipients::parse(&read_data(field).await?)?))
     }
 }
 
 #[rocket::async_trait]
 impl<'r> FromFormField<'r> for Encrypted<String> {
     fn from_value(field: ValueField<'r>) -> form::Result<'r, Self> {
-        let decrypted_value = decrypt(field.value).map_err(|e| {
-            form::Error::validation(format!("Cannot decrypt email field: {:?} ", e))
-        })?;
-        Ok(Encrypted(decrypted_value))
+        Ok(Encrypted(decrypt_value(field.value)?))
     }
 
-    async fn from_data(_field: DataField<'r, '_>) -> form::Result<'r, Self> {
-        todo!("async not implemented yet")
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        Ok(Encrypted(decrypt_value(&read_data(field).await?)?))
     }
 }
 
+/// The quality of `media_type` in the `accept` header, the weight of the most specific range
+/// matching it, zero if none does
+pub(crate) fn quality(accept: &Accept, media_type: &MediaType) -> f32 {
+    accept
+        .iter()
+        .filter_map(|range| {
+            let (top, sub) = (range.media_type().top(), range.media_type().sub());
+            let specificity = match (top.as_str(), sub.as_str()) {
+                ("*", "*") => 0,
+                (_, "*") if top == media_type.top() => 1,
+                _ if top == media_type.top() && sub == media_type.sub() => 2,
+                _ => return None,
+            };
+            Some((specificity, range.weight_or(1.0)))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, weight)| weight)
+        .unwrap_or(0.0)
+}
+
+/// The format of the response to the form, JSON if preferred over HTML in the `accept` header
 struct AcceptEncoding(ContentType);
 
 #[rocket::async_trait]
@@ -272,9 +409,10 @@ impl<'r> FromRequest<'r> for AcceptEncoding {
     type Error = Error;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let accept_encoding = request.headers().get("accept").next();
-        match accept_encoding {
-            Some(str) if str == "application/json" => {
+        match request.accept() {
+            Some(accept)
+                if quality(accept, &MediaType::JSON) > quality(accept, &MediaType::HTML) =>
+            {
                 Outcome::Success(AcceptEncoding(ContentType::JSON))
             }
             _ => Outcome::Success(AcceptEncoding(ContentType::HTML)),
@@ -283,7 +421,7 @@ impl<'r> FromRequest<'r> for AcceptEncoding {
 }
 
 #[derive(Debug)]
-struct Referer(Option<String>);
+pub(crate) struct Referer(pub Option<String>);
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Referer {
@@ -299,62 +437,231 @@ impl<'r> FromRequest<'r> for Referer {
     }
 }
 
+impl Referer {
+    /// The html paragraph linking back to the referring page, empty without one. The header is
+    /// set by the client, so it's escaped and only http(s) urls are linked
+    fn back_to(&self) -> String {
+        match self.0.as_deref() {
+            Some(url) if url.starts_with("https://") || url.starts_with("http://") => {
+                let url = escape_html(url);
+                format!("<p>Back to <a href=\"{}\">{}</a></p>", url, url)
+            }
+            Some(url) => format!("<p>Back to {}</p>", escape_html(url)),
+            None => String::new(),
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct JsonResult {
     pub bolt11: String,
+    pub lnurl: Option<String>,
+    pub amount_msat: Option<i64>,
     pub reply_to: Option<String>,
     pub message: String,
     pub payment_hash: String,
 }
 
+/// Exactly one of the `clear` and the `encrypted` value of a field
+pub(crate) fn one_of<T>(
+    clear: Option<T>,
+    encrypted: Option<T>,
+    missing: Error,
+    only_one: Error,
+) -> Result<T> {
+    match (clear, encrypted) {
+        (Some(_), Some(_)) => Err(only_one),
+        (Some(value), None) | (None, Some(value)) => Ok(value),
+        (None, None) => Err(missing),
+    }
+}
+
+/// An email submitted with the form or the api, with its recipients and subject decrypted
+pub(crate) struct Submission {
+    pub to: Mailboxes,
+
+    /// The difficulty of the proofs of work accepted by the recipients, and the `to_enc` field
+    /// the proofs of work commit to
+    pub accepted_pow: Option<(u32, String)>,
+
+    pub subject: String,
+    pub message: String,
+    pub reply_to: Option<String>,
+    pub referer: Option<String>,
+    pub uploads: Vec<Upload>,
+
+    /// Challenge and nonce of the proof of work sending the email without paying
+    pub stamp: Option<(String, u64)>,
+}
+
+/// A submitted email, waiting for the payment of its invoice or queued with a proof of work
+pub(crate) enum Submitted {
+    Invoice(InvoiceRow, EmailRow),
+    Stamped(EmailRow),
+}
+
+impl SendData<'_> {
+    /// The submission of the form, taking its attachments
+    fn submission(&mut self, referer: Option<String>) -> Result<Submission> {
+        let to = self.to.as_ref().map(|e| (e.0.clone(), None));
+        let to_enc = self
+            .to_enc
+            .as_ref()
+            .map(|e| (e.0.to.clone(), e.0.accepted_pow()));
+        let (to, accepted_pow) = one_of(to, to_enc, Error::MissingTo, Error::OnlyOneTo)?;
+        let subject = one_of(
+            self.subject.clone(),
+            self.subject_enc.as_ref().map(|s| s.0.clone()),
+            Error::MissingSubject,
+            Error::OnlyOneSubject,
+        )?;
+        Ok(Submission {
+            to,
+            accepted_pow,
+            subject,
+            message: self.message.to_string(),
+            reply_to: self.reply_to.as_ref().map(|e| e.0.to_string()),
+            referer,
+            uploads: std::mem::take(&mut self.attachments),
+            stamp: self.pow_challenge.clone().zip(self.pow_nonce),
+        })
+    }
+}
+
+/// Validate the `submission` and create the invoice paying for the email, or queue it at once if
+/// it carries a valid proof of work
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn submit(
+    db: &Db,
+    attachment_config: &AttachmentConfig,
+    pricing: &PricingConfig,
+    pow_config: &PowConfig,
+    backend: &dyn InvoiceBackend,
+    queue: &Queue,
+    limiter: &RateLimiter,
+    submission: Submission,
+) -> Result<Submitted> {
+    let mut uploads = submission.uploads;
+    if submission.message.is_empty() {
+        return Err(Error::EmptyMessage);
+    }
+    uploads.retain(|u| !u.is_empty());
+    if uploads.len() > attachment_config.max_count {
+        return Err(Error::TooManyAttachments);
+    }
+
+    let attachment_sizes: Vec<_> = uploads.iter().map(|u| u.content.len()).collect();
+    let required_msat = pricing.required_msat(
+        submission.message.len(),
+        submission.to.iter().count(),
+        &attachment_sizes,
+    );
+
+    limiter.recipients(db, &submission.to).await?;
+    let email_row = |payment_hash: String| EmailRow {
+        id: None,
+        payment_hash,
+        reply_to_email: submission.reply_to.clone(),
+        to_email: submission.to.to_string(),
+        subject: submission.subject.clone(),
+        message: submission.message.clone(),
+        state: Lifecycle::Reserved,
+        attempts: 0,
+        next_attempt: None,
+        last_error: None,
+        relay: None,
+        referer: submission.referer.clone(),
+    };
+
+    if let Some((challenge, nonce)) = submission.stamp.as_ref() {
+        // only the recipients can accept a proof of work, in the encrypted form payload
+        let (bits, encrypted) = match submission.accepted_pow.as_ref() {
+            Some(accepted_pow) => accepted_pow,
+            None => {
+                let e = "proof of work not accepted by the recipient".to_string();
+                return Err(Error::InvalidStamp(e));
+            }
+        };
+        if !uploads.is_empty() {
+            let e = "attachments require a payment".to_string();
+            return Err(Error::InvalidStamp(e));
+        }
+        let message = &submission.message;
+        let stamp =
+            pow::verify(db, pow_config, challenge, *nonce, encrypted, message, *bits).await?;
+        let email_row = pow::enqueue(db, queue, email_row(stamp)).await?;
+        return Ok(Submitted::Stamped(email_row));
+    }
+
+    let email_row = email_row(String::new());
+    let invoice = backend
+        .create_invoice(
+            db,
+            required_msat,
+            Some(&lnurl::metadata()),
+            email_row.clone(),
+        )
+        .await?;
+    let email_row = EmailRow {
+        payment_hash: invoice.id.clone(),
+        ..email_row
+    };
+    if !uploads.is_empty() {
+        let attachment_rows = uploads
+            .into_iter()
+            .map(|u| AttachmentRow {
+                id: None,
+                payment_hash: invoice.id.clone(),
+                filename: u.filename,
+                content_type: u.content_type,
+                content: u.content,
+            })
+            .collect();
+        AttachmentRow::add(db, attachment_rows).await?;
+    }
+    Ok(Submitted::Invoice(invoice, email_row))
+}
+
 #[post("/", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
 async fn email(
     db: Db,
-    data: Form<SendData<'_>>,
+    mut data: Form<SendData<'_>>,
+    attachment_config: &State<AttachmentConfig>,
+    pricing: &State<PricingConfig>,
+    pow_config: &State<PowConfig>,
+    backend: &State<Arc<dyn InvoiceBackend>>,
+    lnurl: &State<LnurlConfig>,
+    queue: &State<Queue>,
     encoding: AcceptEncoding,
     referer: Referer,
+    limit: RateLimit<'_>,
 ) -> Result<(ContentType, String)> {
-    let to = match (data.to.as_ref(), data.to_enc.as_ref()) {
-        (Some(_), Some(_)) => return Err(Error::OnlyOneTo),
-        (Some(e), None) => e,
-        (None, Some(e)) => &e.0,
-        (None, None) => return Err(Error::MissingTo),
-    };
-    let subject = match (data.subject.as_ref(), data.subject_enc.as_ref()) {
-        (Some(_), Some(_)) => return Err(Error::OnlyOneSubject),
-        (Some(s), None) => s,
-        (None, Some(s)) => &s.0,
-        (None, None) => return Err(Error::MissingSubject),
-    };
-    if data.message.is_empty() {
-        return Err(Error::EmptyMessage);
-    }
-
-    let mut invoices = InvoiceRow::list_available_invoices(&db, 10).await?;
-    let message = data.message.to_string();
-    let reply_to = data.reply_to.as_ref().map(|e| e.0.to_string());
-
-    let invoice = loop {
-        let mut invoice = invoices.pop().ok_or_else(|| Error::InvoiceNotFound)?;
-        let email_row = EmailRow {
-            id: None,
-            payment_hash: invoice.id.to_string(),
-            reply_to_email: reply_to.clone(),
-            to_email: to.0.to_string(),
-            subject: subject.to_string(),
-            message: message.clone(),
-            sent: false,
-        };
-
-        if let Ok(_) = EmailRow::add(&db, email_row).await {
-            invoice.set_showed(&db).await?;
-            break invoice;
-        }
+    let submission = data.submission(referer.0.clone())?;
+    let submitted = submit(
+        &db,
+        attachment_config,
+        pricing,
+        pow_config,
+        backend.as_ref(),
+        queue,
+        limit.0,
+        submission,
+    )
+    .await?;
+    let (invoice, email_row) = match submitted {
+        Submitted::Invoice(invoice, email_row) => (invoice, email_row),
+        Submitted::Stamped(email_row) => return stamped(encoding, referer, &email_row),
     };
+    let message = email_row.message;
+    let reply_to = email_row.reply_to_email;
 
+    let lnurl = lnurl.pay_link(backend.as_ref(), &invoice.id)?;
     if encoding.0.is_json() {
         let json_result = JsonResult {
             bolt11: invoice.bolt11.clone(),
+            lnurl,
+            amount_msat: invoice.amount_msat,
             message,
             reply_to,
             payment_hash: invoice.id.clone(),
@@ -366,17 +673,34 @@ async fn email(
             invoice.bolt11.to_ascii_uppercase()
         ))?;
         let link = format!("lightning:{}", &invoice.bolt11);
-        let mut template = include_str!("../static/invoice.html").to_string();
-        if let Some(str) = referer.0.as_ref() {
-            let back_to = format!("<p>Back to <a href=\"{}\">{}</a></p>", str, str);
-            template = template.replace("{{ BACK_TO }}", &back_to);
-        }
-        template = template.replace("{{ REPLY_TO }}", &reply_to.unwrap_or("N/A".to_string()));
-        template = template.replace("{{ MESSAGE }}", &message);
-        template = template.replace("{{ QR }}", &qr);
-        template = template.replace("{{ INVOICE }}", &invoice.bolt11);
-        template = template.replace("{{ PAYMENT_HASH }}", &invoice.id);
-        template = template.replace("{{ LINK }}", &link);
+        // without LNURL support its section is hidden
+        let (lnurl_qr, lnurl_link, lnurl_hidden) = match lnurl.as_ref() {
+            Some(lnurl) => {
+                let lnurl_link = format!("lightning:{}", lnurl);
+                (qr::create_bmp_base64_qr(&lnurl_link)?, lnurl_link, "")
+            }
+            None => (String::new(), String::new(), " hidden"),
+        };
+        let back_to = referer.back_to();
+        let reply_to = escape_html(reply_to.as_deref().unwrap_or("N/A"));
+        let message = escape_html(&message);
+        let refunds = backend.supports_payments().to_string();
+        let lnurl = lnurl.unwrap_or_default();
+        let vars = [
+            ("BACK_TO", back_to.as_str()),
+            ("REPLY_TO", reply_to.as_str()),
+            ("MESSAGE", message.as_str()),
+            ("QR", qr.as_str()),
+            ("INVOICE", invoice.bolt11.as_str()),
+            ("PAYMENT_HASH", invoice.id.as_str()),
+            ("REFUNDS", refunds.as_str()),
+            ("LINK", link.as_str()),
+            ("LNURL_QR", lnurl_qr.as_str()),
+            ("LNURL_LINK", lnurl_link.as_str()),
+            ("LNURL", lnurl.as_str()),
+            ("LNURL_HIDDEN", lnurl_hidden),
+        ];
+        let template = template::render(include_str!("../static/invoice.html"), &vars);
 
         Ok((encoding.0, template))
     } else {
@@ -384,10 +708,52 @@ async fn email(
     }
 }
 
+/// The response to an email sent with a proof of work, already queued
+fn stamped(
+    encoding: AcceptEncoding,
+    referer: Referer,
+    email_row: &EmailRow,
+) -> Result<(ContentType, String)> {
+    if encoding.0.is_json() {
+        let json_result = json!({
+            "payment_hash": email_row.payment_hash,
+            "message": email_row.message,
+            "reply_to": email_row.reply_to_email,
+            "state": email_row.state,
+        });
+        Ok((encoding.0, json_result.to_string()))
+    } else if encoding.0.is_html() {
+        let back_to = referer.back_to();
+        let reply_to = escape_html(email_row.reply_to_email.as_deref().unwrap_or("N/A"));
+        let message = escape_html(&email_row.message);
+        let vars = [
+            ("BACK_TO", back_to.as_str()),
+            ("REPLY_TO", reply_to.as_str()),
+            ("MESSAGE", message.as_str()),
+            ("PAYMENT_HASH", email_row.payment_hash.as_str()),
+        ];
+        let template = template::render(include_str!("../static/stamped.html"), &vars);
+
+        Ok((encoding.0, template))
+    } else {
+        Err(Error::InvalidContentType(encoding.0))
+    }
+}
+
 pub fn stage() -> AdHoc {
     AdHoc::on_ignite("Diesel SQLite Stage", |rocket| async {
         rocket
             .attach(Db::fairing())
+            .attach(attachment::stage())
+            .attach(pricing::stage())
+            .attach(policy::stage())
+            .attach(ratelimit::stage())
+            .attach(hold::stage())
+            .attach(lnurl::stage())
+            .attach(offer::stage())
+            .attach(refund::stage())
+            .attach(pow::stage())
+            .attach(api::stage())
             .attach(AdHoc::on_ignite("Diesel Migrations", run_migrations))
             .mount(
                 "/",
@@ -406,7 +772,17 @@ pub fn stage() -> AdHoc {
 
 #[cfg(test)]
 mod test {
+    use crate::db::AttachmentRow;
+    use crate::mailer::MemoryMailer;
+    use crate::routes::quality;
+    use crate::test_util::{self, FailingMailer, MockBackend, HTTP_AUTH_BASIC};
+    use crate::Db;
+    use bitcoin_hashes::hex::ToHex;
     use lettre::message::{Mailbox, Mailboxes};
+    use rocket::http::{ContentType, Header, MediaType, Status};
+    use rocket::local::asynchronous::Client;
+    use serde_json::Value;
+    use std::sync::Arc;
 
     #[test]
     fn test_parse() {
@@ -427,4 +803,552 @@ mod test {
         let mbs = s.trim().parse::<Mailboxes>();
         assert!(mbs.is_ok());
     }
+
+    #[test]
+    fn test_quality() {
+        let quality =
+            |accept: &str, media_type: MediaType| quality(&accept.parse().unwrap(), &media_type);
+        assert_eq!(quality("application/json", MediaType::JSON), 1.0);
+        assert_eq!(quality("application/json", MediaType::HTML), 0.0);
+        assert_eq!(quality("*/*;q=0.1", MediaType::HTML), 0.1);
+        assert_eq!(
+            quality("application/*;q=0.5, */*;q=0.1", MediaType::JSON),
+            0.5
+        );
+
+        // the most specific range applies, even if of lower weight
+        let browser = "text/html, application/xhtml+xml, application/json;q=0.9, */*;q=0.8";
+        assert_eq!(quality(browser, MediaType::HTML), 1.0);
+        assert_eq!(quality(browser, MediaType::JSON), 0.9);
+        assert_eq!(quality("application/json;q=0, */*", MediaType::JSON), 0.0);
+    }
+
+    #[rocket::async_test]
+    async fn test_paid_email() {
+        let mailer = MemoryMailer::default();
+        let client = test_util::client(mailer.clone()).await;
+        let preimage = [1u8; 32];
+        let bolt11 = test_util::invoice(preimage, 20_000);
+
+        let response = client
+            .post("/invoice")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(&bolt11)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Created);
+
+        // refused by the default invoice policy
+        let response = client
+            .post("/invoice")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(test_util::invoice([9u8; 32], 100))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        // invoices without amount can't pay any email
+        let response = client
+            .post("/invoice")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(test_util::invoice_without_amount([7u8; 32]))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        // too cheap for any email, not counted
+        let response = client
+            .post("/invoice")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(test_util::invoice([8u8; 32], 10_000))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Created);
+
+        let count = |uri: &'static str| {
+            let client = &client;
+            async move {
+                client
+                    .get(uri)
+                    .dispatch()
+                    .await
+                    .into_json::<i64>()
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(count("/invoice/count").await, 1);
+        assert_eq!(count("/invoice/count?amount_msat=10000").await, 2);
+        assert_eq!(count("/invoice/count?amount_msat=30000").await, 0);
+
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body(
+                "to=to%40example.com&subject=Hi&message=Hello%20there&reply_to=from%40example.com",
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let json: Value = response.into_json().await.unwrap();
+        assert_eq!(json["bolt11"], bolt11);
+        let payment_hash = json["payment_hash"].as_str().unwrap().to_string();
+        assert_eq!(count("/invoice/count").await, 0);
+
+        // the node plugin may notify the same payment more than once
+        for _ in 0..2 {
+            let response = client
+                .post("/invoice/paid")
+                .header(Header::new("authorization", HTTP_AUTH_BASIC))
+                .body(preimage.to_hex())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            let json: Value = response.into_json().await.unwrap();
+            assert_eq!(json["invoice_paid"], true);
+        }
+        test_util::wait_messages(&mailer, 1).await;
+
+        let messages = mailer.messages();
+        let envelope = messages[0].envelope();
+        assert_eq!(envelope.from().unwrap().to_string(), "noreply@pay2.email");
+        assert_eq!(envelope.to()[0].to_string(), "to@example.com");
+        let formatted = String::from_utf8(messages[0].formatted()).unwrap();
+        assert!(formatted.contains("From: Pay2.email <noreply@pay2.email>\r\n"));
+        assert!(formatted.contains("Reply-To: from@example.com\r\n"));
+        assert!(formatted.contains("To: to@example.com\r\n"));
+        assert!(formatted.contains("Subject: Hi\r\n"));
+        assert!(formatted.contains("\r\n\r\nHello there\r\n"));
+        assert!(formatted.contains("Paid 20 sat on "));
+        assert!(formatted.contains("Payment hash: "));
+
+        // the email is marked as sent right after the delivery
+        let mut json = Value::Null;
+        for _ in 0..50 {
+            json = client
+                .post("/info")
+                .body(&payment_hash)
+                .dispatch()
+                .await
+                .into_json()
+                .await
+                .unwrap();
+            if json["state"] != "queued" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(json["state"], "sent");
+        assert_eq!(json["email_sent"], true);
+
+        // a late notification doesn't send the email again
+        client
+            .post("/invoice/paid")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(preimage.to_hex())
+            .dispatch()
+            .await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(mailer.messages().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_form_encodings() {
+        let mailer = MemoryMailer::default();
+        let client = test_util::client(mailer.clone()).await;
+        for preimage in [[2u8; 32], [3u8; 32]] {
+            let response = client
+                .post("/invoice")
+                .header(Header::new("authorization", HTTP_AUTH_BASIC))
+                .body(test_util::invoice(preimage, 30_000))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Created);
+        }
+
+        let urlencoded = (
+            ContentType::Form,
+            "to=a%40example.com%2C%20b%40example.com&subject=Hi&message=Hello%20there\
+             &reply_to=Sender%20%3Cfrom%40example.com%3E"
+                .to_string(),
+        );
+        let boundary = "X-PAY2EMAIL-BOUNDARY";
+        let field = |name: &str, value: &str| {
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+        };
+        let multipart = (
+            ContentType::new("multipart", "form-data").with_params(("boundary", boundary)),
+            format!(
+                "{}{}{}{}--{}--\r\n",
+                field("to", "a@example.com, b@example.com"),
+                field("subject", "Hi"),
+                field("message", "Hello there"),
+                field("reply_to", "Sender <from@example.com>"),
+                boundary
+            ),
+        );
+
+        let accept = "text/html;q=0.5, application/json";
+        for (content_type, body) in [urlencoded, multipart] {
+            let response = client
+                .post("/")
+                .header(content_type)
+                .header(Header::new("accept", accept))
+                .body(body)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            let json: Value = response.into_json().await.unwrap();
+            assert_eq!(json["message"], "Hello there");
+            assert_eq!(json["reply_to"], "Sender <from@example.com>");
+        }
+
+        // encrypted fields are decrypted the same way in multipart submissions
+        let preimage = [7u8; 32];
+        client
+            .post("/invoice")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(test_util::invoice(preimage, 20_000))
+            .dispatch()
+            .await;
+        let response = client
+            .post("/")
+            .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+            .header(Header::new("accept", accept))
+            .body(format!(
+                "{}{}{}--{}--\r\n",
+                field("to_enc", &test_util::encrypted("c@example.com")),
+                field("subject_enc", &test_util::encrypted("Secret subject")),
+                field("message", "Hello there"),
+                boundary
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let json: Value = response.into_json().await.unwrap();
+        assert_eq!(json["payment_hash"], test_util::payment_hash(preimage));
+        client
+            .post("/invoice/paid")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(preimage.to_hex())
+            .dispatch()
+            .await;
+        test_util::wait_messages(&mailer, 1).await;
+        let messages = mailer.messages();
+        assert_eq!(messages[0].envelope().to()[0].to_string(), "c@example.com");
+        let formatted = String::from_utf8(messages[0].formatted()).unwrap();
+        assert!(formatted.contains("Subject: Secret subject"));
+
+        // an undecryptable field is an error
+        let response = client
+            .post("/")
+            .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+            .body(format!(
+                "{}{}{}--{}--\r\n",
+                field("to_enc", "not encrypted"),
+                field("subject", "Hi"),
+                field("message", "Hello there"),
+                boundary
+            ))
+            .dispatch()
+            .await;
+        assert_ne!(response.status(), Status::Ok);
+
+        let response = client
+            .post("/")
+            .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+            .body(format!(
+                "{}{}--{}--\r\n",
+                field("to", "not an email"),
+                field("message", "Hello there"),
+                boundary
+            ))
+            .dispatch()
+            .await;
+        // an invalid optional field is discarded, and the email has no recipient
+        assert_ne!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_attachments() {
+        let mailer = MemoryMailer::default();
+        let client = test_util::client(mailer.clone()).await;
+        let preimage = [4u8; 32];
+        client
+            .post("/invoice")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(test_util::invoice(preimage, 40_000))
+            .dispatch()
+            .await;
+
+        let boundary = "X-PAY2EMAIL-BOUNDARY";
+        let content_type =
+            ContentType::new("multipart", "form-data").with_params(("boundary", boundary));
+        let field = |name: &str, value: &str| {
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+        };
+        let file = |filename: &str, content_type: &str, content: &str| {
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"attachments\"; filename=\"{}\"\r\n\
+                 Content-Type: {}\r\n\r\n{}\r\n",
+                boundary, filename, content_type, content
+            )
+        };
+        let fields = format!(
+            "{}{}{}",
+            field("to", "to@example.com"),
+            field("subject", "Hi"),
+            field("message", "Hello there")
+        );
+
+        let response = client
+            .post("/")
+            .header(content_type.clone())
+            .body(format!(
+                "{}{}--{}--\r\n",
+                fields,
+                file("page.html", "text/html", "<html></html>"),
+                boundary
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        let response = client
+            .post("/")
+            .header(content_type)
+            .header(Header::new("accept", "application/json"))
+            .body(format!(
+                "{}{}{}--{}--\r\n",
+                fields,
+                file("../notes.txt", "text/plain", "my notes"),
+                file("", "application/octet-stream", ""),
+                boundary
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        client
+            .post("/invoice/paid")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(preimage.to_hex())
+            .dispatch()
+            .await;
+        test_util::wait_messages(&mailer, 1).await;
+
+        let formatted = String::from_utf8(mailer.messages()[0].formatted()).unwrap();
+        assert!(formatted.contains("Content-Type: multipart/mixed;"));
+        assert!(formatted.contains("Content-Type: multipart/alternative;"));
+        assert!(formatted.contains("Content-Disposition: attachment; filename=\"notes.txt\""));
+        assert!(formatted.contains("\r\n\r\nmy notes\r\n"));
+    }
+
+    #[rocket::async_test]
+    async fn test_failed_attachments() {
+        let backend = Arc::new(MockBackend::default());
+        let figment = test_util::figment().merge(("queue.max_attempts", 1));
+        let rocket = test_util::rocket_with(figment, Arc::new(FailingMailer), backend);
+        let client = Client::tracked(rocket).await.unwrap();
+        let boundary = "X-PAY2EMAIL-BOUNDARY";
+        let response = client
+            .post("/")
+            .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+            .header(Header::new("accept", "application/json"))
+            .body(format!(
+                "--{b}\r\nContent-Disposition: form-data; name=\"to\"\r\n\r\nto@example.com\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"subject\"\r\n\r\nHi\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"message\"\r\n\r\nHello\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"attachments\"; \
+                 filename=\"notes.txt\"\r\nContent-Type: text/plain\r\n\r\nmy notes\r\n--{b}--\r\n",
+                b = boundary
+            ))
+            .dispatch()
+            .await;
+        let json: Value = response.into_json().await.unwrap();
+        let payment_hash = json["payment_hash"].as_str().unwrap().to_string();
+        let db = Db::get_one(client.rocket()).await.unwrap();
+        let attachments = AttachmentRow::list(&db, payment_hash.clone())
+            .await
+            .unwrap();
+        assert_eq!(attachments.len(), 1);
+
+        // paid when looked up, then the only delivery attempt fails
+        for _ in 0..50 {
+            let json: Value = client
+                .post("/info")
+                .body(&payment_hash)
+                .dispatch()
+                .await
+                .into_json()
+                .await
+                .unwrap();
+            if json["state"] == "failed" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        let attachments = AttachmentRow::list(&db, payment_hash).await.unwrap();
+        assert!(attachments.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_pricing() {
+        let client = test_util::client(MemoryMailer::default()).await;
+        for (i, amount_msat) in [20_000, 50_000, 45_000].iter().enumerate() {
+            let response = client
+                .post("/invoice")
+                .header(Header::new("authorization", HTTP_AUTH_BASIC))
+                .body(test_util::invoice([10 + i as u8; 32], *amount_msat))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Created);
+        }
+
+        // three recipients cost 40 sat with the default pricing, the cheapest invoice above is used
+        let three =
+            "to=a%40example.com%2Cb%40example.com%2Cc%40example.com&subject=Hi&message=Hello";
+        let expected = [Some(45_000), Some(50_000), None];
+        for amount_msat in expected {
+            let response = client
+                .post("/")
+                .header(ContentType::Form)
+                .header(Header::new("accept", "application/json"))
+                .body(three)
+                .dispatch()
+                .await;
+            match amount_msat {
+                Some(amount_msat) => {
+                    assert_eq!(response.status(), Status::Ok);
+                    let json: Value = response.into_json().await.unwrap();
+                    assert_eq!(json["amount_msat"], amount_msat);
+                }
+                None => assert_ne!(response.status(), Status::Ok),
+            }
+        }
+
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body("to=a%40example.com&subject=Hi&message=Hello")
+            .dispatch()
+            .await;
+        let json: Value = response.into_json().await.unwrap();
+        assert_eq!(json["amount_msat"], 20_000);
+    }
+
+    #[rocket::async_test]
+    async fn test_cln_invoice() {
+        let rpc_file = test_util::cln_server();
+        let figment = test_util::figment()
+            .merge(("invoice_backend", "cln"))
+            .merge(("cln.rpc_file", rpc_file));
+        let mailer = MemoryMailer::default();
+        let client = Client::tracked(test_util::rocket(figment, mailer.clone()))
+            .await
+            .unwrap();
+
+        // no invoice in the pool, the node creates one of the exact price
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body("to=a%40example.com%2Cb%40example.com&subject=Hi&message=Hello")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let json: Value = response.into_json().await.unwrap();
+        assert_eq!(json["amount_msat"], 30_000);
+
+        let response = client
+            .post("/invoice/paid")
+            .header(Header::new("authorization", HTTP_AUTH_BASIC))
+            .body(test_util::cln_preimage(0).to_hex())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        test_util::wait_messages(&mailer, 1).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_reconcile() {
+        let rpc_file = test_util::cln_server();
+        let figment = test_util::figment()
+            .merge(("invoice_backend", "cln"))
+            .merge(("cln.rpc_file", rpc_file))
+            .merge(("reconcile_interval", 1));
+        let mailer = MemoryMailer::default();
+        let client = Client::tracked(test_util::rocket(figment, mailer.clone()))
+            .await
+            .unwrap();
+
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body("to=a%40example.com&subject=Hi&message=Hello")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let json: Value = response.into_json().await.unwrap();
+        let payment_hash = json["payment_hash"].as_str().unwrap().to_string();
+
+        // never notified on `/invoice/paid`, the stand-in node lists the invoice as paid
+        test_util::wait_messages(&mailer, 1).await;
+        let json: Value = client
+            .post("/info")
+            .body(&payment_hash)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["invoice_paid"], true);
+    }
+
+    #[rocket::async_test]
+    async fn test_lnd_invoice() {
+        let macaroon_file = test_util::temp_path("pay2email-macaroon");
+        std::fs::write(&macaroon_file, [0xab, 0xcd]).unwrap();
+        let figment = test_util::figment()
+            .merge(("invoice_backend", "lnd"))
+            .merge(("lnd.url", test_util::lnd_server()))
+            .merge(("lnd.macaroon_file", macaroon_file));
+        let mailer = MemoryMailer::default();
+        let client = Client::tracked(test_util::rocket(figment, mailer.clone()))
+            .await
+            .unwrap();
+
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .header(Header::new("accept", "application/json"))
+            .body("to=a%40example.com&subject=Hi&message=Hello")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let json: Value = response.into_json().await.unwrap();
+        assert_eq!(json["amount_msat"], 20_000);
+        let payment_hash = json["payment_hash"].as_str().unwrap().to_string();
+
+        // the stand-in node reports the invoice as settled when looked up
+        let json: Value = client
+            .post("/info")
+            .body(&payment_hash)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(json["invoice_paid"], true);
+        test_util::wait_messages(&mailer, 1).await;
+    }
 }
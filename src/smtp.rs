@@ -0,0 +1,191 @@
+use crate::error::Result;
+use crate::Error;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::transport::smtp::PoolConfig;
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use rocket::serde::Deserialize;
+use std::env;
+use std::time::Duration;
+
+/// How the connection with the SMTP relay is secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Plaintext connection, only meaningful for a relay on localhost
+    None,
+    /// Plaintext connection upgraded with the STARTTLS command, usually on port 587
+    Starttls,
+    /// TLS from the first byte, usually on port 465
+    Implicit,
+}
+
+/// SMTP relay configuration, read from the `smtp` table of the Rocket config, eg:
+///
+/// ```toml
+/// [default.smtp]
+/// host = "smtp.improvmx.com"
+/// port = 587
+/// tls = "starttls"
+/// username = "noreply@pay2.email"
+/// password_env = "SMTP_PASSWORD"
+/// pool_max_size = 10
+/// pool_idle_timeout = 60
+/// failure_threshold = 3
+/// retry_after = 300
+/// ```
+///
+/// The password is not part of the config and it's read from the env var named by `password_env`
+/// (default `SMTP_PASSWORD`) when `username` is set, so that every relay can have its own.
+///
+/// The transport keeps a pool of at most `pool_max_size` connections to the relay, reused across
+/// emails and closed after `pool_idle_timeout` seconds of inactivity.
+///
+/// When multiple relays are configured, a relay failing `failure_threshold` consecutive times is
+/// skipped for `retry_after` seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    pub username: Option<String>,
+
+    #[serde(default = "default_password_env")]
+    pub password_env: String,
+
+    #[serde(default = "default_pool_max_size")]
+    pub pool_max_size: u32,
+
+    #[serde(default = "default_pool_idle_timeout")]
+    pub pool_idle_timeout: u64,
+
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+}
+
+fn default_password_env() -> String {
+    "SMTP_PASSWORD".to_string()
+}
+
+fn default_pool_max_size() -> u32 {
+    10
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    60
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_retry_after() -> u64 {
+    300
+}
+
+impl SmtpConfig {
+    /// Build the pooled transport to the configured relay, it should be built once and shared
+    pub fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+        let tls = match self.tls {
+            TlsMode::None => Tls::None,
+            TlsMode::Starttls => Tls::Required(TlsParameters::new(self.host.clone())?),
+            TlsMode::Implicit => Tls::Wrapper(TlsParameters::new(self.host.clone())?),
+        };
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host)
+            .port(self.port)
+            .tls(tls)
+            .pool_config(
+                PoolConfig::new()
+                    .max_size(self.pool_max_size)
+                    .idle_timeout(Duration::from_secs(self.pool_idle_timeout)),
+            );
+        if let Some(username) = self.username.as_ref() {
+            let password = env::var(&self.password_env)
+                .map_err(|_| Error::InvalidConfig(format!("{} not set", self.password_env)))?;
+            builder = builder.credentials(Credentials::new(username.clone(), password));
+        }
+        Ok(builder.build())
+    }
+
+    /// Check the config is usable, so that errors are detected at launch instead of when sending
+    /// the first email
+    pub fn validate(&self) -> Result<()> {
+        if self.host.trim().is_empty() {
+            return Err(Error::InvalidConfig("smtp.host is empty".to_string()));
+        }
+        if self.port == 0 {
+            return Err(Error::InvalidConfig("smtp.port is 0".to_string()));
+        }
+        if self.pool_max_size == 0 {
+            return Err(Error::InvalidConfig("smtp.pool_max_size is 0".to_string()));
+        }
+        if self.failure_threshold == 0 {
+            return Err(Error::InvalidConfig(
+                "smtp.failure_threshold is 0".to_string(),
+            ));
+        }
+        if self.tls == TlsMode::None && self.username.is_some() {
+            println!(
+                "warning: SMTP credentials are sent in clear text to {}",
+                self.host
+            );
+        }
+        self.transport()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::smtp::{SmtpConfig, TlsMode};
+
+    fn config() -> SmtpConfig {
+        SmtpConfig {
+            host: "localhost".to_string(),
+            port: 25,
+            tls: TlsMode::None,
+            username: None,
+            password_env: "SMTP_PASSWORD".to_string(),
+            pool_max_size: 2,
+            pool_idle_timeout: 60,
+            failure_threshold: 3,
+            retry_after: 300,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_validate() {
+        assert!(config().validate().is_ok());
+
+        let mut c = config();
+        c.host = "".to_string();
+        assert!(c.validate().is_err());
+
+        let mut c = config();
+        c.port = 0;
+        assert!(c.validate().is_err());
+
+        let mut c = config();
+        c.pool_max_size = 0;
+        assert!(c.validate().is_err());
+
+        // every relay reads its own password
+        let mut c = config();
+        c.username = Some("noreply@pay2.email".to_string());
+        c.password_env = "PAY2EMAIL_TEST_RELAY_PASSWORD".to_string();
+        assert!(c.validate().is_err());
+        std::env::set_var("PAY2EMAIL_TEST_RELAY_PASSWORD", "x");
+        assert!(c.validate().is_ok());
+    }
+
+    #[test]
+    fn test_tls_mode() {
+        let tls: TlsMode = serde_json::from_str("\"implicit\"").unwrap();
+        assert_eq!(tls, TlsMode::Implicit);
+        assert!(serde_json::from_str::<TlsMode>("\"ssl\"").is_err());
+    }
+}
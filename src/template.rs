@@ -0,0 +1,61 @@
+/// Replace every `{{ KEY }}` in `template` with the value of `KEY` in `vars`, in a single pass so
+/// that placeholders contained in the values are not replaced. Unknown placeholders are left as is.
+pub fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{ ") {
+        let after = &rest[start + 3..];
+        let end = match after.find(" }}") {
+            Some(end) => end,
+            None => break,
+        };
+        let key = &after[..end];
+        result.push_str(&rest[..start]);
+        match vars.iter().find(|(k, _)| *k == key) {
+            Some((_, value)) => result.push_str(value),
+            None => result.push_str(&rest[start..start + 3 + end + 3]),
+        }
+        rest = &after[end + 3..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Escape `text` so that it can be included in html content and attribute values
+pub fn escape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crate::template::{escape_html, render};
+
+    #[test]
+    fn test_render() {
+        let vars = [("A", "1"), ("B", "{{ A }}")];
+        assert_eq!(render("{{ A }}-{{ B }}", &vars), "1-{{ A }}");
+        assert_eq!(render("{{ C }} {{ A }}", &vars), "{{ C }} 1");
+        assert_eq!(render("no vars {{ A", &vars), "no vars {{ A");
+        assert_eq!(render("", &vars), "");
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<script>alert('x' && \"y\")</script>"),
+            "&lt;script&gt;alert(&#39;x&#39; &amp;&amp; &quot;y&quot;)&lt;/script&gt;"
+        );
+        assert_eq!(escape_html("plain text"), "plain text");
+    }
+}
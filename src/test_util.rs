@@ -0,0 +1,619 @@
+//! Helpers to run the service in tests, with a fresh database and emails kept in memory
+
+use crate::backend::{self, add_reserved, InvoiceBackend, InvoiceStatus, PaymentStatus};
+use crate::bolt12::Offer;
+use crate::db::{EmailRow, InvoiceRow};
+use crate::encrypt::encrypt_to;
+use crate::error::Result;
+use crate::mailer::{Mailer, MemoryMailer, Outbox};
+use crate::{pow, queue, routes, Db, Error};
+use age::x25519::Identity;
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::{sha256, Hash};
+use lettre::Message;
+use lightning::ln::PaymentSecret;
+use lightning_invoice::{Currency, Invoice, InvoiceBuilder, InvoiceDescription};
+use rocket::figment::Figment;
+use rocket::local::asynchronous::Client;
+use rocket::{Build, Rocket};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+
+/// Value of the `authorization` header accepted by the endpoints requiring authentication
+pub const HTTP_AUTH_BASIC: &str = "Basic dGVzdDp0ZXN0";
+
+/// Secret key of the node issuing the invoices created by [`invoice`]
+pub const NODE_SECRET: [u8; 32] = [0x42; 32];
+
+/// Secret key of the server decrypting the values encrypted by [`encrypted`]
+pub const AGE_SECRET_KEY: &str =
+    "AGE-SECRET-KEY-1QURSWPC8QURSWPC8QURSWPC8QURSWPC8QURSWPC8QURSWPC8QURSKMP32K";
+
+/// Encrypt `plaintext` like the `/encrypt` endpoint, for the test key which is set as the server
+/// key
+pub fn encrypted(plaintext: &str) -> String {
+    std::env::set_var("AGE_SECRET_KEY", AGE_SECRET_KEY);
+    let identity = Identity::from_str(AGE_SECRET_KEY).unwrap();
+    encrypt_to(identity.to_public(), plaintext).unwrap()
+}
+
+/// Encrypt the recipients `to` accepting proofs of work of `bits`, like the `/encrypt/pow` endpoint
+pub fn encrypted_opt_in(to: &str, bits: u32) -> String {
+    std::env::set_var("AGE_SECRET_KEY", AGE_SECRET_KEY);
+    encrypted(&pow::opt_in(to, bits))
+}
+
+/// Config of a test instance, with an empty database in a temporary file
+pub fn figment() -> Figment {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let db = std::env::temp_dir().join(format!(
+        "pay2email-test-{}-{}-{}.sqlite",
+        std::process::id(),
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    rocket::Config::figment()
+        .merge(("databases.diesel.url", db.to_str().unwrap()))
+        .merge(("queue.interval", 1))
+        .merge(("log_level", "off"))
+}
+
+/// A test instance delivering the emails to `mailer`
+pub fn rocket(figment: Figment, mailer: MemoryMailer) -> Rocket<Build> {
+    std::env::set_var("HTTP_AUTH_BASIC", HTTP_AUTH_BASIC);
+    let from = "Pay2.email <noreply@pay2.email>".parse().unwrap();
+    rocket::custom(figment)
+        .manage(Outbox::new(from, Arc::new(mailer)))
+        .attach(backend::stage())
+        .attach(routes::stage())
+        .attach(queue::stage())
+}
+
+/// A test instance delivering the emails with `mailer`, its invoices created by `backend` instead of
+/// the configured one, reconciled like the ones of a node
+pub fn rocket_with(
+    figment: Figment,
+    mailer: Arc<dyn Mailer>,
+    backend: Arc<dyn InvoiceBackend>,
+) -> Rocket<Build> {
+    std::env::set_var("HTTP_AUTH_BASIC", HTTP_AUTH_BASIC);
+    let from = "Pay2.email <noreply@pay2.email>".parse().unwrap();
+    rocket::custom(figment)
+        .manage(Outbox::new(from, mailer))
+        .manage(backend)
+        .attach(backend::settlements(Some(
+            backend::default_reconcile_interval(),
+        )))
+        .attach(routes::stage())
+        .attach(queue::stage())
+}
+
+pub async fn client(mailer: MemoryMailer) -> Client {
+    Client::tracked(rocket(figment(), mailer)).await.unwrap()
+}
+
+/// Create an invoice of `amount_msat` paying to `sha256(preimage)`, returns its bolt11
+pub fn invoice(preimage: [u8; 32], amount_msat: u64) -> String {
+    invoice_on(Currency::Bitcoin, preimage, amount_msat)
+}
+
+/// Like [`invoice`] on the network of `currency`
+pub fn invoice_on(currency: Currency, preimage: [u8; 32], amount_msat: u64) -> String {
+    invoice_paying(currency, &payment_hash(preimage), amount_msat)
+}
+
+/// Create an invoice of `amount_msat` paying to the hex `payment_hash`, whose preimage is unknown
+pub fn invoice_paying(currency: Currency, payment_hash: &str, amount_msat: u64) -> String {
+    invoice_describing(currency, payment_hash, amount_msat, None)
+}
+
+/// Like [`invoice_paying`], with the hex `description_hash` instead of a description if any
+pub fn invoice_describing(
+    currency: Currency,
+    payment_hash: &str,
+    amount_msat: u64,
+    description_hash: Option<&str>,
+) -> String {
+    build_invoice(currency, payment_hash, Some(amount_msat), description_hash)
+}
+
+/// Create an invoice without amount paying to `preimage`, refused by the server
+pub fn invoice_without_amount(preimage: [u8; 32]) -> String {
+    build_invoice(Currency::Bitcoin, &payment_hash(preimage), None, None)
+}
+
+fn build_invoice(
+    currency: Currency,
+    payment_hash: &str,
+    amount_msat: Option<u64>,
+    description_hash: Option<&str>,
+) -> String {
+    let secp = Secp256k1::new();
+    let key = SecretKey::from_slice(&NODE_SECRET).unwrap();
+    let builder = InvoiceBuilder::new(currency);
+    // lightning-invoice depends on a different version of bitcoin_hashes
+    let builder = match description_hash {
+        Some(hash) => builder.description_hash(hash.parse().unwrap()),
+        None => builder.description("pay2.email".to_string()),
+    };
+    let builder = builder
+        .payment_hash(payment_hash.parse().unwrap())
+        .payment_secret(PaymentSecret([0x11; 32]))
+        .current_timestamp()
+        .expiry_time(Duration::from_secs(7 * 24 * 60 * 60))
+        .min_final_cltv_expiry(144);
+    let builder = match amount_msat {
+        Some(amount_msat) => builder.amount_milli_satoshis(amount_msat),
+        None => builder,
+    };
+    builder
+        .build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &key))
+        .unwrap()
+        .to_string()
+}
+
+/// Hex public key of the node issuing the invoices created by [`invoice`]
+pub fn node_pubkey() -> String {
+    let key = SecretKey::from_slice(&NODE_SECRET).unwrap();
+    PublicKey::from_secret_key(&Secp256k1::new(), &key).to_string()
+}
+
+/// Wait until `mailer` received `count` emails, panics after a few seconds
+pub async fn wait_messages(mailer: &MemoryMailer, count: usize) {
+    for _ in 0..50 {
+        if mailer.messages().len() >= count {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("expected {} emails, got {}", count, mailer.messages().len());
+}
+
+/// Path of a new file in the temp dir
+pub fn temp_path(prefix: &str) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    std::env::temp_dir().join(format!(
+        "{}-{}-{}",
+        prefix,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Preimage of the invoice number `n` created by the server of [`cln_server`]
+pub fn cln_preimage(n: u8) -> [u8; 32] {
+    [0x80 + n; 32]
+}
+
+/// Start a stand-in Core Lightning node answering `invoice` and `listinvoices` requests on a unix
+/// socket, returns the socket path. Every created invoice is listed as paid. Like the real node, the
+/// connection is left open after the response and invoices with a duplicate label are refused.
+pub fn cln_server() -> PathBuf {
+    let path = temp_path("pay2email-cln");
+    let listener = UnixListener::bind(&path).unwrap();
+    let created = Arc::new(AtomicU8::new(0));
+    let labels = Arc::new(Mutex::new(HashSet::new()));
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let created = created.clone();
+            let labels = labels.clone();
+            tokio::spawn(async move {
+                let mut buffer = vec![];
+                let mut chunk = [0u8; 1024];
+                let request: Value = loop {
+                    let read = stream.read(&mut chunk).await.unwrap();
+                    buffer.extend_from_slice(&chunk[..read]);
+                    if let Ok(request) = serde_json::from_slice(&buffer) {
+                        break request;
+                    }
+                };
+                let amount_msat = request["params"]["amount_msat"].as_u64().unwrap_or(0);
+                let response = if request["method"] == "listinvoices" {
+                    let invoices: Vec<Value> = (0..created.load(Ordering::SeqCst))
+                        .map(cln_preimage)
+                        .filter(|p| request["params"]["payment_hash"] == payment_hash(*p))
+                        .map(|p| json!({ "status": "paid", "payment_preimage": p.to_hex() }))
+                        .collect();
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "result": { "invoices": invoices },
+                    })
+                } else if request["method"] == "offer" {
+                    let bolt12 = offer(&request["params"]);
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "result": {
+                            "offer_id": sha256::Hash::hash(bolt12.as_bytes()).to_hex(),
+                            "bolt12": bolt12,
+                        },
+                    })
+                } else if request["method"] != "invoice" || amount_msat == 0 {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "error": { "code": -32602, "message": "invalid params" },
+                    })
+                } else if !labels
+                    .lock()
+                    .unwrap()
+                    .insert(request["params"]["label"].to_string())
+                {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "error": { "code": 900, "message": "Duplicate label" },
+                    })
+                } else {
+                    let preimage = cln_preimage(created.fetch_add(1, Ordering::SeqCst));
+                    let params = &request["params"];
+                    let description = params["description"]
+                        .as_str()
+                        .filter(|_| params["deschashonly"] == true);
+                    let bolt11 = invoice_describing(
+                        Currency::Bitcoin,
+                        &payment_hash(preimage),
+                        amount_msat,
+                        description_hash(description).as_deref(),
+                    );
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "result": { "bolt11": bolt11 },
+                    })
+                };
+                let mut response = serde_json::to_vec(&response).unwrap();
+                response.extend_from_slice(b"\n\n");
+                stream.write_all(&response).await.unwrap();
+                let _ = stream.read(&mut chunk).await;
+            });
+        }
+    });
+    path
+}
+
+/// The offer created by the stand-in node with the `params` of the `offer` command, issued by
+/// [`node_pubkey`]
+fn offer(params: &Value) -> String {
+    let amount = params["amount"]
+        .as_str()
+        .and_then(|a| a.strip_suffix("msat"))
+        .and_then(|a| a.parse().ok());
+    let key = SecretKey::from_slice(&NODE_SECRET).unwrap();
+    let node_id = PublicKey::from_secret_key(&Secp256k1::new(), &key).serialize();
+    Offer {
+        amount,
+        description: params["description"].as_str().map(|d| d.to_string()),
+        node_id: Some(node_id),
+        ..Offer::default()
+    }
+    .to_string()
+}
+
+/// The hex sha256 of `description`, if any
+pub fn description_hash(description: Option<&str>) -> Option<String> {
+    description.map(|d| sha256::Hash::hash(d.as_bytes()).to_hex())
+}
+
+/// The hex description hash of the invoice `bolt11`, `None` if it has a description
+pub fn invoice_description_hash(bolt11: &str) -> Option<String> {
+    match bolt11.parse::<Invoice>().unwrap().description() {
+        InvoiceDescription::Hash(hash) => Some(hash.0.to_string()),
+        InvoiceDescription::Direct(_) => None,
+    }
+}
+
+/// Payment hash of `preimage`, hex encoded
+pub fn payment_hash(preimage: [u8; 32]) -> String {
+    sha256::Hash::hash(&preimage).to_hex()
+}
+
+/// Start an HTTP server answering every request with `handler(method, path, headers, body)`, returns
+/// its base url. The connection is closed after the response.
+pub fn http_server<F>(handler: F) -> String
+where
+    F: Fn(&str, &str, &str, &[u8]) -> (u16, String) + Send + Sync + 'static,
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let listener = TcpListener::from_std(listener).unwrap();
+    let handler = Arc::new(handler);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let mut buffer = vec![];
+                let mut chunk = [0u8; 1024];
+                let header_end = loop {
+                    let read = stream.read(&mut chunk).await.unwrap();
+                    buffer.extend_from_slice(&chunk[..read]);
+                    if let Some(i) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break i + 4;
+                    }
+                };
+                let head = String::from_utf8(buffer[..header_end].to_vec()).unwrap();
+                let content_length = head
+                    .lines()
+                    .filter_map(|l| l.split_once(": "))
+                    .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                    .map(|(_, v)| v.trim().parse().unwrap())
+                    .unwrap_or(0);
+                while buffer.len() < header_end + content_length {
+                    let read = stream.read(&mut chunk).await.unwrap();
+                    buffer.extend_from_slice(&chunk[..read]);
+                }
+                let mut request_line = head.lines().next().unwrap().split(' ');
+                let method = request_line.next().unwrap();
+                let path = request_line.next().unwrap();
+                let (status, body) = handler(method, path, &head, &buffer[header_end..]);
+                let response = format!(
+                    "HTTP/1.1 {} -\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n{}",
+                    status, body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            });
+        }
+    });
+    url
+}
+
+/// Preimage of the invoice number `n` created by the server of [`lnd_server`]
+pub fn lnd_preimage(n: u8) -> [u8; 32] {
+    [0x40 + n; 32]
+}
+
+/// Start a stand-in LND REST API, requiring the macaroon `abcd`. Every invoice is reported as
+/// settled, the subscription streams an open and a settled invoice then ends.
+pub fn lnd_server() -> String {
+    let created = AtomicU8::new(0);
+    http_server(move |method, path, head, body| {
+        if !head.contains("grpc-metadata-macaroon: abcd\r\n") {
+            return (
+                403,
+                r#"{"error": "verification failed", "code": 2}"#.to_string(),
+            );
+        }
+        match (method, path) {
+            ("POST", "/v1/invoices") => {
+                let request: Value = serde_json::from_slice(body).unwrap();
+                let amount_msat = request["value_msat"].as_str().unwrap().parse().unwrap();
+                let preimage = lnd_preimage(created.fetch_add(1, Ordering::SeqCst));
+                let description_hash = request["description_hash"]
+                    .as_str()
+                    .map(|h| base64::decode(h).unwrap().to_hex());
+                let payment_request = invoice_describing(
+                    Currency::Bitcoin,
+                    &payment_hash(preimage),
+                    amount_msat,
+                    description_hash.as_deref(),
+                );
+                let response = json!({
+                    "r_hash": base64::encode(sha256::Hash::hash(&preimage)),
+                    "payment_request": payment_request,
+                    "add_index": "1",
+                });
+                (200, response.to_string())
+            }
+            ("GET", "/v1/invoices/subscribe") => {
+                let update = |n: u8, state: &str| {
+                    let r_hash = base64::encode(sha256::Hash::hash(&lnd_preimage(n)));
+                    json!({ "result": { "r_hash": r_hash, "state": state } }).to_string()
+                };
+                (
+                    200,
+                    format!("{}\n{}\n", update(0, "OPEN"), update(1, "SETTLED")),
+                )
+            }
+            ("GET", p) if p.starts_with("/v1/invoice/") => {
+                let hash = p.trim_start_matches("/v1/invoice/");
+                match (0..created.load(Ordering::SeqCst))
+                    .map(lnd_preimage)
+                    .find(|p| payment_hash(*p) == hash)
+                {
+                    Some(preimage) => {
+                        let response = json!({
+                            "r_hash": base64::encode(sha256::Hash::hash(&preimage)),
+                            "r_preimage": base64::encode(preimage),
+                            "state": "SETTLED",
+                        });
+                        (200, response.to_string())
+                    }
+                    None => (404, "{}".to_string()),
+                }
+            }
+            _ => (404, "{}".to_string()),
+        }
+    })
+}
+
+/// Backend keeping its invoices in memory, their payments are simulated with
+/// [`MockBackend::pay`]. Creates hold invoices.
+#[derive(Default)]
+pub struct MockBackend {
+    invoices: Mutex<HashMap<String, InvoiceStatus>>,
+    /// The invoices paid by the node, with the status of their payment
+    payments: Mutex<Vec<(String, PaymentStatus)>>,
+    /// Status of the next payments, complete if not set
+    outcome: Mutex<Option<PaymentStatus>>,
+}
+
+impl MockBackend {
+    /// A payment to `payment_hash` arrives, held if the invoice is a hold invoice
+    pub fn pay(&self, payment_hash: &str) {
+        self.invoices
+            .lock()
+            .unwrap()
+            .insert(payment_hash.to_string(), InvoiceStatus::Accepted);
+    }
+
+    /// Status of the invoice paying to `payment_hash`
+    pub fn status(&self, payment_hash: &str) -> Option<InvoiceStatus> {
+        self.invoices.lock().unwrap().get(payment_hash).cloned()
+    }
+
+    /// The invoices paid by the node
+    pub fn paid(&self) -> Vec<String> {
+        let payments = self.payments.lock().unwrap();
+        payments
+            .iter()
+            .filter(|(_, status)| *status == PaymentStatus::Complete)
+            .map(|(bolt11, _)| bolt11.clone())
+            .collect()
+    }
+
+    /// The next payments end with `status`, pending ones fail with an uncertain error
+    pub fn set_outcome(&self, status: PaymentStatus) {
+        *self.outcome.lock().unwrap() = Some(status);
+    }
+
+    /// The pending payments complete
+    pub fn complete_payments(&self) {
+        for (_, status) in self.payments.lock().unwrap().iter_mut() {
+            if *status == PaymentStatus::Pending {
+                *status = PaymentStatus::Complete;
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl InvoiceBackend for MockBackend {
+    async fn create_invoice(
+        &self,
+        db: &Db,
+        amount_msat: u64,
+        hashed_description: Option<&str>,
+        email_row: EmailRow,
+    ) -> Result<InvoiceRow> {
+        let preimage: [u8; 32] = rand::random();
+        let bolt11 = invoice_describing(
+            Currency::Bitcoin,
+            &payment_hash(preimage),
+            amount_msat,
+            description_hash(hashed_description).as_deref(),
+        );
+        let status = InvoiceStatus::Settled(preimage.to_hex());
+        self.invoices
+            .lock()
+            .unwrap()
+            .insert(payment_hash(preimage), status);
+        add_reserved(db, bolt11, email_row).await
+    }
+
+    fn supports_description_hash(&self) -> bool {
+        true
+    }
+
+    async fn lookup_invoice(&self, _db: &Db, payment_hash: &str) -> Result<InvoiceStatus> {
+        self.status(payment_hash).ok_or(Error::InvoiceNotFound)
+    }
+
+    fn supports_hold(&self) -> bool {
+        true
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        amount_msat: u64,
+        payment_hash: &str,
+        hashed_description: Option<&str>,
+    ) -> Result<String> {
+        let bolt11 = invoice_describing(
+            Currency::Bitcoin,
+            payment_hash,
+            amount_msat,
+            description_hash(hashed_description).as_deref(),
+        );
+        self.invoices
+            .lock()
+            .unwrap()
+            .insert(payment_hash.to_string(), InvoiceStatus::Open);
+        Ok(bolt11)
+    }
+
+    async fn settle_hold_invoice(&self, preimage: &str) -> Result<()> {
+        let payment_hash = backend::payment_hash(preimage)?;
+        let mut invoices = self.invoices.lock().unwrap();
+        match invoices.get_mut(&payment_hash) {
+            Some(status) if *status == InvoiceStatus::Accepted => {
+                *status = InvoiceStatus::Settled(preimage.to_string());
+                Ok(())
+            }
+            _ => Err(Error::InvoiceNotFound),
+        }
+    }
+
+    async fn cancel_hold_invoice(&self, payment_hash: &str) -> Result<()> {
+        let mut invoices = self.invoices.lock().unwrap();
+        match invoices.get_mut(payment_hash) {
+            Some(InvoiceStatus::Settled(_)) | None => Err(Error::InvoiceNotFound),
+            Some(status) => {
+                *status = InvoiceStatus::Canceled;
+                Ok(())
+            }
+        }
+    }
+
+    fn supports_payments(&self) -> bool {
+        true
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<()> {
+        let status = self
+            .outcome
+            .lock()
+            .unwrap()
+            .unwrap_or(PaymentStatus::Complete);
+        let mut payments = self.payments.lock().unwrap();
+        payments.push((bolt11.to_string(), status));
+        match status {
+            PaymentStatus::Complete => Ok(()),
+            PaymentStatus::Failed => Err(Error::PaymentFailed("no route".to_string())),
+            PaymentStatus::Pending => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        }
+    }
+
+    async fn lookup_payment(&self, bolt11: &str) -> Result<Option<PaymentStatus>> {
+        let payments = self.payments.lock().unwrap();
+        let last = payments.iter().rev().find(|(b, _)| b == bolt11);
+        Ok(last.map(|(_, status)| *status))
+    }
+}
+
+/// Mailer refusing every email
+pub struct FailingMailer;
+
+#[rocket::async_trait]
+impl Mailer for FailingMailer {
+    async fn send(&self, _message: Message) -> Result<String> {
+        Err(Error::NoRelayAvailable)
+    }
+}
+
+/// Mailer rejecting every recipient, like an unknown mailbox
+pub struct RejectingMailer;
+
+#[rocket::async_trait]
+impl Mailer for RejectingMailer {
+    async fn send(&self, _message: Message) -> Result<String> {
+        Err(Error::Rejected("550 5.1.1 user unknown".to_string()))
+    }
+}
@@ -0,0 +1,57 @@
+// Send the pay2.email forms with a `data-pow-bits` attribute with a proof of work instead of a
+// payment, the recipient must accept at least that difficulty in the encrypted `to_enc` field.
+//
+// The stamp is `challenge:nonce:to_enc:message`, whose sha256 must start with `data-pow-bits`
+// zero bits, or the `min_bits` of the challenge if higher.
+
+function leadingZeros(hash) {
+    let bits = 0
+    for (const byte of hash) {
+        if (byte != 0) {
+            return bits + Math.clz32(byte) - 24
+        }
+        bits += 8
+    }
+    return bits
+}
+
+function hiddenInput(form, name) {
+    let input = form.querySelector(`input[name="${name}"]`)
+    if (!input) {
+        input = document.createElement("input")
+        input.type = "hidden"
+        input.name = name
+        form.appendChild(input)
+    }
+    return input
+}
+
+async function stamp(form) {
+    const origin = new URL(form.action).origin
+    const response = await fetch(origin + "/pow/challenge")
+    const { challenge, min_bits } = await response.json()
+    const bits = Math.max(parseInt(form.dataset.powBits), min_bits)
+    const to = form.querySelector('[name="to_enc"]').value
+    const message = form.querySelector('[name="message"]').value.replace(/\r\n/g, "\n")
+    const encoder = new TextEncoder()
+    for (let nonce = 0; ; nonce++) {
+        const data = encoder.encode(`${challenge}:${nonce}:${to}:${message}`)
+        const hash = new Uint8Array(await crypto.subtle.digest("SHA-256", data))
+        if (leadingZeros(hash) >= bits) {
+            hiddenInput(form, "pow_challenge").value = challenge
+            hiddenInput(form, "pow_nonce").value = nonce
+            return
+        }
+    }
+}
+
+for (const form of document.querySelectorAll("form[data-pow-bits]")) {
+    form.addEventListener("submit", function (event) {
+        event.preventDefault()
+        const button = form.querySelector('[type="submit"]')
+        if (button) {
+            button.disabled = true
+        }
+        stamp(form).then(function () { form.submit() })
+    })
+}